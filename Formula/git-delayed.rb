@@ -21,7 +21,7 @@ class GitDelayed < Formula
   end
 
   service do
-    run [opt_bin/"git-delayed", "daemon", "start"]
+    run [opt_bin/"git-delayed", "daemon", "run", "--foreground"]
     keep_alive true
     log_path var/"log/git-delayed.log"
     error_log_path var/"log/git-delayed.log"
@@ -19,82 +19,13 @@ sudo chmod +x "$INSTALL_DIR/git-delayed"
 
 echo "✓ Binary installed to $INSTALL_DIR/git-delayed"
 
-# Detect OS and set up daemon autostart
-if [[ "$OSTYPE" == "darwin"* ]]; then
-    # macOS - use launchd
-    echo "Setting up macOS launchd service..."
-    
-    PLIST_DIR="$HOME/Library/LaunchAgents"
-    PLIST_FILE="$PLIST_DIR/com.git-delayed.daemon.plist"
-    
-    mkdir -p "$PLIST_DIR"
-    
-    cat > "$PLIST_FILE" << EOF
-<?xml version="1.0" encoding="UTF-8"?>
-<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
-<plist version="1.0">
-<dict>
-    <key>Label</key>
-    <string>com.git-delayed.daemon</string>
-    <key>ProgramArguments</key>
-    <array>
-        <string>$INSTALL_DIR/git-delayed</string>
-        <string>daemon</string>
-        <string>start</string>
-    </array>
-    <key>RunAtLoad</key>
-    <true/>
-    <key>KeepAlive</key>
-    <true/>
-    <key>StandardOutPath</key>
-    <string>$HOME/Library/Application Support/git-delayed/launchd.out</string>
-    <key>StandardErrorPath</key>
-    <string>$HOME/Library/Application Support/git-delayed/launchd.err</string>
-</dict>
-</plist>
-EOF
-    
-    # Load the service
-    launchctl unload "$PLIST_FILE" 2>/dev/null || true
-    launchctl load "$PLIST_FILE"
-    
-    echo "✓ Daemon configured to start automatically via launchd"
-    echo "  Service file: $PLIST_FILE"
-    
-elif [[ "$OSTYPE" == "linux-gnu"* ]]; then
-    # Linux - use systemd
-    echo "Setting up Linux systemd service..."
-    
-    SYSTEMD_DIR="$HOME/.config/systemd/user"
-    SERVICE_FILE="$SYSTEMD_DIR/git-delayed.service"
-    
-    mkdir -p "$SYSTEMD_DIR"
-    
-    cat > "$SERVICE_FILE" << EOF
-[Unit]
-Description=Git Delayed Daemon
-After=network.target
-
-[Service]
-Type=simple
-ExecStart=$INSTALL_DIR/git-delayed daemon start
-Restart=always
-RestartSec=10
-
-[Install]
-WantedBy=default.target
-EOF
-    
-    # Reload systemd and enable service
-    systemctl --user daemon-reload
-    systemctl --user enable git-delayed.service
-    systemctl --user start git-delayed.service
-    
-    echo "✓ Daemon configured to start automatically via systemd"
-    echo "  Service file: $SERVICE_FILE"
-    echo "  Check status: systemctl --user status git-delayed"
+# Set up daemon autostart (systemd user unit on Linux, launchd agent on macOS)
+echo "Setting up daemon autostart..."
+if "$INSTALL_DIR/git-delayed" service install --user; then
+    echo "✓ Daemon configured to start automatically"
+    echo "  Check status: git-delayed service status --user"
 else
-    echo "⚠ Automatic daemon startup not configured for this OS"
+    echo "⚠ Automatic daemon startup could not be configured"
     echo "  You can manually start the daemon with: git-delayed daemon start"
 fi
 
@@ -1,18 +1,36 @@
 use anyhow::Result;
 use chrono::Local;
 use clap::{Parser, Subcommand};
+use serde_json::Value;
+use std::path::PathBuf;
 use uuid::Uuid;
 
+use crate::config;
+use crate::control;
 use crate::daemon;
+use crate::doctor;
+use crate::executor;
 use crate::git;
-use crate::models::{ExecutionStatus, LogEntry, OperationType, ScheduledOperation};
+use crate::notify;
+use crate::pause::{self, PauseTarget};
+use crate::repos;
+use crate::models::{
+    ExecutionStatus, IntegrationStrategy, LogEntry, MisfirePolicy, OperationState, OperationType, RejectPolicy,
+    ScheduledOperation, ScheduledOperations,
+};
 use crate::schedule;
+use crate::service;
 use crate::storage;
+use crate::store::{self, Backend, LogQuery};
+use crate::transfer::{self, Conflict, Format};
 
 #[derive(Parser)]
 #[command(name = "git-delayed")]
 #[command(about = "Schedule git commits and pushes for future execution")]
 struct Cli {
+    #[arg(long, global = true, value_name = "NAME", help = "Use a separate queue, logs, config and daemon (defaults to $GIT_DELAYED_PROFILE)")]
+    profile: Option<String>,
+    
     #[command(subcommand)]
     command: Commands,
 }
@@ -24,6 +42,12 @@ enum Commands {
         #[arg(help = "Time specification (e.g., '+10 hours', 'Monday', '2025-11-04 09:00')")]
         time_spec: String,
         
+        #[arg(long, global = true, value_name = "POLICY", value_parser = schedule::parse_misfire_policy, help = "If the time is missed (machine off or asleep): run, skip, reschedule or within:30m")]
+        misfire: Option<MisfirePolicy>,
+        
+        #[arg(long, global = true, value_name = "DURATION", value_parser = schedule::parse_timeout, help = "Kill the operation's git commands if it takes longer than this (e.g., 90s, 10m), instead of the configured default")]
+        timeout: Option<u64>,
+        
         #[command(subcommand)]
         action: ScheduleAction,
     },
@@ -32,7 +56,16 @@ enum Commands {
     List,
     
     #[command(about = "Show execution logs")]
-    Logs,
+    Logs {
+        #[command(subcommand)]
+        action: Option<LogsAction>,
+        
+        #[arg(long, value_name = "PATH", num_args = 0..=1, default_missing_value = ".", help = "Only show entries for a repository (the current one if no path is given)")]
+        repo: Option<PathBuf>,
+        
+        #[arg(short = 'n', long, help = "Show at most this many entries, newest first")]
+        limit: Option<usize>,
+    },
     
     #[command(about = "Cancel a scheduled operation")]
     Cancel {
@@ -40,11 +73,95 @@ enum Commands {
         operation_id: String,
     },
     
+    #[command(visible_alias = "run-now", about = "Run scheduled operations right now instead of waiting")]
+    Run {
+        #[arg(required = true, help = "Operation IDs to run, in the order given")]
+        operation_ids: Vec<String>,
+        
+        #[arg(long, help = "Show what would happen without running anything")]
+        dry_run: bool,
+    },
+    
+    #[command(about = "Hold back operations without cancelling them")]
+    Pause {
+        #[arg(help = "Operation ID to pause (pauses everything if neither this nor --repo is given)")]
+        operation_id: Option<String>,
+        
+        #[arg(long, value_name = "PATH", num_args = 0..=1, default_missing_value = ".", conflicts_with = "operation_id", help = "Pause every operation in a repository (the current one if no path is given)")]
+        repo: Option<PathBuf>,
+        
+        #[arg(long, value_name = "TIME", help = "Resume automatically at this time (e.g., '+2 hours', '17:00')")]
+        until: Option<String>,
+    },
+    
+    #[command(about = "Resume paused operations")]
+    Resume {
+        #[arg(help = "Operation ID to resume (resumes the global pause if neither this nor --repo is given)")]
+        operation_id: Option<String>,
+        
+        #[arg(long, value_name = "PATH", num_args = 0..=1, default_missing_value = ".", conflicts_with = "operation_id", help = "Resume a paused repository (the current one if no path is given)")]
+        repo: Option<PathBuf>,
+    },
+    
     #[command(about = "Manage the daemon process")]
     Daemon {
         #[command(subcommand)]
         action: DaemonAction,
     },
+    
+    #[command(about = "Start the daemon automatically (systemd or launchd)")]
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+    
+    #[command(about = "Copy the queue and logs to another storage backend")]
+    Migrate {
+        #[arg(long, value_name = "BACKEND", value_parser = store::parse_backend, help = "Backend to copy to: json or sqlite")]
+        to: Backend,
+        
+        #[arg(long, value_name = "BACKEND", value_parser = store::parse_backend, help = "Backend to copy from (defaults to the configured one)")]
+        from: Option<Backend>,
+    },
+    
+    #[command(about = "Write the queue to a file, to back it up, move it or view it in a calendar")]
+    Export {
+        #[arg(long, value_name = "FORMAT", default_value = "json", value_parser = transfer::parse_format, help = "json (can be imported again), csv or ics")]
+        format: Format,
+        
+        #[arg(short, long, value_name = "FILE", help = "Write to this file instead of stdout")]
+        output: Option<PathBuf>,
+        
+        #[arg(long, value_name = "PATH", num_args = 0..=1, default_missing_value = ".", help = "Only export operations for a repository (the current one if no path is given)")]
+        repo: Option<PathBuf>,
+    },
+    
+    #[command(about = "Add the operations from a json export to the queue")]
+    Import {
+        #[arg(help = "File written by `git delayed export`")]
+        file: PathBuf,
+        
+        #[arg(long, value_name = "POLICY", default_value = "abort", value_parser = transfer::parse_conflict, help = "When an operation's id is already queued: abort, skip, replace or new-id")]
+        on_conflict: Conflict,
+        
+        #[arg(long, value_name = "FROM=TO", value_parser = transfer::parse_remap, help = "Replace a repository path prefix, e.g. /home/old/src=/home/new/src (repeatable)")]
+        remap: Vec<(PathBuf, PathBuf)>,
+        
+        #[arg(long, help = "Show what would be imported without changing the queue")]
+        dry_run: bool,
+    },
+    
+    #[command(about = "Keep a repository's queue in its git dir instead of the global one")]
+    Repo {
+        #[command(subcommand)]
+        action: RepoAction,
+    },
+    
+    #[command(about = "Check the queue, logs and repositories for problems")]
+    Doctor {
+        #[arg(long, help = "Repair what can be repaired")]
+        fix: bool,
+    },
 }
 
 #[derive(Subcommand)]
@@ -56,7 +173,40 @@ enum ScheduleAction {
     },
     
     #[command(about = "Schedule a push only")]
-    Push,
+    Push {
+        #[arg(long, value_name = "STRATEGY", help = "If the push is rejected as non-fast-forward, fetch and rebase or merge onto the remote, then push again")]
+        on_reject: Option<IntegrationStrategy>,
+
+        #[arg(long, requires = "on_reject", help = "Command that must pass in the updated worktree before pushing again")]
+        test_cmd: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum LogsAction {
+    #[command(about = "Delete old log entries")]
+    Prune {
+        #[arg(long, value_name = "AGE", value_parser = schedule::parse_age, help = "Delete entries older than this (e.g., 90d, 2w), defaults to log_retention_days")]
+        older_than: Option<chrono::Duration>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RepoAction {
+    #[command(about = "Keep this repository's operations in .git/delayed, so they move with it")]
+    Enable,
+    
+    #[command(about = "Move this repository's operations back to the global queue")]
+    Disable,
+    
+    #[command(about = "List the repositories keeping their own queue")]
+    List,
+    
+    #[command(about = "Find repositories keeping their own queue that moved, and register them where they are now")]
+    Relocate {
+        #[arg(long, value_name = "DIR", help = "Also look in this dir, up to 3 levels down (repeatable); the dirs above each repository's old place are always searched")]
+        search: Vec<PathBuf>,
+    },
 }
 
 #[derive(Subcommand)]
@@ -68,37 +218,145 @@ enum DaemonAction {
     Stop,
     
     #[command(about = "Check daemon status")]
-    Status,
+    Status {
+        #[arg(long, help = "Print the status as JSON")]
+        json: bool,
+    },
+    
+    #[command(about = "Make the daemon reload config.json")]
+    Reload,
+    
+    #[command(about = "Follow what the daemon is doing")]
+    Events,
+    
+    #[command(about = "Run the daemon, in the foreground for systemd or other supervisors")]
+    Run {
+        #[arg(long, help = "Don't fork, log to stderr and report readiness via sd_notify")]
+        foreground: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServiceAction {
+    #[command(about = "Install and start the service")]
+    Install {
+        #[arg(long, help = "Install for the current user only instead of system-wide")]
+        user: bool,
+        
+        #[arg(long, help = "Binary the service should run (defaults to this one)")]
+        binary: Option<PathBuf>,
+        
+        #[arg(long, help = "Only write the service file, don't start it")]
+        no_start: bool,
+    },
+    
+    #[command(about = "Stop and remove the service")]
+    Uninstall {
+        #[arg(long, help = "Remove the per-user service instead of the system-wide one")]
+        user: bool,
+    },
+    
+    #[command(about = "Show whether the service is installed and running")]
+    Status {
+        #[arg(long, help = "Check the per-user service instead of the system-wide one")]
+        user: bool,
+    },
 }
 
 pub fn run() -> Result<()> {
     let cli = Cli::parse();
+    if let Some(profile) = &cli.profile {
+        storage::set_profile(profile)?;
+    }
     
     match cli.command {
-        Commands::Schedule { time_spec, action } => match action {
-            ScheduleAction::Commit { message } => {
-                handle_schedule(&time_spec, OperationType::Commit, &message)
+        Commands::Schedule { time_spec, misfire, timeout, action } => match action {
+            ScheduleAction::Commit { message } => handle_schedule(
+                &time_spec,
+                OperationType::Commit,
+                &message,
+                None,
+                misfire.unwrap_or_default(),
+                timeout,
+            ),
+            ScheduleAction::Push { on_reject, test_cmd } => {
+                let policy = on_reject.map(|strategy| RejectPolicy {
+                    strategy,
+                    test_command: test_cmd,
+                });
+                handle_schedule(
+                    &time_spec,
+                    OperationType::Push,
+                    "push",
+                    policy,
+                    misfire.unwrap_or_default(),
+                    timeout,
+                )
             }
-            ScheduleAction::Push => handle_schedule(&time_spec, OperationType::Push, "push"),
         }
         Commands::List => {
             handle_list()
         }
-        Commands::Logs => {
-            handle_logs()
-        }
+        Commands::Logs { action, repo, limit } => match action {
+            Some(LogsAction::Prune { older_than }) => handle_logs_prune(older_than),
+            None => handle_logs(repo, limit),
+        },
         Commands::Cancel { operation_id } => {
             handle_cancel(&operation_id)
         }
+        Commands::Run { operation_ids, dry_run } => {
+            handle_run(&operation_ids, dry_run)
+        }
+        Commands::Pause { operation_id, repo, until } => {
+            handle_pause(pause_target(operation_id, repo)?, until.as_deref())
+        }
+        Commands::Resume { operation_id, repo } => {
+            handle_resume(pause_target(operation_id, repo)?)
+        }
         Commands::Daemon { action } => match action {
             DaemonAction::Start => handle_daemon_start(),
             DaemonAction::Stop => handle_daemon_stop(),
-            DaemonAction::Status => handle_daemon_status(),
+            DaemonAction::Status { json } => handle_daemon_status(json),
+            DaemonAction::Reload => handle_daemon_reload(),
+            DaemonAction::Events => handle_daemon_events(),
+            DaemonAction::Run { foreground } => handle_daemon_run(foreground),
         },
+        Commands::Service { action } => match action {
+            ServiceAction::Install { user, binary, no_start } => {
+                handle_service_install(user, binary, !no_start)
+            }
+            ServiceAction::Uninstall { user } => handle_service_uninstall(user),
+            ServiceAction::Status { user } => handle_service_status(user),
+        },
+        Commands::Migrate { to, from } => {
+            handle_migrate(from, to)
+        }
+        Commands::Export { format, output, repo } => {
+            handle_export(format, output, repo)
+        }
+        Commands::Import { file, on_conflict, remap, dry_run } => {
+            handle_import(&file, on_conflict, &remap, dry_run)
+        }
+        Commands::Repo { action } => match action {
+            RepoAction::Enable => handle_repo_enable(),
+            RepoAction::Disable => handle_repo_disable(),
+            RepoAction::List => handle_repo_list(),
+            RepoAction::Relocate { search } => handle_repo_relocate(&search),
+        },
+        Commands::Doctor { fix } => {
+            doctor::report(&doctor::run(fix))
+        }
     }
 }
 
-fn handle_schedule(time_spec: &str, operation_type: OperationType, message: &str) -> Result<()> {
+fn handle_schedule(
+    time_spec: &str,
+    operation_type: OperationType,
+    message: &str,
+    on_reject: Option<RejectPolicy>,
+    misfire: MisfirePolicy,
+    timeout_secs: Option<u64>,
+) -> Result<()> {
     let repo_path = git::get_repository_path()?;
     let scheduled_time = schedule::parse_time_spec(time_spec)?;
     
@@ -117,11 +375,17 @@ fn handle_schedule(time_spec: &str, operation_type: OperationType, message: &str
         scheduled_time,
         created_at: Local::now(),
         retry_count: 0,
-        state: crate::models::OperationState::Pending,
+        state: OperationState::Pending,
         branch,
+        on_reject,
+        misfire,
+        paused_until: None,
+        state_before_pause: None,
+        timeout_secs,
     };
     
     storage::add_scheduled_operation(operation.clone())?;
+    daemon::notify_daemon();
     
     println!("✓ Operation scheduled successfully");
     println!("  ID: {}", operation.id);
@@ -131,12 +395,52 @@ fn handle_schedule(time_spec: &str, operation_type: OperationType, message: &str
     if operation_type == OperationType::Commit {
         println!("  Message: {}", message);
     }
+    if operation.misfire != MisfirePolicy::RunImmediately {
+        println!("  If missed: {}", operation.misfire);
+    }
+    if let Some(secs) = operation.timeout_secs {
+        println!("  Timeout: {}s", secs);
+    }
+    if let Some(policy) = &operation.on_reject {
+        println!("  On rejection: {}", policy.strategy);
+        if let Some(cmd) = &policy.test_command {
+            println!("  Test command: {}", cmd);
+        }
+    }
     
     Ok(())
 }
 
+// the queue as the daemon sees it, or straight from the file if it isn't running
+fn load_queue() -> Result<ScheduledOperations> {
+    match control::call("queue", Value::Null)? {
+        Some(queue) => Ok(ScheduledOperations {
+            operations: serde_json::from_value(queue)?,
+            ..Default::default()
+        }),
+        None => storage::load_scheduled_operations(),
+    }
+}
+
 fn handle_list() -> Result<()> {
-    let mut operations = storage::load_scheduled_operations()?;
+    let mut operations = load_queue()?;
+    
+    let now = Local::now();
+    let pauses = storage::load_pauses()?;
+    let until = |until: Option<chrono::DateTime<Local>>| {
+        until
+            .map(|t| format!(" until {}", t.format("%Y-%m-%d %H:%M:%S")))
+            .unwrap_or_default()
+    };
+    if let Some(global) = pauses.global.as_ref().filter(|p| p.is_active(now)) {
+        println!("⏸ All operations are paused{}", until(global.until));
+    }
+    for repo in pauses.repositories.iter().filter(|p| p.pause.is_active(now)) {
+        println!("⏸ {} is paused{}", repo.repository_path.display(), until(repo.pause.until));
+    }
+    for (path, reason) in repos::unavailable()? {
+        println!("⚠ {}: {}, its operations aren't listed", path.display(), reason);
+    }
     
     if operations.operations.is_empty() {
         println!("No scheduled operations");
@@ -148,8 +452,8 @@ fn handle_list() -> Result<()> {
     println!("\nScheduled Operations:");
     println!("{:-<130}", "");
     println!(
-        "{:<38} | {:<19} | {:<8} | {:<8} | {:<20} | {:<15} | {}",
-        "ID", "Scheduled Time", "Type", "State", "Repository", "Branch", "Message"
+        "{:<38} | {:<19} | {:<8} | {:<8} | {:<20} | {:<15} | Message",
+        "ID", "Scheduled Time", "Type", "State", "Repository", "Branch"
     );
     println!("{:-<130}", "");
     
@@ -162,12 +466,19 @@ fn handle_list() -> Result<()> {
         
         let branch_display = op.branch.as_deref().unwrap_or("-");
         
+        // held back by a global or repository pause
+        let state = if pause::is_held(&op, &pauses, now) && op.state == OperationState::Pending {
+            "held".to_string()
+        } else {
+            op.state.to_string()
+        };
+        
         println!(
             "{:<38} | {} | {:<8} | {:<8} | {:<20} | {:<15} | {}",
             op.id,
             op.scheduled_time.format("%Y-%m-%d %H:%M:%S"),
             op.operation_type,
-            op.state,
+            state,
             repo_name,
             branch_display,
             op.commit_message
@@ -179,22 +490,25 @@ fn handle_list() -> Result<()> {
     Ok(())
 }
 
-fn handle_logs() -> Result<()> {
-    let mut logs = storage::load_logs()?;
+fn handle_logs(repo: Option<PathBuf>, limit: Option<usize>) -> Result<()> {
+    let query = LogQuery {
+        repository_path: repo.map(|path| git::find_repository(&path)).transpose()?,
+        limit,
+        ..Default::default()
+    };
+    let entries = storage::query_logs(&query)?;
     
-    if logs.entries.is_empty() {
+    if entries.is_empty() {
         println!("No execution logs");
         return Ok(());
     }
     
-    logs.entries.sort_by(|a, b| b.executed_at.cmp(&a.executed_at));
-    
     println!("\nExecution Logs:");
     println!("{:-<120}", "");
-    println!("{:<19} | {:<10} | {:<20} | {:<30} | {}", "Executed At", "Status", "Repository", "Message", "ID");
+    println!("{:<19} | {:<10} | {:<20} | {:<30} | ID", "Executed At", "Status", "Repository", "Message");
     println!("{:-<120}", "");
     
-    for entry in logs.entries {
+    for entry in entries {
         let repo_name = entry.repository_path
             .file_name()
             .and_then(|n| n.to_str())
@@ -220,8 +534,16 @@ fn handle_logs() -> Result<()> {
             entry.id
         );
         
+        if let Some(output) = entry.hook_output {
+            for line in output.lines() {
+                println!("  │ {}", line);
+            }
+        }
         if let Some(error) = entry.error_message {
-            println!("  └─ Error: {}", error);
+            match entry.error_category {
+                Some(category) => println!("  └─ Error ({}): {}", category, error),
+                None => println!("  └─ Error: {}", error),
+            }
         }
     }
     
@@ -230,6 +552,20 @@ fn handle_logs() -> Result<()> {
     Ok(())
 }
 
+fn handle_logs_prune(older_than: Option<chrono::Duration>) -> Result<()> {
+    let age = match older_than {
+        Some(age) => age,
+        None => match config::load_config()?.log_retention_days {
+            Some(days) => chrono::Duration::days(days as i64),
+            None => return Err(anyhow::anyhow!("no log_retention_days in config.json, pass --older-than")),
+        },
+    };
+    
+    let removed = storage::prune_logs(age)?;
+    println!("✓ Deleted {} log entries", removed);
+    Ok(())
+}
+
 fn handle_cancel(operation_id: &str) -> Result<()> {
     let operations = storage::load_scheduled_operations()?;
     
@@ -246,12 +582,15 @@ fn handle_cancel(operation_id: &str) -> Result<()> {
         executed_at: Local::now(),
         status: ExecutionStatus::Cancelled,
         error_message: None,
+        error_category: None,
+        hook_output: None,
     };
     
     let removed = storage::remove_scheduled_operation(operation_id)?;
     
     if removed {
         storage::append_log_entry(log_entry)?;
+        daemon::notify_daemon();
         println!("✓ Operation cancelled: {}", operation_id);
     } else {
         return Err(anyhow::anyhow!("Failed to remove operation: {}", operation_id));
@@ -260,26 +599,503 @@ fn handle_cancel(operation_id: &str) -> Result<()> {
     Ok(())
 }
 
+// runs in this process through the same path the daemon uses,
+// the per-operation claim keeps the two from running the same one
+fn handle_run(operation_ids: &[String], dry_run: bool) -> Result<()> {
+    let queue = storage::load_scheduled_operations()?;
+    let operations = operation_ids
+        .iter()
+        .map(|id| {
+            queue
+                .operations
+                .iter()
+                .find(|op| op.id == *id)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("Operation not found: {}", id))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    
+    if dry_run {
+        for operation in &operations {
+            println!("{} {} in {}", operation.operation_type, operation.id, operation.repository_path.display());
+            for step in executor::plan(operation)? {
+                println!("  {}", step);
+            }
+        }
+        return Ok(());
+    }
+    
+    let config = config::load_config()?;
+    notify::configure(&config.notifications);
+    let mut failed = 0;
+    for operation in operations {
+        let id = operation.id.clone();
+        match daemon::run_operation(operation, &config)? {
+            None => {
+                println!("⚠ {} is already being run", id);
+                failed += 1;
+            }
+            Some(entry) => match entry.status {
+                ExecutionStatus::Success => println!("✓ {} done", id),
+                ExecutionStatus::Skipped => println!(
+                    "- {} skipped: {}",
+                    id,
+                    entry.error_message.unwrap_or_default()
+                ),
+                _ => {
+                    println!("✗ {} failed: {}", id, entry.error_message.unwrap_or_default());
+                    failed += 1;
+                }
+            },
+        }
+    }
+    daemon::notify_daemon();
+    notify::flush();
+    
+    if failed > 0 {
+        return Err(anyhow::anyhow!("{} operation(s) didn't run", failed));
+    }
+    Ok(())
+}
+
+fn pause_target(operation_id: Option<String>, repo: Option<PathBuf>) -> Result<PauseTarget> {
+    Ok(match (operation_id, repo) {
+        (Some(id), _) => PauseTarget::Operation(id),
+        (None, Some(path)) => PauseTarget::Repository(git::find_repository(&path)?),
+        (None, None) => PauseTarget::All,
+    })
+}
+
+fn target_params(target: &PauseTarget) -> serde_json::Map<String, Value> {
+    let mut params = serde_json::Map::new();
+    match target {
+        PauseTarget::All => {}
+        PauseTarget::Repository(path) => {
+            params.insert("repo".to_string(), Value::from(path.to_string_lossy()));
+        }
+        PauseTarget::Operation(id) => {
+            params.insert("id".to_string(), Value::from(id.as_str()));
+        }
+    }
+    params
+}
+
+// goes through the daemon when it's running so it reacts straight away
+fn handle_pause(target: PauseTarget, until: Option<&str>) -> Result<()> {
+    let until = until.map(schedule::parse_time_spec).transpose()?;
+    
+    let mut params = target_params(&target);
+    params.insert("until".to_string(), serde_json::to_value(until)?);
+    let message = match control::call("pause", Value::Object(params))? {
+        Some(result) => result["message"].as_str().unwrap_or_default().to_string(),
+        None => pause::pause(&target, until)?,
+    };
+    
+    println!("⏸ {}", message);
+    Ok(())
+}
+
+fn handle_resume(target: PauseTarget) -> Result<()> {
+    let message = match control::call("resume", Value::Object(target_params(&target)))? {
+        Some(result) => result["message"].as_str().unwrap_or_default().to_string(),
+        None => pause::resume(&target)?,
+    };
+    
+    println!("▶ {}", message);
+    Ok(())
+}
+
 fn handle_daemon_start() -> Result<()> {
     daemon::start_daemon()?;
     Ok(())
 }
 
+fn handle_daemon_reload() -> Result<()> {
+    if control::call("reload", Value::Null)?.is_none() {
+        // no socket, fall back to the signal
+        let pid = daemon::daemon_pid()?.ok_or_else(|| anyhow::anyhow!("daemon not running"))?;
+        nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(pid as i32),
+            nix::sys::signal::Signal::SIGHUP,
+        )?;
+    }
+    println!("✓ Daemon is reloading its config");
+    Ok(())
+}
+
+fn handle_daemon_events() -> Result<()> {
+    let connected = control::subscribe(|event| {
+        let data = &event["data"];
+        let detail = match data["id"].as_str() {
+            Some(id) => match data["status"].as_str() {
+                Some(status) => format!("{} {}", id, status),
+                None => id.to_string(),
+            },
+            None => String::new(),
+        };
+        println!(
+            "{} {} {}",
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            event["event"].as_str().unwrap_or("?"),
+            detail
+        );
+        true
+    })?;
+    
+    if !connected {
+        return Err(anyhow::anyhow!("daemon not running"));
+    }
+    Ok(())
+}
+
+fn handle_daemon_run(foreground: bool) -> Result<()> {
+    if foreground {
+        daemon::run_foreground()
+    } else {
+        daemon::start_daemon()
+    }
+}
+
 fn handle_daemon_stop() -> Result<()> {
-    daemon::stop_daemon()?;
-    println!("✓ Daemon stopped successfully");
+    let finishing = daemon::stop_daemon()?;
+    if finishing.is_empty() {
+        println!("✓ Daemon stopped successfully");
+    } else {
+        println!(
+            "⏳ Daemon is finishing {}, it'll stop once that's done",
+            finishing.join(", ")
+        );
+    }
     Ok(())
 }
 
-fn handle_daemon_status() -> Result<()> {
-    if daemon::is_daemon_running()? {
-        let pid = daemon::read_pid_file()?;
-        let operations = storage::load_scheduled_operations()?;
-        println!("✓ Daemon is running");
-        println!("  PID: {}", pid);
-        println!("  Scheduled operations: {}", operations.operations.len());
+fn handle_daemon_status(json: bool) -> Result<()> {
+    let running = daemon::is_daemon_running()?;
+    let pid = daemon::read_pid_file().ok();
+    // one that can't be read (cut off by a crash) is as good as none
+    let heartbeat = storage::load_heartbeat().ok().flatten();
+    // straight from the file, a hung daemon wouldn't answer on the socket
+    let operations = storage::load_scheduled_operations()?;
+    let pauses = storage::load_pauses()?;
+    let now = Local::now();
+    let paused = pauses.global.as_ref().is_some_and(|p| p.is_active(now));
+    let profile = storage::profile()?;
+    let unavailable = repos::unavailable()?;
+    
+    // only a heartbeat from the running daemon counts, an old one is left behind on exit
+    let heartbeat = heartbeat.filter(|h| running && Some(h.pid) == pid);
+    let since_tick = heartbeat.as_ref().map(|h| (now - h.last_tick).num_seconds());
+    let stale = since_tick.is_some_and(|secs| secs > daemon::HEARTBEAT_STALE_SECS);
+    
+    if json {
+        let status = serde_json::json!({
+            "running": running,
+            "pid": pid.filter(|_| running),
+            "heartbeat": heartbeat,
+            "stale": stale,
+            "paused": paused,
+            "scheduled": operations.operations.len(),
+            "unavailable_queues": unavailable.iter().map(|(path, _)| path).collect::<Vec<_>>(),
+            "profile": profile,
+            "storage_dir": storage::get_storage_dir()?,
+        });
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+    
+    let for_profile = profile.map(|p| format!(" for profile {}", p)).unwrap_or_default();
+    if !running {
+        println!("✗ Daemon is not running{}", for_profile);
+        return Ok(());
+    }
+    
+    println!("✓ Daemon is running{}", for_profile);
+    match pid {
+        Some(pid) if daemon::is_git_delayed_process(pid) => println!("  PID: {}", pid),
+        Some(pid) => println!("  ⚠ PID file says {}, but that isn't a git-delayed process", pid),
+        None => println!("  ⚠ PID file is missing"),
+    }
+    println!("  Scheduled operations: {}", operations.operations.len());
+    for (path, reason) in &unavailable {
+        println!("  ⚠ {}: {}, its operations won't run", path.display(), reason);
+    }
+    println!("  Data: {}", storage::get_storage_dir()?.display());
+    if paused {
+        println!("  ⏸ Paused");
+    }
+    
+    let Some(heartbeat) = heartbeat else {
+        println!("  ⚠ No heartbeat yet");
+        return Ok(());
+    };
+    println!("  Version: {}", heartbeat.version);
+    println!(
+        "  Started: {} (up {})",
+        heartbeat.started_at.format("%Y-%m-%d %H:%M:%S"),
+        format_age(now - heartbeat.started_at)
+    );
+    println!("  Last tick: {} ago", format_age(now - heartbeat.last_tick));
+    for id in &heartbeat.running {
+        println!("  Running: {}", id);
+    }
+    if let Some(next) = &heartbeat.next_due {
+        println!("  Next due: {} at {}", next.id, next.scheduled_time.format("%Y-%m-%d %H:%M:%S"));
+    }
+    println!("  Failures since start: {}", heartbeat.failures);
+    
+    if stale {
+        println!(
+            "  ⚠ Heartbeat is stale, the daemon may be hung{}",
+            if heartbeat.running.is_empty() { "" } else { " on the running operation" }
+        );
+    }
+    if stale {
+        return Ok(());
+    }
+    match control::call("status", Value::Null) {
+        Ok(Some(_)) => {}
+        Ok(None) => println!("  ⚠ Control socket isn't answering"),
+        Err(e) => println!("  ⚠ Control socket error: {}", e),
+    }
+    Ok(())
+}
+
+fn format_age(age: chrono::Duration) -> String {
+    let secs = age.num_seconds().max(0);
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+fn service_options(user: bool, binary: Option<PathBuf>) -> Result<service::ServiceOptions> {
+    let binary = match binary {
+        Some(binary) => binary,
+        None => std::env::current_exe()?,
+    };
+    // an absolute path, the service doesn't run in this directory
+    let home = match std::env::var_os(storage::HOME_ENV).filter(|home| !home.is_empty()) {
+        Some(home) => Some(std::path::absolute(home)?),
+        None => None,
+    };
+    Ok(service::ServiceOptions {
+        binary,
+        user,
+        profile: storage::profile()?,
+        home,
+    })
+}
+
+fn handle_service_install(user: bool, binary: Option<PathBuf>, start: bool) -> Result<()> {
+    let options = service_options(user, binary)?;
+    if !options.binary.exists() {
+        return Err(anyhow::anyhow!("binary not found: {}", options.binary.display()));
+    }
+    
+    let (path, outcome) = service::install(&options, start)?;
+    match outcome {
+        service::WriteOutcome::Created => println!("✓ Service installed"),
+        service::WriteOutcome::Updated => println!("✓ Service updated"),
+        service::WriteOutcome::Unchanged => println!("✓ Service already installed"),
+    }
+    println!("  Service file: {}", path.display());
+    println!("  Binary: {}", options.binary.display());
+    Ok(())
+}
+
+fn handle_service_uninstall(user: bool) -> Result<()> {
+    if service::uninstall(user, storage::profile()?.as_deref())? {
+        println!("✓ Service removed");
     } else {
-        println!("✗ Daemon is not running");
+        println!("✗ Service is not installed");
+    }
+    Ok(())
+}
+
+fn handle_service_status(user: bool) -> Result<()> {
+    let status = service::status(&service_options(user, None)?)?;
+    
+    if !status.installed {
+        println!("✗ Service is not installed");
+        return Ok(());
+    }
+    
+    println!("✓ Service is installed");
+    println!("  Service file: {}", status.path.display());
+    if !status.up_to_date {
+        println!("  ⚠ Service file differs from what this binary would write, run `service install` to update it");
+    }
+    println!("  Running: {}", if status.active { "yes" } else { "no" });
+    Ok(())
+}
+
+fn handle_export(format: Format, output: Option<PathBuf>, repo: Option<PathBuf>) -> Result<()> {
+    let mut operations = load_queue()?.operations;
+    if let Some(repo) = repo {
+        let repo = git::find_repository(&repo)?;
+        operations.retain(|op| op.repository_path == repo);
+    }
+    operations.sort_by_key(|op| op.scheduled_time);
+    
+    let exported = transfer::export(&operations, format)?;
+    match output {
+        Some(path) => {
+            std::fs::write(&path, exported)?;
+            println!("✓ Exported {} operation(s) to {} as {}", operations.len(), path.display(), format);
+        }
+        None => print!("{}", exported),
+    }
+    Ok(())
+}
+
+fn handle_import(file: &std::path::Path, conflict: Conflict, remaps: &[(PathBuf, PathBuf)], dry_run: bool) -> Result<()> {
+    let incoming = transfer::read(file)?;
+    let queued = load_queue()?.operations;
+    let queued_ids = queued.iter().map(|op| op.id.clone()).collect();
+    let plan = transfer::plan(incoming, &queued_ids, conflict, remaps, transfer::find_repository)?;
+    
+    for (id, reason) in plan.skipped.iter().chain(&plan.invalid) {
+        println!("  skipping {}: {}", id, reason);
+    }
+    if !plan.invalid.is_empty() {
+        println!("  use --remap FROM=TO if the repositories are somewhere else on this machine");
+    }
+    
+    if dry_run {
+        for op in &plan.add {
+            let replacing = if plan.replace.contains(&op.id) { " (replacing the queued one)" } else { "" };
+            println!(
+                "would import {} {} for {} at {}{}",
+                op.operation_type,
+                op.id,
+                op.repository_path.display(),
+                op.scheduled_time.format("%Y-%m-%d %H:%M:%S"),
+                replacing
+            );
+        }
+        return Ok(());
+    }
+    
+    for op in &plan.add {
+        match queued.iter().find(|queued| plan.replace.contains(&op.id) && queued.id == op.id) {
+            Some(old) => replace_operation(old, op.clone())?,
+            None => storage::add_scheduled_operation(op.clone())?,
+        }
+    }
+    if !plan.add.is_empty() {
+        daemon::notify_daemon();
+    }
+    
+    println!("✓ Imported {} operation(s), skipped {}", plan.add.len(), plan.skipped.len() + plan.invalid.len());
+    if !plan.replace.is_empty() {
+        println!("  Replaced {} queued operation(s)", plan.replace.len());
+    }
+    Ok(())
+}
+
+// swap a queued operation for an imported one with the same id, in one transaction if it
+// stays in the same repository, otherwise the old one is put back if adding the new one fails
+fn replace_operation(old: &ScheduledOperation, new: ScheduledOperation) -> Result<()> {
+    if old.repository_path == new.repository_path && storage::update_scheduled_operation(new.clone())? {
+        return Ok(());
+    }
+    storage::remove_scheduled_operation(&old.id)?;
+    if let Err(e) = storage::add_scheduled_operation(new) {
+        storage::add_scheduled_operation(old.clone())?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn handle_repo_enable() -> Result<()> {
+    let repo_path = git::get_repository_path()?;
+    let moved = repos::enable(&repo_path)?;
+    daemon::notify_daemon();
+    
+    println!("✓ {} keeps its queue in {}", repo_path.display(), repos::queue_path(&git::git_dir(&repo_path)?).display());
+    if moved > 0 {
+        println!("  Moved {} operation(s) from the global queue", moved);
+    }
+    Ok(())
+}
+
+fn handle_repo_disable() -> Result<()> {
+    let repo_path = git::get_repository_path()?;
+    let moved = repos::disable(&repo_path)?;
+    daemon::notify_daemon();
+    
+    println!("✓ {} uses the global queue again", repo_path.display());
+    if moved > 0 {
+        println!("  Moved {} operation(s) back", moved);
+    }
+    Ok(())
+}
+
+fn handle_repo_list() -> Result<()> {
+    let registered = repos::registered()?;
+    if registered.is_empty() {
+        println!("No repositories keep their own queue");
+        return Ok(());
+    }
+    
+    for repository in registered {
+        match repos::locate(&repository) {
+            Some(queue) => {
+                let operations = match queue.operations() {
+                    Ok(operations) => format!("{} operation(s)", operations.len()),
+                    Err(e) => format!("unreadable: {:#}", e),
+                };
+                println!("  {} ({})", queue.workdir.display(), operations);
+            }
+            None => println!(
+                "  {} (not found, run `git delayed repo relocate` if it moved)",
+                repository.git_dir.display()
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn handle_repo_relocate(search: &[PathBuf]) -> Result<()> {
+    let results = repos::relocate(search)?;
+    if results.is_empty() {
+        println!("✓ Every repository keeping its own queue is where it was");
+        return Ok(());
+    }
+    
+    let mut found = 0;
+    for (repository, workdir) in &results {
+        match workdir {
+            Some(workdir) => {
+                found += 1;
+                println!("✓ {} is now {}", repository.git_dir.display(), workdir.display());
+            }
+            None => println!("✗ {} not found, try --search <dir>", repository.git_dir.display()),
+        }
+    }
+    if found > 0 {
+        daemon::notify_daemon();
+    }
+    Ok(())
+}
+
+fn handle_migrate(from: Option<Backend>, to: Backend) -> Result<()> {
+    let config = config::load_config()?;
+    let from = from.unwrap_or(config.storage);
+    if from == to {
+        return Err(anyhow::anyhow!("already using {}, nothing to migrate", to));
+    }
+    // the daemon would keep writing to the old backend halfway through the copy
+    if daemon::is_daemon_running()? {
+        return Err(anyhow::anyhow!("stop the daemon first: git delayed daemon stop"));
+    }
+    
+    let (operations, entries) = store::migrate(store::open(from, &config)?.as_ref(), store::open(to, &config)?.as_ref())?;
+    println!("✓ Copied {} operation(s) and {} log entries from {} to {}", operations, entries, from, to);
+    if config.storage != to {
+        println!("  Set \"storage\": \"{}\" in config.json to use it, the {} data is left in place", to, from);
     }
     Ok(())
 }
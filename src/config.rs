@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::time::Duration;
+
+use crate::logfile::Rotation;
+use crate::models::ScheduledOperation;
+use crate::notify::Sink;
+use crate::storage;
+use crate::store::Backend;
+
+// the longest an operation or hook may be given, larger timeouts are capped to it
+pub const MAX_TIMEOUT_SECS: u64 = 7 * 24 * 60 * 60;
+
+// user settings, read from config.json in the storage dir
+// every field has a default so the file is optional and can be partial
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Config {
+    // how many repositories the daemon works on at the same time
+    pub max_parallel: usize,
+    // how long one operation may take before its git processes are killed
+    pub operation_timeout_secs: u64,
+    // how long each hook script may run
+    pub hook_timeout_secs: u64,
+    // what a failing pre-operation hook does to the operation
+    pub pre_hook_failure: PreHookFailure,
+    // give up on an operation after this many retries, retry forever if not set
+    pub max_retries: Option<u32>,
+    // where to send operation outcomes
+    pub notifications: Vec<Sink>,
+    // serve Prometheus metrics on 127.0.0.1 at this port, off if not set
+    pub metrics_port: Option<u16>,
+    // where the queue and logs are kept, json or sqlite
+    pub storage: Backend,
+    // the daemon deletes log entries older than this, kept forever if not set
+    pub log_retention_days: Option<u32>,
+    // the json backend starts a new log file once the current one is this big or old
+    pub log_rotate_bytes: u64,
+    pub log_rotate_days: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PreHookFailure {
+    // try again after the retry delay
+    #[default]
+    Defer,
+    // log it as failed and take it off the queue
+    Fail,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_parallel: 4,
+            operation_timeout_secs: 600,
+            hook_timeout_secs: 60,
+            pre_hook_failure: PreHookFailure::Defer,
+            max_retries: None,
+            notifications: Vec::new(),
+            metrics_port: None,
+            storage: Backend::Json,
+            log_retention_days: None,
+            log_rotate_bytes: 10 * 1024 * 1024,
+            log_rotate_days: 30,
+        }
+    }
+}
+
+impl Config {
+    // the operation's own timeout if it has one, else the configured default
+    pub fn operation_timeout(&self, operation: &ScheduledOperation) -> Duration {
+        let secs = operation.timeout_secs.unwrap_or(self.operation_timeout_secs);
+        Duration::from_secs(secs.min(MAX_TIMEOUT_SECS))
+    }
+
+    pub fn hook_timeout(&self) -> Duration {
+        Duration::from_secs(self.hook_timeout_secs.min(MAX_TIMEOUT_SECS))
+    }
+
+    pub fn log_rotation(&self) -> Rotation {
+        Rotation {
+            max_bytes: self.log_rotate_bytes,
+            max_age_days: self.log_rotate_days,
+        }
+    }
+}
+
+pub fn load_config() -> Result<Config> {
+    let path = storage::get_config_file_path()?;
+    if !path.exists() {
+        return Ok(Config::default());
+    }
+
+    let content = fs::read_to_string(&path)?;
+    if content.trim().is_empty() {
+        return Ok(Config::default());
+    }
+
+    let mut config: Config =
+        serde_json::from_str(&content).with_context(|| format!("bad config in {}", path.display()))?;
+    config.operation_timeout_secs = config.operation_timeout_secs.min(MAX_TIMEOUT_SECS);
+    config.hook_timeout_secs = config.hook_timeout_secs.min(MAX_TIMEOUT_SECS);
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_huge_timeouts_are_capped() {
+        let config: Config = serde_json::from_str(r#"{"operation_timeout_secs": 18446744073709551615}"#)
+            .unwrap();
+        let mut operation: ScheduledOperation = serde_json::from_value(serde_json::json!({
+            "id": "a",
+            "repository_path": "/src/app",
+            "operation_type": "Push",
+            "commit_message": "push",
+            "scheduled_time": "2025-01-01T09:00:00+01:00",
+            "created_at": "2025-01-01T08:00:00+01:00",
+            "retry_count": 0,
+            "state": "Pending",
+            "branch": null,
+            "on_reject": null,
+            "misfire": "RunImmediately",
+            "paused_until": null,
+            "state_before_pause": null,
+            "timeout_secs": null
+        }))
+        .unwrap();
+        assert_eq!(config.operation_timeout(&operation), Duration::from_secs(MAX_TIMEOUT_SECS));
+        operation.timeout_secs = Some(u64::MAX);
+        assert_eq!(Config::default().operation_timeout(&operation), Duration::from_secs(MAX_TIMEOUT_SECS));
+    }
+
+    #[test]
+    fn test_partial_config_uses_defaults() {
+        let config: Config = serde_json::from_str("{}").unwrap();
+        assert_eq!(config.max_parallel, 4);
+        assert_eq!(config.operation_timeout_secs, 600);
+    }
+
+    #[test]
+    fn test_config_overrides() {
+        let config: Config =
+            serde_json::from_str(r#"{"max_parallel": 1, "pre_hook_failure": "fail"}"#).unwrap();
+        assert_eq!(config.max_parallel, 1);
+        assert_eq!(config.pre_hook_failure, PreHookFailure::Fail);
+    }
+}
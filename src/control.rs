@@ -0,0 +1,322 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::daemon;
+use crate::pause::{self, PauseTarget};
+use crate::signals;
+use crate::storage;
+
+// JSON-RPC 2.0 over a unix socket in a private dir in the storage dir, one message per line
+// methods: status, queue, run-now, pause, resume, reload, subscribe
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    pub jsonrpc: String,
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+// connections that asked for events
+static SUBSCRIBERS: Mutex<Vec<Sender<Value>>> = Mutex::new(Vec::new());
+
+// send an event to every subscriber, dropping the ones that went away
+pub fn publish(event: &str, data: Value) {
+    let notification = json!({
+        "jsonrpc": "2.0",
+        "method": "event",
+        "params": { "event": event, "data": data },
+    });
+    let mut subscribers = SUBSCRIBERS.lock().unwrap_or_else(|e| e.into_inner());
+    subscribers.retain(|subscriber| subscriber.send(notification.clone()).is_ok());
+}
+
+// bind the socket and serve it from a background thread
+// only called while holding the daemon lock, so an existing socket is stale
+pub fn start_server() -> Result<()> {
+    let path = storage::get_socket_path()?;
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+
+    // the socket is only for the owning user, its dir keeps others from even connecting
+    let dir = storage::get_socket_dir()?;
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("couldn't create {}", dir.display()))?;
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("couldn't bind {}", path.display()))?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let _ = serve_connection(stream);
+            });
+        }
+    });
+    Ok(())
+}
+
+pub fn remove_socket() -> Result<()> {
+    let path = storage::get_socket_path()?;
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+fn serve_connection(stream: UnixStream) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    let reader = BufReader::new(stream);
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request: Request = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                send(&mut writer, &error_response(Value::Null, PARSE_ERROR, e.to_string()))?;
+                continue;
+            }
+        };
+
+        // subscribing turns the connection into a one-way event stream
+        if request.method == "subscribe" {
+            send(&mut writer, &ok_response(request.id, json!({ "subscribed": true })))?;
+            return stream_events(writer);
+        }
+
+        let response = match dispatch(&request.method, &request.params) {
+            Ok(result) => ok_response(request.id, result),
+            Err((code, message)) => error_response(request.id, code, message),
+        };
+        send(&mut writer, &response)?;
+    }
+    Ok(())
+}
+
+fn stream_events(mut writer: UnixStream) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    SUBSCRIBERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(sender);
+
+    for event in receiver {
+        send(&mut writer, &event)?;
+    }
+    Ok(())
+}
+
+fn dispatch(method: &str, params: &Value) -> std::result::Result<Value, (i64, String)> {
+    let server_error = |e: anyhow::Error| (SERVER_ERROR, format!("{:#}", e));
+
+    match method {
+        "status" => Ok(daemon::status_snapshot()),
+        "queue" => {
+            let operations = storage::load_scheduled_operations().map_err(server_error)?;
+            serde_json::to_value(operations.operations).map_err(|e| server_error(e.into()))
+        }
+        "run-now" => {
+            let id = params
+                .get("id")
+                .and_then(Value::as_str)
+                .ok_or((INVALID_PARAMS, "missing id".to_string()))?;
+            match daemon::run_now(id).map_err(server_error)? {
+                true => Ok(json!({ "id": id })),
+                false => Err((INVALID_PARAMS, format!("operation not found: {}", id))),
+            }
+        }
+        "pause" => {
+            let target = pause_target(params);
+            let until = match params.get("until") {
+                None | Some(Value::Null) => None,
+                Some(until) => Some(
+                    serde_json::from_value(until.clone())
+                        .map_err(|e| (INVALID_PARAMS, format!("bad until: {}", e)))?,
+                ),
+            };
+            let message = pause::pause(&target, until).map_err(server_error)?;
+            publish("paused", json!({ "target": target, "until": until }));
+            signals::request_wakeup();
+            Ok(json!({ "message": message }))
+        }
+        "resume" => {
+            let target = pause_target(params);
+            let message = pause::resume(&target).map_err(server_error)?;
+            publish("resumed", json!({ "target": target }));
+            signals::request_wakeup();
+            Ok(json!({ "message": message }))
+        }
+        "reload" => {
+            signals::request_reload();
+            Ok(json!({ "reloading": true }))
+        }
+        _ => Err((METHOD_NOT_FOUND, format!("unknown method: {}", method))),
+    }
+}
+
+// pause and resume take an operation `id`, a `repo` path, or neither for everything
+fn pause_target(params: &Value) -> PauseTarget {
+    if let Some(id) = params.get("id").and_then(Value::as_str) {
+        PauseTarget::Operation(id.to_string())
+    } else if let Some(repo) = params.get("repo").and_then(Value::as_str) {
+        PauseTarget::Repository(repo.into())
+    } else {
+        PauseTarget::All
+    }
+}
+
+fn ok_response(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn error_response(id: Value, code: i64, message: String) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn send(writer: &mut UnixStream, message: &Value) -> Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+// connect to the daemon, None if nothing is listening
+fn connect() -> Result<Option<UnixStream>> {
+    let path = storage::get_socket_path()?;
+    match UnixStream::connect(&path) {
+        Ok(stream) => Ok(Some(stream)),
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// call a method on the running daemon
+// Ok(None) means no daemon is listening, and the caller should fall back to the files
+pub fn call(method: &str, params: Value) -> Result<Option<Value>> {
+    let Some(mut stream) = connect()? else {
+        return Ok(None);
+    };
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let request = Request {
+        jsonrpc: "2.0".to_string(),
+        id: json!(1),
+        method: method.to_string(),
+        params,
+    };
+    send(&mut stream, &serde_json::to_value(request)?)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let response: Response = serde_json::from_str(&line).context("bad response from daemon")?;
+
+    match response.error {
+        Some(error) => Err(anyhow::anyhow!("daemon: {}", error.message)),
+        None => Ok(Some(response.result.unwrap_or(Value::Null))),
+    }
+}
+
+// stream daemon events to `on_event` until it returns false or the daemon goes away
+// returns false if no daemon is listening
+pub fn subscribe(mut on_event: impl FnMut(&Value) -> bool) -> Result<bool> {
+    let Some(mut stream) = connect()? else {
+        return Ok(false);
+    };
+
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "subscribe" });
+    send(&mut stream, &request)?;
+
+    for line in BufReader::new(stream).lines() {
+        let message: Value = serde_json::from_str(&line?)?;
+        if message.get("method").and_then(Value::as_str) == Some("event")
+            && !on_event(&message["params"])
+        {
+            break;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_method() {
+        let err = dispatch("explode", &Value::Null).unwrap_err();
+        assert_eq!(err.0, METHOD_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_run_now_needs_id() {
+        let err = dispatch("run-now", &json!({})).unwrap_err();
+        assert_eq!(err.0, INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_pause_target_from_params() {
+        assert_eq!(pause_target(&Value::Null), PauseTarget::All);
+        assert_eq!(
+            pause_target(&json!({ "repo": "/src/app" })),
+            PauseTarget::Repository("/src/app".into())
+        );
+        assert_eq!(
+            pause_target(&json!({ "id": "abc", "repo": "/src/app" })),
+            PauseTarget::Operation("abc".to_string())
+        );
+    }
+
+    #[test]
+    fn test_publish_reaches_subscribers() {
+        let (sender, receiver) = mpsc::channel();
+        SUBSCRIBERS.lock().unwrap().push(sender);
+
+        publish("paused", json!({}));
+
+        let event = receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(event["method"], "event");
+        assert_eq!(event["params"]["event"], "paused");
+    }
+}
@@ -1,14 +1,36 @@
-use anyhow::{Context, Result};
-use chrono::{Duration as ChronoDuration, Local};
+use anyhow::Result;
+use chrono::{DateTime, Duration as ChronoDuration, Local};
 use daemonize::Daemonize;
+use fs2::FileExt;
 use std::fs;
+use std::collections::{HashSet, VecDeque};
 use std::fs::File;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Mutex, OnceLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::config::{self, PreHookFailure};
+use crate::control;
+use crate::doctor;
 use crate::executor;
-use crate::models::{ExecutionStatus, LogEntry, OperationType};
+use crate::git;
+use crate::hooks::{self, Hook};
+use crate::metrics;
+use crate::notify::{self, Event};
+use crate::pause;
+use crate::process;
+use crate::repos;
+use crate::schedule;
+use crate::sdnotify;
+use crate::signals;
+use crate::models::{
+    ErrorCategory, ExecutionStatus, Heartbeat, LogEntry, MisfirePolicy, NextDue, OperationState,
+    OperationType, Pauses, RejectPolicy, ScheduledOperation,
+};
 use crate::storage;
+use crate::wakeup::{Wakeup, Waker};
 
 pub fn write_pid_file(pid: u32) -> Result<()> {
     fs::write(storage::get_pid_file_path()?, pid.to_string())?;
@@ -28,137 +50,955 @@ pub fn delete_pid_file() -> Result<()> {
     Ok(())
 }
 
-pub fn is_process_running(pid: u32) -> bool {
-    #[cfg(unix)]
+// check the pid really belongs to a git-delayed binary
+// after a reboot the pid in daemon.pid may have been reused by anything
+pub fn is_git_delayed_process(pid: u32) -> bool {
+    #[cfg(target_os = "linux")]
     {
-        use nix::sys::signal::{kill, Signal};
-        use nix::unistd::Pid;
-        kill(Pid::from_raw(pid as i32), Signal::SIGCONT).is_ok()
+        // the link reads "git-delayed (deleted)" if the binary was upgraded underneath us
+        fs::read_link(format!("/proc/{}/exe", pid))
+            .ok()
+            .and_then(|exe| exe.file_name().map(|n| n.to_string_lossy().into_owned()))
+            .is_some_and(|name| name.starts_with("git-delayed"))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        std::process::Command::new("ps")
+            .args(["-p", &pid.to_string(), "-o", "comm="])
+            .output()
+            .map(|out| String::from_utf8_lossy(&out.stdout).trim().ends_with("git-delayed"))
+            .unwrap_or(false)
     }
-    
-    #[cfg(not(unix))]
-    false
 }
 
+// how long starting the daemon keeps trying for the single-instance lock
+// `daemon status`, `list` and doctor hold it shared for a moment to look, that's not a daemon
+const DAEMON_LOCK_WAIT: Duration = Duration::from_secs(2);
+
+// take the single-instance lock, None if another daemon holds it
+// the lock lives as long as the returned file, and the kernel drops it when we die
+pub fn acquire_daemon_lock() -> Result<Option<File>> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(storage::get_lock_file_path()?)?;
+
+    let deadline = Instant::now() + DAEMON_LOCK_WAIT;
+    loop {
+        if file.try_lock_exclusive().is_ok() {
+            return Ok(Some(file));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// whether some process holds the single-instance lock
+// looking takes it shared for a moment, which acquire_daemon_lock waits out
+pub fn daemon_lock_held() -> Result<bool> {
+    let path = storage::get_lock_file_path()?;
+    Ok(match File::open(&path) {
+        Ok(file) => match file.try_lock_shared() {
+            Ok(()) => {
+                let _ = FileExt::unlock(&file);
+                false
+            }
+            Err(_) => true,
+        },
+        Err(_) => false,
+    })
+}
+
+// the daemon lock is the source of truth, the pid file is just informational
 pub fn is_daemon_running() -> Result<bool> {
+    let running = daemon_lock_held()?;
+
+    // nobody holds the lock, so a leftover pid file is stale
+    if !running {
+        delete_pid_file()?;
+    }
+
+    Ok(running)
+}
+
+// pid of the running daemon, checked against the lock and the process table
+pub fn daemon_pid() -> Result<Option<u32>> {
+    if !is_daemon_running()? {
+        return Ok(None);
+    }
     match read_pid_file() {
-        Ok(pid) => Ok(is_process_running(pid)),
-        Err(_) => Ok(false),
+        Ok(pid) if is_git_delayed_process(pid) => Ok(Some(pid)),
+        _ => Ok(None),
     }
 }
 
+const RETRY_DELAY_MINUTES: i64 = 10;
+
+// longest the daemon sleeps without a reason to wake up
+// keeps us honest if the wall clock moves under us
+const MAX_IDLE: Duration = Duration::from_secs(60);
+
+// an operation later than this missed its time, and its misfire policy decides
+const MISFIRE_GRACE_SECS: i64 = 60;
+
+// how often the heartbeat is ticked while a batch of operations runs
+const BUSY_TICK: Duration = Duration::from_secs(15);
+
+// wall vs monotonic drift between ticks that counts as a clock jump
+const CLOCK_JUMP_SECS: i64 = 30;
+
+// how often old log entries are pruned when log_retention_days is set
+const RETENTION_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// how long `daemon stop` waits for the daemon to wrap up, one still running an operation
+// after that is left to finish it (that can take up to the operation's timeout)
+const STOP_TIMEOUT_SECS: u64 = 30;
+
+// exit codes for `daemon run`, so supervisors can tell failures apart
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_ALREADY_RUNNING: i32 = 75; // EX_TEMPFAIL
+pub const EXIT_CONFIG: i32 = 78; // EX_CONFIG
+
+// an error that should end the process with a specific exit code
+#[derive(Debug)]
+pub struct DaemonExit {
+    pub code: i32,
+    pub message: String,
+}
+
+impl fmt::Display for DaemonExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for DaemonExit {}
+
+fn exit_error(code: i32, message: String) -> anyhow::Error {
+    DaemonExit { code, message }.into()
+}
+
+// what the daemon is doing right now, for the control socket and heartbeat.json
+static STARTED_AT: OnceLock<DateTime<Local>> = OnceLock::new();
+static LAST_TICK: Mutex<Option<DateTime<Local>>> = Mutex::new(None);
+// running operation ids with the time they should be done by
+static RUNNING: Mutex<Vec<(String, Instant)>> = Mutex::new(Vec::new());
+static NEXT_DUE: Mutex<Option<NextDue>> = Mutex::new(None);
+static FAILURES: AtomicU32 = AtomicU32::new(0);
+
+// the loop ticks at least every MAX_IDLE and a busy batch every BUSY_TICK,
+// a heartbeat this old means it's stuck
+pub const HEARTBEAT_STALE_SECS: i64 = 3 * MAX_IDLE.as_secs() as i64;
+
+// None outside the daemon process
+fn heartbeat() -> Option<Heartbeat> {
+    let started_at = *STARTED_AT.get()?;
+    Some(Heartbeat {
+        pid: std::process::id(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        started_at,
+        last_tick: LAST_TICK.lock().unwrap_or_else(|e| e.into_inner()).unwrap_or(started_at),
+        running: RUNNING
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(id, _)| id.clone())
+            .collect(),
+        next_due: NEXT_DUE.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+        failures: FAILURES.load(Ordering::SeqCst),
+    })
+}
+
+pub fn last_tick() -> Option<DateTime<Local>> {
+    *LAST_TICK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write_heartbeat() {
+    if let Some(heartbeat) = heartbeat() {
+        if let Err(e) = storage::save_heartbeat(&heartbeat) {
+            log(&format!("couldn't write heartbeat: {:#}", e));
+        }
+    }
+}
+
+pub fn status_snapshot() -> serde_json::Value {
+    let paused = storage::load_pauses()
+        .ok()
+        .and_then(|pauses| pauses.global)
+        .is_some_and(|p| p.is_active(Local::now()));
+    let mut status = serde_json::to_value(heartbeat()).unwrap_or_default();
+    if let Some(status) = status.as_object_mut() {
+        status.insert("paused".to_string(), paused.into());
+    }
+    status
+}
+
+// make a queued operation due right away, false if there's no such operation
+pub fn run_now(operation_id: &str) -> Result<bool> {
+    let now = Local::now();
+    let found = storage::update_operations(|operation| {
+        if operation.id != operation_id {
+            return false;
+        }
+        operation.scheduled_time = now;
+        operation.state = OperationState::Pending;
+        operation.state_before_pause = None;
+        operation.paused_until = None;
+        true
+    })?;
+    if found == 0 {
+        return Ok(false);
+    }
+    signals::request_wakeup();
+    Ok(true)
+}
+
+// the signal handlers have to be installed first, see publish_pid
 pub fn run_daemon_loop() -> Result<()> {
-    loop {
+    let mut waker = Waker::new()?;
+    let mut config = config::load_config().map_err(|e| exit_error(EXIT_CONFIG, format!("{:#}", e)))?;
+    notify::configure(&config.notifications);
+    let _ = STARTED_AT.set(Local::now());
+    if let Err(e) = control::start_server() {
+        log(&format!("control socket unavailable: {:#}", e));
+    }
+    if let Some(port) = config.metrics_port {
+        match metrics::start_server(port) {
+            Ok(()) => log(&format!("serving metrics on http://127.0.0.1:{}/metrics", port)),
+            Err(e) => log(&format!("metrics unavailable: {:#}", e)),
+        }
+    }
+    match storage::profile()? {
+        Some(profile) => log(&format!("daemon started for profile {}", profile)),
+        None => log("daemon started"),
+    }
+    let _ = sdnotify::notify("READY=1");
+    // it can take a while with many repositories, so it doesn't hold up the queue
+    thread::spawn(self_check);
+
+    // the queue is only re-read when it changed, we were poked, or something is due
+    let mut reload = true;
+    let mut next_due: Option<DateTime<Local>> = None;
+    let mut last_tick = (Instant::now(), Local::now());
+    // since when the daemon has been watching the queue without a gap, an operation that
+    // came due after this isn't missed, just waiting for a busy batch to finish
+    let mut awake_since = last_tick.1;
+    let mut last_pruned: Option<Instant> = None;
+    let mut last_error: Option<String> = None;
+    // operations that came due while held by a pause, see select
+    let mut held: HashSet<String> = HashSet::new();
+
+    while !signals::shutdown_requested() {
+        keep_alive();
+
+        // the monotonic clock stops while the machine sleeps, the wall clock doesn't
+        // a gap between the two means we were suspended or the clock was changed
+        let (last_instant, last_wall) = last_tick;
+        last_tick = (Instant::now(), Local::now());
+        let wall_elapsed = last_tick.1 - last_wall;
+        let mono_elapsed = ChronoDuration::from_std(last_instant.elapsed()).unwrap_or_default();
+        let drift = wall_elapsed - mono_elapsed;
+        if drift.num_seconds().abs() >= CLOCK_JUMP_SECS {
+            log(&format!(
+                "wall clock jumped {}s relative to the monotonic clock (suspend or clock change)",
+                drift.num_seconds()
+            ));
+            // it woke up somewhere between the end of the gap and now, the earliest
+            // guess only counts time that was really lost
+            awake_since = (last_wall + drift).min(last_tick.1);
+            reload = true;
+        }
+
+        if signals::take_reload() {
+            match config::load_config() {
+                Ok(new_config) => {
+                    if new_config.storage != config.storage {
+                        log("the storage backend changes when the daemon restarts");
+                    }
+                    config = new_config;
+                    notify::configure(&config.notifications);
+                    log("config reloaded");
+                    control::publish("config_reloaded", serde_json::json!({}));
+                }
+                Err(e) => log(&format!("keeping old config, reload failed: {}", e)),
+            }
+            reload = true;
+        }
+
+        if last_pruned.is_none_or(|t| t.elapsed() >= RETENTION_INTERVAL) {
+            apply_retention(&config);
+            last_pruned = Some(Instant::now());
+        }
+
         let now = Local::now();
-        let mut operations = storage::load_scheduled_operations()?;
-        
-        // sort by scheduled time to process in order
-        operations.operations.sort_by_key(|op| op.scheduled_time);
-        
-        // process only the first due operation, then break
-        // this ensures sequential execution
-        for mut operation in operations.operations {
-            if operation.scheduled_time <= now {
-                storage::remove_scheduled_operation(&operation.id)?;
-                
-                // handle push operations specially
-                if operation.operation_type == OperationType::Push {
-                    match executor::execute_push_with_branch(
-                        &operation.repository_path,
-                        operation.branch.as_deref(),
-                    ) {
-                        Ok(executor::PushResult::Success(_)) => {
-                            storage::append_log_entry(LogEntry {
-                                id: operation.id,
-                                repository_path: operation.repository_path,
-                                operation_type: operation.operation_type,
-                                commit_message: operation.commit_message,
-                                scheduled_time: operation.scheduled_time,
-                                executed_at: Local::now(),
-                                status: ExecutionStatus::Success,
-                                error_message: None,
-                            })?;
-                        }
-                        Ok(executor::PushResult::NothingToPush) => {
-                            storage::append_log_entry(LogEntry {
-                                id: operation.id,
-                                repository_path: operation.repository_path,
-                                operation_type: operation.operation_type,
-                                commit_message: operation.commit_message,
-                                scheduled_time: operation.scheduled_time,
-                                executed_at: Local::now(),
-                                status: ExecutionStatus::Skipped,
-                                error_message: Some("nothing to push".to_string()),
-                            })?;
-                        }
-                        Err(e) => {
-                            operation.retry_count += 1;
-                            operation.state = crate::models::OperationState::Failing;
-                            operation.scheduled_time = Local::now() + ChronoDuration::minutes(10);
-                            
-                            storage::append_log_entry(LogEntry {
-                                id: operation.id.clone(),
-                                repository_path: operation.repository_path.clone(),
-                                operation_type: operation.operation_type.clone(),
-                                commit_message: format!("{} (retry {})", operation.commit_message, operation.retry_count),
-                                scheduled_time: operation.scheduled_time,
-                                executed_at: Local::now(),
-                                status: ExecutionStatus::Failure,
-                                error_message: Some(format!("retry {}: {}", operation.retry_count, e)),
-                            })?;
-                            
-                            storage::add_scheduled_operation(operation)?;
-                        }
+        let due = next_due.is_some_and(|t| t <= now);
+
+        if reload || due {
+            match tick(now, awake_since, &mut held, &config) {
+                Ok(Tick::Ran) => {
+                    // run everything that's due, then re-read the queue
+                    reload = true;
+                    continue;
+                }
+                Ok(Tick::Waiting { next, first_waiting }) => {
+                    next_due = next;
+                    *NEXT_DUE.lock().unwrap_or_else(|e| e.into_inner()) = first_waiting;
+                    last_error = None;
+                }
+                Err(e) => {
+                    // a broken data file mustn't take the daemon down, it's tried again
+                    // after MAX_IDLE or as soon as the file changes
+                    let message = format!("{:#}", e);
+                    if last_error.as_ref() != Some(&message) {
+                        log(&format!("couldn't process the queue: {}", message));
                     }
-                } else {
-                    // handle commit operations
-                    match executor::execute_commit(&operation.repository_path, &operation.commit_message) {
-                        Ok(_) => {
-                            storage::append_log_entry(LogEntry {
-                                id: operation.id,
-                                repository_path: operation.repository_path,
-                                operation_type: operation.operation_type,
-                                commit_message: operation.commit_message,
-                                scheduled_time: operation.scheduled_time,
-                                executed_at: Local::now(),
-                                status: ExecutionStatus::Success,
-                                error_message: None,
-                            })?;
-                        }
-                        Err(e) => {
-                            operation.retry_count += 1;
-                            operation.state = crate::models::OperationState::Failing;
-                            operation.scheduled_time = Local::now() + ChronoDuration::minutes(10);
-                            
-                            storage::append_log_entry(LogEntry {
-                                id: operation.id.clone(),
-                                repository_path: operation.repository_path.clone(),
-                                operation_type: operation.operation_type.clone(),
-                                commit_message: format!("{} (retry {})", operation.commit_message, operation.retry_count),
-                                scheduled_time: operation.scheduled_time,
-                                executed_at: Local::now(),
-                                status: ExecutionStatus::Failure,
-                                error_message: Some(format!("retry {}: {}", operation.retry_count, e)),
-                            })?;
-                            
-                            storage::add_scheduled_operation(operation)?;
+                    last_error = Some(message);
+                    next_due = None;
+                }
+            }
+            write_heartbeat();
+        }
+
+        let timeout = next_due
+            .map(|t| (t - now).to_std().unwrap_or(Duration::ZERO))
+            .unwrap_or(MAX_IDLE)
+            .min(MAX_IDLE)
+            .min(watchdog_interval().unwrap_or(MAX_IDLE));
+
+        reload = waker.wait(timeout) != Wakeup::Timeout;
+    }
+
+    log("daemon stopping");
+    notify::flush();
+    let _ = sdnotify::notify("STOPPING=1");
+    let _ = control::remove_socket();
+    Ok(())
+}
+
+enum Tick {
+    // due operations were run
+    Ran,
+    // nothing is due, wake up at `next`
+    Waiting {
+        next: Option<DateTime<Local>>,
+        first_waiting: Option<NextDue>,
+    },
+}
+
+fn tick(
+    now: DateTime<Local>,
+    awake_since: DateTime<Local>,
+    held: &mut HashSet<String>,
+    config: &config::Config,
+) -> Result<Tick> {
+    // timed pauses that ran out are lifted before looking at the queue
+    if pause::clear_expired(now)? {
+        log("pause expired, resuming");
+    }
+    let pauses = storage::load_pauses()?;
+    let mut operations = storage::load_scheduled_operations()?;
+
+    // sort by scheduled time to process in order
+    operations.operations.sort_by_key(|op| op.scheduled_time);
+    let pause_expiry = pause::next_expiry(&operations.operations, &pauses, now);
+    let selection = select(operations.operations, &pauses, now, held);
+
+    if !selection.due.is_empty() {
+        let due = apply_misfire_policies(selection.due, now, awake_since, held)?;
+        run_due_operations(due, config)?;
+        return Ok(Tick::Ran);
+    }
+    let first_waiting = selection.waiting.first().map(|op| NextDue {
+        id: op.id.clone(),
+        scheduled_time: op.scheduled_time,
+    });
+    let next = first_waiting
+        .iter()
+        .map(|next| next.scheduled_time)
+        .chain(pause_expiry)
+        .chain(selection.held_until_due)
+        .min();
+    Ok(Tick::Waiting { next, first_waiting })
+}
+
+struct Selection {
+    due: Vec<ScheduledOperation>,
+    waiting: Vec<ScheduledOperation>,
+    // the next time a held operation comes due, to notice that it did
+    held_until_due: Option<DateTime<Local>>,
+}
+
+// split the queue (sorted by time) into what's due and what's waiting
+// operations that need attention wait for the user, paused ones for a resume
+// one that comes due while it's held goes into `held`, so once it's released its
+// misfire policy sees it as late from its own time, not from when the daemon woke up
+fn select(
+    operations: Vec<ScheduledOperation>,
+    pauses: &Pauses,
+    now: DateTime<Local>,
+    held: &mut HashSet<String>,
+) -> Selection {
+    held.retain(|id| operations.iter().any(|op| &op.id == id));
+    let (blocked, ready): (Vec<_>, Vec<_>) = operations
+        .into_iter()
+        .filter(|op| op.state != OperationState::NeedsAttention)
+        .partition(|op| pause::is_blocked(op, pauses, now));
+
+    let mut held_until_due = None;
+    for operation in &blocked {
+        if operation.scheduled_time <= now {
+            held.insert(operation.id.clone());
+        } else if held_until_due.is_none() {
+            held_until_due = Some(operation.scheduled_time);
+        }
+    }
+
+    let (due, waiting) = ready.into_iter().partition(|op| op.scheduled_time <= now);
+    Selection {
+        due,
+        waiting,
+        held_until_due,
+    }
+}
+
+// the checks of `git delayed doctor`, only reported
+// repositories that moved near where they were are found again first
+fn self_check() {
+    match repos::relocate(&[]) {
+        Ok(results) => {
+            for (repository, workdir) in results {
+                if let Some(workdir) = workdir {
+                    log(&format!("found the queue of {} in {}", repository.git_dir.display(), workdir.display()));
+                }
+            }
+        }
+        Err(e) => log(&format!("couldn't look for moved repositories: {:#}", e)),
+    }
+    for finding in doctor::run(false) {
+        log(&format!("self-check: {} (run `git delayed doctor`)", finding.problem));
+    }
+}
+
+fn watchdog_interval() -> Option<Duration> {
+    static INTERVAL: OnceLock<Option<Duration>> = OnceLock::new();
+    *INTERVAL.get_or_init(sdnotify::watchdog_interval)
+}
+
+// record a tick in the heartbeat and ping the systemd watchdog if a ping is due
+// called from the loop, between operations and by the batch ticker while nothing has overrun,
+// so a hung daemon stops ticking
+fn keep_alive() {
+    static LAST_PING: Mutex<Option<Instant>> = Mutex::new(None);
+
+    *LAST_TICK.lock().unwrap_or_else(|e| e.into_inner()) = Some(Local::now());
+    write_heartbeat();
+
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    let mut last = LAST_PING.lock().unwrap_or_else(|e| e.into_inner());
+    if last.is_none_or(|t| t.elapsed() >= interval) {
+        let _ = sdnotify::notify("WATCHDOG=1");
+        *last = Some(Instant::now());
+    }
+}
+
+// drop log entries older than log_retention_days, a failure only gets logged
+fn apply_retention(config: &config::Config) {
+    let Some(days) = config.log_retention_days else {
+        return;
+    };
+    match storage::prune_logs(ChronoDuration::days(days as i64)) {
+        Ok(0) => {}
+        Ok(removed) => log(&format!("pruned {} log entries older than {} days", removed, days)),
+        Err(e) => log(&format!("couldn't prune logs: {:#}", e)),
+    }
+}
+
+// timestamped line on stderr, which ends up in daemon.err
+fn log(message: &str) {
+    eprintln!("{} {}", Local::now().format("%Y-%m-%d %H:%M:%S"), message);
+}
+
+// how long an operation was missed while the daemon was stopped, asleep or the clock jumped
+// time spent waiting for a busy batch while the daemon was awake doesn't count,
+// time held by a pause does (see select)
+fn missed_by(scheduled_time: DateTime<Local>, awake_since: DateTime<Local>) -> ChronoDuration {
+    (awake_since - scheduled_time).max(ChronoDuration::zero())
+}
+
+// sort out operations that missed their time by more than the grace period
+// returns the ones that should still run, skipped and rescheduled ones are logged
+fn apply_misfire_policies(
+    operations: Vec<ScheduledOperation>,
+    now: DateTime<Local>,
+    awake_since: DateTime<Local>,
+    held: &mut HashSet<String>,
+) -> Result<Vec<ScheduledOperation>> {
+    let mut to_run = Vec::new();
+
+    for mut operation in operations {
+        let late = if held.remove(&operation.id) {
+            (now - operation.scheduled_time).max(ChronoDuration::zero())
+        } else {
+            missed_by(operation.scheduled_time, awake_since)
+        };
+        if late.num_seconds() <= MISFIRE_GRACE_SECS {
+            to_run.push(operation);
+            continue;
+        }
+
+        let missed = format!("missed by {}", format_duration(late));
+        match operation.misfire {
+            MisfirePolicy::RunImmediately => to_run.push(operation),
+            MisfirePolicy::RunIfWithin { max_late_minutes } if late.num_minutes() < max_late_minutes => {
+                to_run.push(operation)
+            }
+            MisfirePolicy::RunIfWithin { .. } | MisfirePolicy::Skip => {
+                storage::remove_scheduled_operation(&operation.id)?;
+                log(&format!("skipping {}, {}", operation.id, missed));
+                record(log_entry(
+                    &operation,
+                    ExecutionStatus::Skipped,
+                    Some(format!("{}, skipped by misfire policy ({})", missed, operation.misfire)),
+                    None,
+                ))?;
+            }
+            MisfirePolicy::Reschedule => {
+                let original = operation.scheduled_time;
+                operation.scheduled_time = schedule::next_slot(original, now);
+                log(&format!("rescheduling {} to {}", operation.id, operation.scheduled_time));
+
+                let mut entry = log_entry(
+                    &operation,
+                    ExecutionStatus::Skipped,
+                    Some(format!(
+                        "{}, rescheduled to {}",
+                        missed,
+                        operation.scheduled_time.format("%Y-%m-%d %H:%M:%S")
+                    )),
+                    None,
+                );
+                entry.scheduled_time = original;
+                record(entry)?;
+                storage::update_scheduled_operation(operation)?;
+            }
+        }
+    }
+
+    Ok(to_run)
+}
+
+fn format_duration(duration: ChronoDuration) -> String {
+    let minutes = duration.num_minutes();
+    if minutes >= 60 * 24 {
+        format!("{}d {}h", minutes / (60 * 24), minutes % (60 * 24) / 60)
+    } else if minutes >= 60 {
+        format!("{}h {}m", minutes / 60, minutes % 60)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+// run a batch of due operations
+// different repositories run in parallel (up to max_parallel at once),
+// operations within one repository run strictly in scheduled order
+fn run_due_operations(operations: Vec<ScheduledOperation>, config: &config::Config) -> Result<()> {
+    let mut by_repo: Vec<Vec<ScheduledOperation>> = Vec::new();
+    for operation in operations {
+        match by_repo
+            .iter_mut()
+            .find(|group| group[0].repository_path == operation.repository_path)
+        {
+            Some(group) => group.push(operation),
+            None => by_repo.push(vec![operation]),
+        }
+    }
+    // everything due was skipped or rescheduled by its misfire policy
+    if by_repo.is_empty() {
+        return Ok(());
+    }
+
+    let workers = config.max_parallel.clamp(1, by_repo.len());
+    let queue = Mutex::new(VecDeque::from(by_repo));
+    let done = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        // the loop doesn't tick while the batch runs, so tick for it until an operation overruns
+        scope.spawn(|| {
+            let every = watchdog_interval().map_or(BUSY_TICK, |interval| interval.min(BUSY_TICK));
+            let mut last = Instant::now();
+            while !done.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(100));
+                if last.elapsed() >= every && !overrunning(Instant::now()) {
+                    keep_alive();
+                    last = Instant::now();
+                }
+            }
+        });
+
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| -> Result<()> {
+                    loop {
+                        let group = queue.lock().unwrap().pop_front();
+                        let Some(group) = group else {
+                            return Ok(());
+                        };
+                        for operation in group {
+                            // leave the rest queued for the next run
+                            if signals::shutdown_requested() {
+                                return Ok(());
+                            }
+                            keep_alive();
+                            run_operation(operation, config)?;
                         }
                     }
+                })
+            })
+            .collect();
+
+        let results: Vec<Result<()>> = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err(anyhow::anyhow!("worker panicked"))))
+            .collect();
+        done.store(true, Ordering::SeqCst);
+        results.into_iter().collect()
+    })
+}
+
+// whether a running operation is past its timeout, its hooks' timeouts and some slack
+fn overrunning(now: Instant) -> bool {
+    RUNNING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .any(|(_, deadline)| *deadline < now)
+}
+
+// the longest a single operation can take: the git command and every hook around it, plus slack
+fn operation_deadline(operation: &ScheduledOperation, config: &config::Config) -> Instant {
+    let timeout = config.operation_timeout(operation).as_secs();
+    let hooks = [Hook::PreOperation, Hook::PostOperation, Hook::OnFailure]
+        .into_iter()
+        .map(|hook| hooks::find_hooks(hook, &operation.repository_path).len() as u64)
+        .sum::<u64>();
+    let budget = timeout.saturating_add(hooks.saturating_mul(config.hook_timeout().as_secs()));
+    process::deadline(Duration::from_secs(budget).saturating_add(MAX_IDLE))
+}
+
+// claim an operation, take it off the queue and run it
+// shared by the daemon and `git delayed run`, so both log, retry and stash the same way
+// None if someone else is running it, or it was changed or removed since it was loaded
+pub fn run_operation(operation: ScheduledOperation, config: &config::Config) -> Result<Option<LogEntry>> {
+    let Some(_claim) = storage::claim_operation(&operation.id)? else {
+        return Ok(None);
+    };
+    // taken off the queue in the same transaction that checks it's unchanged
+    if storage::take_scheduled_operation(&operation.id, operation.scheduled_time)?.is_none() {
+        return Ok(None);
+    }
+
+    log(&format!(
+        "running {} {} in {}",
+        operation.operation_type,
+        operation.id,
+        operation.repository_path.display()
+    ));
+    control::publish(
+        "operation_started",
+        serde_json::json!({
+            "id": operation.id,
+            "operation_type": operation.operation_type,
+            "repository_path": operation.repository_path,
+        }),
+    );
+
+    let id = operation.id.clone();
+    let deadline = operation_deadline(&operation, config);
+    RUNNING.lock().unwrap_or_else(|e| e.into_inner()).push((id.clone(), deadline));
+    write_heartbeat();
+    let result = execute_operation(operation, config);
+    RUNNING.lock().unwrap_or_else(|e| e.into_inner()).retain(|(r, _)| *r != id);
+    write_heartbeat();
+    result.map(Some)
+}
+
+// poke a running daemon so it picks up queue changes straight away
+pub fn notify_daemon() {
+    #[cfg(unix)]
+    if let Ok(Some(pid)) = daemon_pid() {
+        use nix::sys::signal::{kill, Signal};
+        use nix::unistd::Pid;
+
+        let _ = kill(Pid::from_raw(pid as i32), Signal::SIGUSR1);
+    }
+}
+
+// run a single operation that has already been taken off the queue, with its hooks around it,
+// log the outcome and put it back if it should be retried
+fn execute_operation(operation: ScheduledOperation, config: &config::Config) -> Result<LogEntry> {
+    let hook_timeout = config.hook_timeout();
+    let mut hook_runs = hooks::run_hooks(Hook::PreOperation, &operation, None, hook_timeout);
+
+    let (mut entry, requeue) = match hook_runs.iter().find(|run| !run.success) {
+        Some(failed) => {
+            let reason = format!(
+                "pre-operation hook {} {}",
+                failed.path.display(),
+                failed.failure.as_deref().unwrap_or("failed")
+            );
+            if config.pre_hook_failure == PreHookFailure::Defer {
+                // not a failure of the operation itself, so it doesn't count as a retry
+                let mut deferred = operation.clone();
+                deferred.scheduled_time = Local::now() + ChronoDuration::minutes(RETRY_DELAY_MINUTES);
+                let mut entry = log_entry(
+                    &operation,
+                    ExecutionStatus::Skipped,
+                    Some(format!("deferred by {}", reason)),
+                    Some(ErrorCategory::HookRejected),
+                );
+                entry.hook_output = hooks::format_output(&hook_runs);
+                let entry = record(entry)?;
+                storage::add_scheduled_operation(deferred)?;
+                return Ok(entry);
+            }
+            let entry = log_entry(
+                &operation,
+                ExecutionStatus::Failure,
+                Some(format!("{}, not running", reason)),
+                Some(ErrorCategory::HookRejected),
+            );
+            (entry, None)
+        }
+        None => {
+            let started = Instant::now();
+            let result = execute_git(operation.clone(), config);
+            metrics::record_execution(&operation.operation_type, operation.retry_count, started.elapsed());
+            result
+        }
+    };
+
+    hook_runs.extend(hooks::run_hooks(Hook::PostOperation, &operation, Some(&entry), hook_timeout));
+    if matches!(entry.status, ExecutionStatus::Failure) {
+        hook_runs.extend(hooks::run_hooks(Hook::OnFailure, &operation, Some(&entry), hook_timeout));
+    }
+    entry.hook_output = hooks::format_output(&hook_runs);
+
+    // a transient failure that isn't going back on the queue ran out of retries
+    let exhausted = matches!(entry.status, ExecutionStatus::Failure)
+        && requeue.is_none()
+        && entry.error_category.is_some_and(|c| c.is_transient());
+    let entry = if exhausted {
+        record_as(entry, Some(Event::Exhausted))?
+    } else {
+        record(entry)?
+    };
+    if let Some(operation) = requeue {
+        storage::add_scheduled_operation(operation)?;
+    }
+    Ok(entry)
+}
+
+// the git part of an operation
+// returns its log entry, and the operation if it goes back on the queue
+fn execute_git(
+    mut operation: ScheduledOperation,
+    config: &config::Config,
+) -> (LogEntry, Option<ScheduledOperation>) {
+    let timeout = config.operation_timeout(&operation);
+    let started = Instant::now();
+
+    let result = match operation.operation_type {
+        OperationType::Push => {
+            let result = executor::execute_push_with_branch(
+                &operation.repository_path,
+                operation.branch.as_deref(),
+                timeout,
+            );
+            match (result, &operation.on_reject) {
+                (Err(e), Some(policy))
+                    if executor::error_category(&e) == ErrorCategory::NonFastForward =>
+                {
+                    // catching up gets whatever is left of the operation's time
+                    integrate_and_push(&operation, policy, timeout.saturating_sub(started.elapsed()))
                 }
-                
-                // only process one operation per loop iteration
-                break;
+                (result, _) => result,
             }
         }
-        
-        thread::sleep(Duration::from_secs(60));
+        OperationType::Commit => {
+            executor::execute_commit(&operation.repository_path, &operation.commit_message, timeout)
+                .map(executor::PushResult::Success)
+        }
+    };
+
+    match result {
+        Ok(executor::PushResult::Success(_)) => {
+            (log_entry(&operation, ExecutionStatus::Success, None, None), None)
+        }
+        Ok(executor::PushResult::NothingToPush) => (
+            log_entry(
+                &operation,
+                ExecutionStatus::Skipped,
+                Some("nothing to push".to_string()),
+                None,
+            ),
+            None,
+        ),
+        Err(e) => {
+            let category = executor::error_category(&e);
+
+            if matches!(category, ErrorCategory::Conflict | ErrorCategory::TestFailed) {
+                // keep it queued so the user can look at it, but stop retrying
+                operation.state = OperationState::NeedsAttention;
+                let entry = log_entry(
+                    &operation,
+                    ExecutionStatus::Failure,
+                    Some(format!("needs attention: {}", e)),
+                    Some(category),
+                );
+                return (entry, Some(operation));
+            }
+
+            if !category.is_transient() {
+                // retrying won't help, drop it from the queue
+                let entry = log_entry(
+                    &operation,
+                    ExecutionStatus::Failure,
+                    Some(format!("{} error, not retrying: {}", category, e)),
+                    Some(category),
+                );
+                return (entry, None);
+            }
+
+            if config.max_retries.is_some_and(|max| operation.retry_count >= max) {
+                let entry = log_entry(
+                    &operation,
+                    ExecutionStatus::Failure,
+                    Some(format!("giving up after {} retries: {}", operation.retry_count, e)),
+                    Some(category),
+                );
+                return (entry, None);
+            }
+
+            operation.retry_count += 1;
+            operation.state = OperationState::Failing;
+            operation.scheduled_time = Local::now() + ChronoDuration::minutes(RETRY_DELAY_MINUTES);
+
+            let mut entry = log_entry(
+                &operation,
+                ExecutionStatus::Failure,
+                Some(format!("retry {}: {}", operation.retry_count, e)),
+                Some(category),
+            );
+            entry.commit_message = format!("{} (retry {})", operation.commit_message, operation.retry_count);
+            (entry, Some(operation))
+        }
     }
 }
 
-pub fn start_daemon() -> Result<()> {
-    if is_daemon_running()? {
-        return Err(anyhow::anyhow!("daemon already running (pid {})", read_pid_file()?));
+// the push was rejected as non-fast-forward and the operation opted in to catching up
+fn integrate_and_push(
+    operation: &ScheduledOperation,
+    policy: &RejectPolicy,
+    timeout: Duration,
+) -> Result<executor::PushResult> {
+    let branch = match &operation.branch {
+        Some(branch) => branch.clone(),
+        None => git::get_current_branch(&operation.repository_path)?,
+    };
+    let worktree = storage::get_worktrees_dir()?.join(&operation.id);
+
+    executor::integrate_and_push(&operation.repository_path, &branch, policy, &worktree, timeout)
+        .map(executor::PushResult::Success)
+}
+
+// write an outcome to the log and tell subscribers and notification sinks about it
+fn record(entry: LogEntry) -> Result<LogEntry> {
+    let event = match entry.status {
+        ExecutionStatus::Success => Some(Event::Success),
+        ExecutionStatus::Failure => Some(Event::Failure),
+        ExecutionStatus::Skipped => Some(Event::Skipped),
+        ExecutionStatus::Cancelled => None,
+    };
+    record_as(entry, event)
+}
+
+fn record_as(entry: LogEntry, event: Option<Event>) -> Result<LogEntry> {
+    if matches!(entry.status, ExecutionStatus::Failure) {
+        FAILURES.fetch_add(1, Ordering::SeqCst);
+    }
+    metrics::record_outcome(&entry.status);
+    let published = serde_json::to_value(&entry)?;
+    storage::append_log_entry(entry.clone())?;
+    control::publish("operation_finished", published);
+    if let Some(event) = event {
+        notify::notify(event, &entry);
     }
+    Ok(entry)
+}
+
+fn log_entry(
+    operation: &ScheduledOperation,
+    status: ExecutionStatus,
+    error_message: Option<String>,
+    error_category: Option<ErrorCategory>,
+) -> LogEntry {
+    LogEntry {
+        id: operation.id.clone(),
+        repository_path: operation.repository_path.clone(),
+        operation_type: operation.operation_type.clone(),
+        commit_message: operation.commit_message.clone(),
+        scheduled_time: operation.scheduled_time,
+        executed_at: Local::now(),
+        status,
+        error_message,
+        error_category,
+        hook_output: None,
+    }
+}
+
+fn already_running() -> anyhow::Error {
+    let message = match read_pid_file() {
+        Ok(pid) => format!("daemon already running (pid {})", pid),
+        Err(_) => "daemon already running".to_string(),
+    };
+    exit_error(EXIT_ALREADY_RUNNING, message)
+}
+
+// run the daemon in the calling process, for systemd and other supervisors
+// logs go to stderr and nothing forks
+pub fn run_foreground() -> Result<()> {
+    let Some(lock) = acquire_daemon_lock()? else {
+        return Err(already_running());
+    };
+
+    publish_pid()?;
+    let result = run_daemon_loop();
+    if let Err(e) = &result {
+        log(&format!("daemon failed: {}", e));
+    }
+    delete_pid_file()?;
+    drop(lock);
+    result
+}
+
+// the CLI sends SIGUSR1 to the pid in the pid file, which kills a process that
+// doesn't handle it yet, so the handlers go in before the pid is written
+fn publish_pid() -> Result<()> {
+    signals::install()?;
+    write_pid_file(std::process::id())
+}
+
+pub fn start_daemon() -> Result<()> {
+    // taken before forking, the daemon inherits it and keeps it for its lifetime
+    let Some(lock) = acquire_daemon_lock()? else {
+        return Err(already_running());
+    };
     
     let dir = storage::get_storage_dir()?;
     let daemonize = Daemonize::new()
@@ -168,19 +1008,32 @@ pub fn start_daemon() -> Result<()> {
     
     match daemonize.start() {
         Ok(_) => {
-            write_pid_file(std::process::id())?;
-            run_daemon_loop()
+            publish_pid()?;
+            let result = run_daemon_loop();
+            if let Err(e) = &result {
+                log(&format!("daemon failed: {}", e));
+            }
+            delete_pid_file()?;
+            drop(lock);
+            result
         }
         Err(e) => Err(anyhow::anyhow!("daemonize failed: {}", e)),
     }
 }
 
-pub fn stop_daemon() -> Result<()> {
+// returns the operations the daemon is still finishing, empty once it's stopped
+pub fn stop_daemon() -> Result<Vec<String>> {
     if !is_daemon_running()? {
         return Err(anyhow::anyhow!("daemon not running"));
     }
     
     let pid = read_pid_file()?;
+    if !is_git_delayed_process(pid) {
+        return Err(anyhow::anyhow!(
+            "pid {} from the pid file isn't git-delayed, not signalling it",
+            pid
+        ));
+    }
     
     #[cfg(unix)]
     {
@@ -189,18 +1042,167 @@ pub fn stop_daemon() -> Result<()> {
         
         kill(Pid::from_raw(pid as i32), Signal::SIGTERM)?;
         
-        for _ in 0..10 {
+        // give a running operation time to finish, the lock goes away when it exits
+        for _ in 0..STOP_TIMEOUT_SECS * 2 {
             thread::sleep(Duration::from_millis(500));
-            if !is_process_running(pid) {
+            if !is_daemon_running()? {
                 break;
             }
         }
         
-        if is_process_running(pid) {
-            return Err(anyhow::anyhow!("daemon didn't stop"));
+        if is_daemon_running()? {
+            let finishing = finishing_operations(pid);
+            if finishing.is_empty() {
+                return Err(anyhow::anyhow!("daemon didn't stop"));
+            }
+            return Ok(finishing);
         }
     }
     
     delete_pid_file()?;
-    Ok(())
+    Ok(Vec::new())
+}
+
+// what the daemon with `pid` said it was running in its last heartbeat
+fn finishing_operations(pid: u32) -> Vec<String> {
+    match storage::load_heartbeat() {
+        Ok(Some(heartbeat)) if heartbeat.pid == pid => heartbeat.running,
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Pause;
+    use uuid::Uuid;
+
+    fn overdue(misfire: MisfirePolicy, late: ChronoDuration) -> ScheduledOperation {
+        let now = Local::now();
+        ScheduledOperation {
+            id: Uuid::new_v4().to_string(),
+            repository_path: std::env::temp_dir().join(format!("git-delayed-missing-{}", Uuid::new_v4())),
+            operation_type: OperationType::Push,
+            commit_message: "push".to_string(),
+            scheduled_time: now - late,
+            created_at: now - late,
+            retry_count: 0,
+            state: OperationState::Pending,
+            branch: Some("main".to_string()),
+            on_reject: None,
+            misfire,
+            paused_until: None,
+            state_before_pause: None,
+            timeout_secs: None,
+        }
+    }
+
+    #[test]
+    fn test_looking_at_the_lock_doesnt_stop_the_daemon() {
+        let looking = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(storage::get_lock_file_path().unwrap())
+            .unwrap();
+        looking.try_lock_shared().unwrap();
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            FileExt::unlock(&looking).unwrap();
+        });
+
+        let lock = acquire_daemon_lock().unwrap();
+        assert!(lock.is_some());
+        release.join().unwrap();
+    }
+
+    #[test]
+    fn test_all_due_operations_skipped() {
+        let now = Local::now();
+        let operation = overdue(MisfirePolicy::Skip, ChronoDuration::hours(2));
+        storage::add_scheduled_operation(operation.clone()).unwrap();
+
+        let mut held = HashSet::new();
+        let to_run = apply_misfire_policies(vec![operation.clone()], now, now, &mut held).unwrap();
+        assert!(to_run.is_empty());
+        run_due_operations(to_run, &config::Config::default()).unwrap();
+
+        let queue = storage::load_scheduled_operations().unwrap();
+        assert!(!queue.operations.iter().any(|op| op.id == operation.id));
+    }
+
+    #[test]
+    fn test_operation_deadline_covers_its_timeout() {
+        let config = config::Config::default();
+        let mut operation = overdue(MisfirePolicy::RunImmediately, ChronoDuration::zero());
+        let start = Instant::now();
+        let timeout = Duration::from_secs(config.operation_timeout_secs);
+        assert!(operation_deadline(&operation, &config) >= start + timeout);
+
+        operation.timeout_secs = Some(3600);
+        let deadline = operation_deadline(&operation, &config);
+        assert!(deadline >= start + Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn test_waiting_for_a_busy_batch_isnt_a_misfire() {
+        let now = Local::now();
+        // came due an hour ago, but the daemon has been up for two
+        let operation = overdue(MisfirePolicy::Skip, ChronoDuration::hours(1));
+        let awake_since = now - ChronoDuration::hours(2);
+        let mut held = HashSet::new();
+        let to_run = apply_misfire_policies(vec![operation.clone()], now, awake_since, &mut held).unwrap();
+        assert_eq!(to_run.len(), 1);
+        assert_eq!(to_run[0].id, operation.id);
+    }
+
+    #[test]
+    fn test_time_held_by_a_pause_is_a_misfire() {
+        let start = Local::now();
+        let awake_since = start - ChronoDuration::hours(3);
+        let mut held = HashSet::new();
+        let paused = Pauses {
+            global: Some(Pause { since: start, until: None }),
+            repositories: Vec::new(),
+        };
+
+        let mut skip = overdue(MisfirePolicy::Skip, -ChronoDuration::minutes(10));
+        skip.id = uuid::Uuid::new_v4().to_string();
+        let within_30m = MisfirePolicy::RunIfWithin { max_late_minutes: 30 };
+        let mut within = overdue(within_30m, -ChronoDuration::minutes(10));
+        within.id = uuid::Uuid::new_v4().to_string();
+        let mut recent = overdue(MisfirePolicy::Skip, -ChronoDuration::minutes(100));
+        recent.id = uuid::Uuid::new_v4().to_string();
+        let queue = vec![skip.clone(), within.clone(), recent.clone()];
+        for operation in &queue {
+            storage::add_scheduled_operation(operation.clone()).unwrap();
+        }
+
+        // still paused as they come due
+        let selection = select(queue.clone(), &paused, start, &mut held);
+        assert!(selection.due.is_empty());
+        assert_eq!(selection.held_until_due, Some(skip.scheduled_time));
+        let selection = select(queue.clone(), &paused, start + ChronoDuration::minutes(20), &mut held);
+        assert!(selection.due.is_empty());
+        assert_eq!(held.len(), 2);
+
+        // resumed an hour after they were due, the daemon was awake all along
+        let now = start + ChronoDuration::minutes(70);
+        let selection = select(queue, &Pauses::default(), now, &mut held);
+        assert_eq!(selection.due.len(), 2);
+        let to_run = apply_misfire_policies(selection.due, now, awake_since, &mut held).unwrap();
+        assert!(to_run.is_empty(), "{:?}", to_run);
+        assert!(held.is_empty());
+
+        let queue = storage::load_scheduled_operations().unwrap();
+        assert!(!queue.operations.iter().any(|op| op.id == skip.id || op.id == within.id));
+        storage::remove_scheduled_operation(&recent.id).unwrap();
+    }
+
+    #[test]
+    fn test_missed_by_counts_only_the_gap() {
+        let now = Local::now();
+        assert_eq!(missed_by(now - ChronoDuration::hours(3), now - ChronoDuration::hours(1)), ChronoDuration::hours(2));
+        assert_eq!(missed_by(now - ChronoDuration::minutes(5), now - ChronoDuration::hours(1)), ChronoDuration::zero());
+    }
 }
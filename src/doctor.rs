@@ -0,0 +1,553 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config;
+use crate::daemon;
+use crate::executor;
+use crate::git;
+use crate::logfile::LogFile;
+use crate::migrations;
+use crate::repos;
+use crate::models::{ScheduledOperation, ScheduledOperations};
+use crate::sqlite;
+use crate::storage;
+use crate::store::LogQuery;
+
+// what `git delayed doctor` looks for, and what --fix does about it
+// the daemon runs the same checks without fixing when it starts
+
+const GIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct Finding {
+    pub problem: String,
+    // what --fix does (or did), or what to do by hand
+    pub remedy: String,
+    pub fixable: bool,
+    pub fixed: bool,
+}
+
+impl Finding {
+    fn manual(problem: String, remedy: impl Into<String>) -> Self {
+        Self {
+            problem,
+            remedy: remedy.into(),
+            fixable: false,
+            fixed: false,
+        }
+    }
+
+    // a problem --fix repairs with `repair`, which says what it did
+    fn fixable(fix: bool, problem: String, remedy: String, repair: impl FnOnce() -> Result<String>) -> Self {
+        let mut finding = Self {
+            problem,
+            remedy,
+            fixable: true,
+            fixed: false,
+        };
+        if fix {
+            match repair() {
+                Ok(done) => {
+                    finding.remedy = done;
+                    finding.fixed = true;
+                }
+                Err(e) => finding.remedy = format!("{} failed: {:#}", finding.remedy, e),
+            }
+        }
+        finding
+    }
+}
+
+type Check = fn(bool, &mut Vec<Finding>) -> Result<()>;
+
+pub fn run(fix: bool) -> Vec<Finding> {
+    let checks: &[(&str, Check)] = &[
+        ("queue", check_queue_file),
+        ("repository queues", check_repository_queues),
+        ("pauses", check_pauses_file),
+        ("log", check_log_files),
+        ("database", check_database),
+        ("pid file", check_pid_file),
+        ("repositories", check_repositories),
+        ("worktrees", check_worktrees),
+        ("auto-stash", check_stashes),
+    ];
+
+    let mut findings = Vec::new();
+    for (name, check) in checks {
+        if let Err(e) = check(fix, &mut findings) {
+            findings.push(Finding::manual(format!("couldn't check the {}: {:#}", name, e), "see the error above"));
+        }
+    }
+    findings
+}
+
+fn check_queue_file(fix: bool, findings: &mut Vec<Finding>) -> Result<()> {
+    check_queue(fix, storage::get_scheduled_file_path()?, findings)
+}
+
+// the queues kept in .git/delayed, one that can't be found or doesn't load is left out of
+// the queue until it's back
+fn check_repository_queues(fix: bool, findings: &mut Vec<Finding>) -> Result<()> {
+    for repository in repos::registered()? {
+        match repos::locate(&repository) {
+            Some(queue) => check_queue(fix, queue.path(), findings)?,
+            None => findings.push(Finding::manual(
+                format!(
+                    "the queue in {} isn't there (moved, deleted or on a drive that isn't mounted), its operations won't run",
+                    repository.git_dir.display()
+                ),
+                "if it moved: git delayed repo relocate [--search <dir>], if it's on a drive: mount it",
+            )),
+        }
+    }
+    Ok(())
+}
+
+// a scheduled.json: a newer version is left alone, anything else that doesn't load is corrupt
+fn check_queue(fix: bool, path: PathBuf, findings: &mut Vec<Finding>) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let error = match storage::load_queue(&path) {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+    let content = fs::read_to_string(&path)?;
+    if is_newer(&content, &migrations::QUEUE) {
+        findings.push(Finding::manual(
+            format!("{:#}", error),
+            "upgrade git-delayed, or restore the scheduled.json.v<N>.bak it was upgraded from",
+        ));
+        return Ok(());
+    }
+
+    let salvaged = salvage_queue(&content).len();
+    findings.push(Finding::fixable(
+        fix,
+        format!("{} is corrupt: {:#}", path.display(), error.root_cause()),
+        format!("set it aside and keep the {} operation(s) that still parse", salvaged),
+        || {
+            storage::locked(&path, || {
+                // read again under the lock, the CLI may have written since
+                let operations = salvage_queue(&fs::read_to_string(&path)?);
+                let kept = operations.len();
+                let quarantined = storage::quarantine(&path)?;
+                let queue = ScheduledOperations {
+                    operations,
+                    ..Default::default()
+                };
+                storage::write_atomic(&path, serde_json::to_string_pretty(&queue)?.as_bytes())?;
+                Ok(format!("kept {} operation(s), the original is {}", kept, quarantined.display()))
+            })
+        },
+    ));
+    Ok(())
+}
+
+fn check_pauses_file(fix: bool, findings: &mut Vec<Finding>) -> Result<()> {
+    let Err(error) = storage::load_pauses() else {
+        return Ok(());
+    };
+    let path = storage::get_pauses_file_path()?;
+    findings.push(Finding::fixable(
+        fix,
+        format!("{} is corrupt: {:#}", path.display(), error.root_cause()),
+        "set it aside, which lifts every pause".to_string(),
+        || {
+            storage::locked(&path, || {
+                let quarantined = storage::quarantine(&path)?;
+                fs::remove_file(&path)?;
+                Ok(format!("removed it, the original is {}", quarantined.display()))
+            })
+        },
+    ));
+    Ok(())
+}
+
+// the json backend's log, and a logs.json from before it was JSONL that can't be migrated
+fn check_log_files(fix: bool, findings: &mut Vec<Finding>) -> Result<()> {
+    let dir = storage::get_storage_dir()?;
+    let legacy = dir.join("logs.json");
+    if legacy.exists() {
+        let content = fs::read_to_string(&legacy)?;
+        if let Err(e) = serde_json::from_str::<Value>(&content) {
+            let entries = salvage_array(&content, "entries");
+            findings.push(Finding::fixable(
+                fix,
+                format!("{} is corrupt: {}", legacy.display(), e),
+                format!("set it aside and keep the {} entries that still parse", entries.len()),
+                || {
+                    let quarantined = storage::quarantine(&legacy)?;
+                    let kept = entries.len();
+                    storage::write_atomic(&legacy, json!({ "entries": entries }).to_string().as_bytes())?;
+                    Ok(format!("kept {} entries, the original is {}", kept, quarantined.display()))
+                },
+            ));
+            // the log can't be read until logs.json is migrated
+            return Ok(());
+        }
+    }
+
+    let log = LogFile::new(&dir, config::load_config()?.log_rotation());
+    for (path, skipped) in log.damaged()? {
+        findings.push(Finding::fixable(
+            fix,
+            format!("{} has {} line(s) that can't be read", path.display(), skipped),
+            "rewrite it without them".to_string(),
+            || {
+                let quarantined = log.repair(&path)?;
+                Ok(format!("rewrote it, the original is {}", quarantined.display()))
+            },
+        ));
+    }
+    Ok(())
+}
+
+fn check_database(fix: bool, findings: &mut Vec<Finding>) -> Result<()> {
+    let path = storage::get_database_path()?;
+    if !path.exists() {
+        return Ok(());
+    }
+    let problems = match sqlite::check(&path) {
+        Ok(problems) => problems,
+        Err(e) => {
+            findings.push(Finding::manual(
+                format!("{:#}", e),
+                "upgrade git-delayed, or restore a backup of the database",
+            ));
+            return Ok(());
+        }
+    };
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    findings.push(Finding::fixable(
+        fix,
+        format!("{} is damaged: {}", path.display(), problems.join("; ")),
+        "rebuild it from the rows that can still be read (stop the daemon first)".to_string(),
+        || {
+            if daemon::is_daemon_running()? {
+                return Err(anyhow::anyhow!("the daemon is running, stop it with `git delayed daemon stop`"));
+            }
+            let (quarantined, lost) = sqlite::rebuild(&path)?;
+            Ok(format!("rebuilt it, {} row(s) lost, the original is {}", lost, quarantined.display()))
+        },
+    ));
+    Ok(())
+}
+
+fn check_pid_file(fix: bool, findings: &mut Vec<Finding>) -> Result<()> {
+    let path = storage::get_pid_file_path()?;
+    if !path.exists() {
+        return Ok(());
+    }
+    if !daemon::daemon_lock_held()? {
+        findings.push(Finding::fixable(
+            fix,
+            format!("{} is left over from a daemon that isn't running", path.display()),
+            "delete it".to_string(),
+            || {
+                daemon::delete_pid_file()?;
+                Ok("deleted it".to_string())
+            },
+        ));
+        return Ok(());
+    }
+    if !daemon::read_pid_file().is_ok_and(daemon::is_git_delayed_process) {
+        findings.push(Finding::manual(
+            format!("the daemon is running but {} doesn't name it", path.display()),
+            "restart it: git delayed daemon stop && git delayed daemon start",
+        ));
+    }
+    Ok(())
+}
+
+// queued operations for repositories that were moved or deleted fail every time they run
+fn check_repositories(_fix: bool, findings: &mut Vec<Finding>) -> Result<()> {
+    // a queue that doesn't load was reported already
+    let Ok(queue) = storage::load_scheduled_operations() else {
+        return Ok(());
+    };
+    for operation in missing_repositories(&queue.operations) {
+        findings.push(Finding::manual(
+            format!(
+                "{} {} is queued for {}, which isn't a git repository anymore",
+                operation.operation_type,
+                operation.id,
+                operation.repository_path.display()
+            ),
+            format!("cancel it: git delayed cancel {}", operation.id),
+        ));
+    }
+    Ok(())
+}
+
+fn missing_repositories(operations: &[ScheduledOperation]) -> Vec<&ScheduledOperation> {
+    operations
+        .iter()
+        .filter(|op| git::git_dir(&op.repository_path).is_err())
+        .collect()
+}
+
+// scratch worktrees are named after their operation, and removed when it's done
+fn check_worktrees(fix: bool, findings: &mut Vec<Finding>) -> Result<()> {
+    for entry in fs::read_dir(storage::get_worktrees_dir()?)? {
+        let worktree = entry?.path();
+        let Some(id) = worktree.file_name().map(|n| n.to_string_lossy().into_owned()) else {
+            continue;
+        };
+        // still being used if the operation is running
+        let Some(_claim) = storage::claim_operation(&id)? else {
+            continue;
+        };
+        findings.push(Finding::fixable(
+            fix,
+            format!("{} is a worktree left over from an interrupted push", worktree.display()),
+            "remove it".to_string(),
+            || {
+                let git_dir = worktree_git_dir(&worktree);
+                fs::remove_dir_all(&worktree)?;
+                // let the repository forget about it too
+                if let Some(git_dir) = git_dir {
+                    let _ = executor::git(&git_dir, &["worktree", "prune"], GIT_TIMEOUT);
+                }
+                Ok("removed it".to_string())
+            },
+        ));
+    }
+    Ok(())
+}
+
+// the .git file of a linked worktree reads "gitdir: <repo git dir>/worktrees/<name>"
+fn worktree_git_dir(worktree: &Path) -> Option<PathBuf> {
+    let content = fs::read_to_string(worktree.join(".git")).ok()?;
+    let gitdir = PathBuf::from(content.strip_prefix("gitdir:")?.trim());
+    Some(gitdir.parent()?.parent()?.to_path_buf())
+}
+
+// a push stashes uncommitted changes and pops them afterwards
+// an auto-stash that's still there when nothing is running was never given back
+fn check_stashes(fix: bool, findings: &mut Vec<Finding>) -> Result<()> {
+    if storage::running_operations()? > 0 {
+        return Ok(());
+    }
+
+    let mut repositories = BTreeSet::new();
+    if let Ok(queue) = storage::load_scheduled_operations() {
+        repositories.extend(queue.operations.into_iter().map(|op| op.repository_path));
+    }
+    if let Ok(entries) = storage::query_logs(&LogQuery::default()) {
+        repositories.extend(entries.into_iter().map(|entry| entry.repository_path));
+    }
+
+    for repository in repositories {
+        let stashes = auto_stashes(&repository).unwrap_or_default();
+        let Some(newest) = stashes.first().cloned() else {
+            continue;
+        };
+        let problem = format!(
+            "{} has {} git-delayed auto-stash entr{} that never got popped ({})",
+            repository.display(),
+            stashes.len(),
+            if stashes.len() == 1 { "y" } else { "ies" },
+            stashes.join(", ")
+        );
+        if git::has_unstaged_changes(&repository)? {
+            findings.push(Finding::manual(
+                problem,
+                format!("commit or stash your changes, then: git -C {} stash pop {}", repository.display(), newest),
+            ));
+            continue;
+        }
+        findings.push(Finding::fixable(
+            fix,
+            problem,
+            format!("pop {}, the working tree is clean", newest),
+            || {
+                let output = executor::git(&repository, &["stash", "pop", &newest], GIT_TIMEOUT)?;
+                if !output.status.success() {
+                    return Err(anyhow::anyhow!("{}", String::from_utf8_lossy(&output.stderr).trim()));
+                }
+                Ok(format!("popped {}", newest))
+            },
+        ));
+    }
+    Ok(())
+}
+
+// stash refs of git-delayed's auto-stashes, newest first
+fn auto_stashes(repository: &Path) -> Result<Vec<String>> {
+    let output = executor::git(repository, &["stash", "list", "--format=%gd %s"], GIT_TIMEOUT)?;
+    if !output.status.success() {
+        return Ok(Vec::new());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.ends_with(executor::AUTO_STASH_MESSAGE))
+        .filter_map(|line| line.split_whitespace().next().map(str::to_string))
+        .collect())
+}
+
+fn is_newer(content: &str, schema: &migrations::Schema) -> bool {
+    serde_json::from_str::<Value>(content)
+        .ok()
+        .and_then(|value| migrations::version_of(&value).ok())
+        .is_some_and(|version| version > schema.current())
+}
+
+// every operation in a broken scheduled.json that still makes sense on its own
+fn salvage_queue(content: &str) -> Vec<ScheduledOperation> {
+    let mut queue = json!({ "operations": salvage_array(content, "operations") });
+    // the steps only fill in missing fields, so they're safe whatever version it was
+    if migrations::upgrade(&migrations::QUEUE, &mut queue, 1).is_err() {
+        return Vec::new();
+    }
+    let Value::Array(operations) = queue["operations"].take() else {
+        return Vec::new();
+    };
+    operations
+        .into_iter()
+        .filter_map(|operation| serde_json::from_value(operation).ok())
+        .collect()
+}
+
+// the complete values of the `key` array in a JSON object that may be cut off
+fn salvage_array(content: &str, key: &str) -> Vec<Value> {
+    if let Ok(value) = serde_json::from_str::<Value>(content) {
+        return value.get(key).and_then(Value::as_array).cloned().unwrap_or_default();
+    }
+
+    let Some(start) = content.find(&format!("\"{}\"", key)) else {
+        return Vec::new();
+    };
+    let Some(open) = content[start..].find('[') else {
+        return Vec::new();
+    };
+    let mut rest = &content[start + open + 1..];
+    let mut values = Vec::new();
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        let mut stream = serde_json::Deserializer::from_str(rest).into_iter::<Value>();
+        match stream.next() {
+            Some(Ok(value)) => {
+                values.push(value);
+                rest = &rest[stream.byte_offset()..];
+            }
+            _ => break,
+        }
+    }
+    values
+}
+
+// one line per finding, for the CLI and the daemon log
+pub fn describe(finding: &Finding) -> String {
+    let (mark, label) = match (finding.fixed, finding.fixable) {
+        (true, _) => ("✓", "fixed"),
+        (false, true) => ("✗", "--fix will"),
+        (false, false) => ("✗", "to fix"),
+    };
+    format!("{} {}\n    {}: {}", mark, finding.problem, label, finding.remedy)
+}
+
+// report for `git delayed doctor`, an error if problems are left
+pub fn report(findings: &[Finding]) -> Result<()> {
+    if findings.is_empty() {
+        println!("✓ No problems found");
+        return Ok(());
+    }
+    for finding in findings {
+        println!("{}", describe(finding));
+    }
+    let left = findings.iter().filter(|f| !f.fixed).count();
+    if left == 0 {
+        println!("\n✓ Fixed {} problem(s)", findings.len());
+        return Ok(());
+    }
+    let fixable = findings.iter().filter(|f| f.fixable && !f.fixed).count();
+    if fixable > 0 && fixable == left {
+        return Err(anyhow::anyhow!("{} problem(s) found, run `git delayed doctor --fix` to repair them", left));
+    }
+    Err(anyhow::anyhow!("{} problem(s) left", left))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUEUE: &str = r#"{
+  "version": 2,
+  "operations": [
+    {
+      "id": "a",
+      "repository_path": "/src/app",
+      "operation_type": "Push",
+      "commit_message": "push",
+      "scheduled_time": "2025-01-01T09:00:00+01:00",
+      "created_at": "2025-01-01T08:00:00+01:00"
+    },
+    {
+      "id": "b",
+      "repository_path": "/src/app",
+      "operation_type": "Commit",
+      "commit_message": "wip",
+      "scheduled_time": "2025-01-02T09:00:00+01:00",
+      "created_at": "2025-01-01T08:00:00+01:00"
+    }
+  ]
+}"#;
+
+    #[test]
+    fn test_salvage_cut_off_queue() {
+        // cut off halfway through the second operation
+        let cut = &QUEUE[..QUEUE.find("\"Commit\"").unwrap()];
+        let operations = salvage_queue(cut);
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].id, "a");
+    }
+
+    #[test]
+    fn test_salvage_skips_broken_operations() {
+        let broken = QUEUE.replace("\"operation_type\": \"Commit\"", "\"operation_type\": \"Rebase\"");
+        let operations = salvage_queue(&broken);
+        assert_eq!(operations.iter().map(|op| op.id.as_str()).collect::<Vec<_>>(), ["a"]);
+        assert_eq!(salvage_queue(QUEUE).len(), 2);
+        assert!(salvage_queue("garbage").is_empty());
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert!(!is_newer(QUEUE, &migrations::QUEUE));
+        assert!(is_newer(r#"{"version": 99, "operations": []}"#, &migrations::QUEUE));
+        assert!(!is_newer("{", &migrations::QUEUE));
+    }
+
+    #[test]
+    fn test_missing_repositories() {
+        let gone = crate::store::conformance::operation("gone", "/nonexistent/git-delayed-doctor");
+        let here = crate::store::conformance::operation("here", env!("CARGO_MANIFEST_DIR"));
+        let operations = [gone, here];
+        let missing: Vec<&str> = missing_repositories(&operations).iter().map(|op| op.id.as_str()).collect();
+        assert_eq!(missing, ["gone"]);
+    }
+
+    #[test]
+    fn test_fixable_reports_what_it_did() {
+        let finding = Finding::fixable(false, "broken".into(), "mend it".into(), || Ok("mended".into()));
+        assert!(!finding.fixed);
+        assert_eq!(finding.remedy, "mend it");
+
+        let finding = Finding::fixable(true, "broken".into(), "mend it".into(), || Ok("mended".into()));
+        assert!(finding.fixed);
+        assert_eq!(finding.remedy, "mended");
+
+        let finding = Finding::fixable(true, "broken".into(), "mend it".into(), || Err(anyhow::anyhow!("no")));
+        assert!(!finding.fixed);
+        assert_eq!(finding.remedy, "mend it failed: no");
+    }
+}
@@ -1,32 +1,139 @@
 use anyhow::Result;
+use std::fmt;
 use std::path::Path;
-use std::process::Command;
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
+
+use crate::process;
+use crate::models::{
+    ErrorCategory, IntegrationStrategy, OperationType, RejectPolicy, ScheduledOperation,
+};
+
+// a failed git invocation, tagged with what kind of failure it was
+#[derive(Debug)]
+pub struct GitError {
+    pub category: ErrorCategory,
+    pub message: String,
+}
+
+impl GitError {
+    pub fn new(category: ErrorCategory, message: String) -> Self {
+        Self { category, message }
+    }
+
+    // build an error from a failed git command, classifying it by its output
+    fn from_output(context: &str, output: &Output) -> Self {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let category = classify(&format!("{}\n{}", stdout, stderr));
+        // commit reports "nothing to commit" on stdout, everything else is on stderr
+        let detail = if stderr.trim().is_empty() { stdout } else { stderr };
+        Self::new(category, format!("{}: {}", context, detail.trim()))
+    }
+}
+
+impl fmt::Display for GitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for GitError {}
+
+// work out the failure category from git's output
+// order matters: protected branches are reported as hook rejections by most hosts
+pub fn classify(output: &str) -> ErrorCategory {
+    let text = output.to_lowercase();
+    let has = |needles: &[&str]| needles.iter().any(|n| text.contains(n));
+
+    if has(&[
+        "protected branch",
+        "gh006",
+        "not allowed to push code to protected branches",
+        "not allowed to force push",
+    ]) {
+        ErrorCategory::ProtectedBranch
+    } else if has(&["hook declined", "hook failed", "pre-push hook", "pre-commit hook"]) {
+        ErrorCategory::HookRejected
+    } else if has(&["non-fast-forward", "fetch first", "updates were rejected because the tip"]) {
+        ErrorCategory::NonFastForward
+    } else if has(&[
+        "authentication failed",
+        // ssh refusing the key, a bare "permission denied" is usually the filesystem
+        "permission denied (publickey",
+        "could not read username",
+        "could not read password",
+        "terminal prompts disabled",
+        "invalid username or password",
+        "the requested url returned error: 401",
+        "the requested url returned error: 403",
+        "host key verification failed",
+    ]) {
+        ErrorCategory::Auth
+    } else if has(&[
+        "could not resolve host",
+        "could not resolve hostname",
+        "connection timed out",
+        "connection refused",
+        "connection reset",
+        "network is unreachable",
+        "operation timed out",
+        "the remote end hung up unexpectedly",
+        "early eof",
+        "unable to access",
+        "temporary failure in name resolution",
+    ]) {
+        ErrorCategory::Network
+    } else if has(&["nothing to commit", "no changes added to commit", "nothing added to commit"]) {
+        ErrorCategory::NothingToCommit
+    } else if has(&["not a git repository", "does not appear to be a git repository", "no such file or directory"]) {
+        ErrorCategory::Repository
+    } else {
+        ErrorCategory::Other
+    }
+}
+
+// category of an error coming out of the executor
+// anything that isn't a GitError (io, libgit2 lookups) is treated as unknown
+pub fn error_category(error: &anyhow::Error) -> ErrorCategory {
+    error
+        .downcast_ref::<GitError>()
+        .map(|e| e.category)
+        .unwrap_or(ErrorCategory::Other)
+}
 
 #[derive(Debug)]
 pub enum PushResult {
-    Success(String),
+    Success(#[allow(dead_code)] String),
     NothingToPush,
 }
 
+// what uncommitted changes are stashed as while a push switches branches
+pub const AUTO_STASH_MESSAGE: &str = "git-delayed auto-stash";
+
+// cleanup after a failed or timed out step (checking out back, popping the stash,
+// removing a worktree) gets its own time, the operation's may already be used up
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(30);
+
 // run git commit in the specified repo
-pub fn execute_commit(repo_path: &Path, message: &str) -> Result<String> {
-    let output = Command::new("git")
-        .args(["commit", "-m", message])
-        .current_dir(repo_path)
-        .output()?;
+pub fn execute_commit(repo_path: &Path, message: &str, timeout: Duration) -> Result<String> {
+    let output = git(repo_path, &["commit", "-m", message], timeout)?;
 
     if output.status.success() {
         Ok(String::from_utf8_lossy(&output.stdout).to_string())
     } else {
-        Err(anyhow::anyhow!(
-            "commit failed: {}",
-            String::from_utf8_lossy(&output.stderr)
-        ))
+        Err(GitError::from_output("commit failed", &output).into())
     }
 }
 
 // run git push in the specified repo, optionally switching to a specific branch
-pub fn execute_push_with_branch(repo_path: &Path, branch: Option<&str>) -> Result<PushResult> {
+// every git command shares `timeout`, so a hung step can't hold up the daemon
+pub fn execute_push_with_branch(
+    repo_path: &Path,
+    branch: Option<&str>,
+    timeout: Duration,
+) -> Result<PushResult> {
+    let deadline = process::deadline(timeout);
     let current_branch = crate::git::get_current_branch(repo_path)?;
     let target_branch = branch.unwrap_or(&current_branch);
     
@@ -40,10 +147,11 @@ pub fn execute_push_with_branch(repo_path: &Path, branch: Option<&str>) -> Resul
     let mut stashed = false;
     
     if has_changes {
-        let stash = Command::new("git")
-            .args(["stash", "push", "-u", "-m", "git-delayed auto-stash"])
-            .current_dir(repo_path)
-            .output()?;
+        let stash = git(
+            repo_path,
+            &["stash", "push", "-u", "-m", AUTO_STASH_MESSAGE],
+            remaining(deadline),
+        )?;
         
         if stash.status.success() {
             stashed = true;
@@ -53,72 +161,408 @@ pub fn execute_push_with_branch(repo_path: &Path, branch: Option<&str>) -> Resul
     // switch to target branch if needed
     let mut switched = false;
     if current_branch != target_branch {
-        let checkout = Command::new("git")
-            .args(["checkout", target_branch])
-            .current_dir(repo_path)
-            .output()?;
+        let checkout = git(repo_path, &["checkout", target_branch], remaining(deadline));
         
-        if !checkout.status.success() {
-            // unstash before returning error
-            if stashed {
-                let _ = Command::new("git")
-                    .args(["stash", "pop"])
-                    .current_dir(repo_path)
-                    .output();
+        match checkout {
+            Ok(checkout) if checkout.status.success() => switched = true,
+            checkout => {
+                // unstash before returning error
+                if stashed {
+                    let _ = git(repo_path, &["stash", "pop"], CLEANUP_TIMEOUT);
+                }
+                let checkout = checkout?;
+                return Err(GitError::from_output(
+                    &format!("couldn't switch to branch {}", target_branch),
+                    &checkout,
+                )
+                .into());
             }
-            return Err(anyhow::anyhow!(
-                "couldn't switch to branch {}: {}",
-                target_branch,
-                String::from_utf8_lossy(&checkout.stderr)
-            ));
         }
-        switched = true;
     }
     
     // do the push
-    let output = Command::new("git")
-        .arg("push")
-        .current_dir(repo_path)
-        .output()?;
-    
-    let push_result = if output.status.success() {
-        Ok(PushResult::Success(format!(
+    let push_result = match git(repo_path, &["push"], remaining(deadline)) {
+        Ok(output) if output.status.success() => Ok(PushResult::Success(format!(
             "{}{}",
             String::from_utf8_lossy(&output.stdout),
             String::from_utf8_lossy(&output.stderr)
-        )))
-    } else {
-        Err(anyhow::anyhow!(
-            "push failed: {}",
-            String::from_utf8_lossy(&output.stderr)
-        ))
+        ))),
+        Ok(output) => Err(GitError::from_output("push failed", &output).into()),
+        Err(e) => Err(e),
     };
     
     // switch back to original branch if we changed it
     if switched {
-        let _ = Command::new("git")
-            .args(["checkout", &current_branch])
-            .current_dir(repo_path)
-            .output();
+        let _ = git(repo_path, &["checkout", &current_branch], CLEANUP_TIMEOUT);
     }
     
     // unstash changes if we stashed them
     if stashed {
-        let _ = Command::new("git")
-            .args(["stash", "pop"])
-            .current_dir(repo_path)
-            .output();
+        let _ = git(repo_path, &["stash", "pop"], CLEANUP_TIMEOUT);
     }
 
     push_result
 }
 
-// backward compat - push without branch switching
-pub fn execute_push(repo_path: &Path) -> Result<String> {
-    match execute_push_with_branch(repo_path, None)? {
-        PushResult::Success(msg) => Ok(msg),
-        PushResult::NothingToPush => Ok("nothing to push".to_string()),
+// bring a rejected push up to date with the remote and push again
+// everything happens in a throwaway worktree so the user's checkout is never touched
+pub fn integrate_and_push(
+    repo_path: &Path,
+    branch: &str,
+    policy: &RejectPolicy,
+    worktree_path: &Path,
+    timeout: Duration,
+) -> Result<String> {
+    let deadline = process::deadline(timeout);
+    let upstream = crate::git::upstream(repo_path, branch)?;
+    let fetch = git(repo_path, &["fetch", &upstream.remote, &upstream.branch], remaining(deadline))?;
+    if !fetch.status.success() {
+        return Err(GitError::from_output("fetch failed", &fetch).into());
+    }
+
+    let worktree = worktree_path.to_string_lossy().to_string();
+    let add = git(repo_path, &["worktree", "add", "--detach", &worktree, branch], remaining(deadline))?;
+    if !add.status.success() {
+        return Err(GitError::from_output("couldn't create worktree", &add).into());
+    }
+
+    let result = integrate_in_worktree(worktree_path, branch, &upstream, policy, deadline);
+
+    let _ = git(repo_path, &["worktree", "remove", "--force", &worktree], CLEANUP_TIMEOUT);
+    let _ = git(repo_path, &["worktree", "prune"], CLEANUP_TIMEOUT);
+
+    result
+}
+
+fn integrate_in_worktree(
+    worktree: &Path,
+    branch: &str,
+    target: &crate::git::Upstream,
+    policy: &RejectPolicy,
+    deadline: Instant,
+) -> Result<String> {
+    let upstream = target.tracking();
+
+    let integrate = match policy.strategy {
+        IntegrationStrategy::Rebase => git(worktree, &["rebase", &upstream], remaining(deadline))?,
+        IntegrationStrategy::Merge => {
+            git(worktree, &["merge", "--no-edit", &upstream], remaining(deadline))?
+        }
+    };
+
+    if !integrate.status.success() {
+        let abort = match policy.strategy {
+            IntegrationStrategy::Rebase => "rebase",
+            IntegrationStrategy::Merge => "merge",
+        };
+        let _ = git(worktree, &[abort, "--abort"], CLEANUP_TIMEOUT);
+
+        let mut error = GitError::from_output(
+            &format!("{} onto {} failed", policy.strategy, upstream),
+            &integrate,
+        );
+        error.category = ErrorCategory::Conflict;
+        return Err(error.into());
+    }
+
+    if let Some(test_command) = &policy.test_command {
+        let test = process::output_with_timeout(
+            Command::new("sh").args(["-c", test_command]).current_dir(worktree),
+            remaining(deadline),
+        )
+        .map_err(timed_out)?;
+
+        if !test.status.success() {
+            let mut error = GitError::from_output(
+                &format!("test command `{}` failed", test_command),
+                &test,
+            );
+            error.category = ErrorCategory::TestFailed;
+            return Err(error.into());
+        }
+    }
+
+    let refspec = format!("HEAD:refs/heads/{}", target.branch);
+    let push = git(worktree, &["push", &target.remote, &refspec], remaining(deadline))?;
+    if !push.status.success() {
+        return Err(GitError::from_output("push after integration failed", &push).into());
     }
+
+    Ok(format!(
+        "pushed after {} onto {}; local {} now differs from the remote, pull to sync",
+        policy.strategy, upstream, branch
+    ))
 }
 
+// what running the operation would do right now, without touching anything
+pub fn plan(operation: &ScheduledOperation) -> Result<Vec<String>> {
+    let repo_path = &operation.repository_path;
+    let mut steps = Vec::new();
+
+    match operation.operation_type {
+        OperationType::Commit => {
+            if crate::git::has_staged_changes(repo_path)? {
+                steps.push(format!("commit staged changes: {}", operation.commit_message));
+            } else {
+                steps.push("nothing is staged, the commit would fail".to_string());
+            }
+        }
+        OperationType::Push => {
+            let current_branch = crate::git::get_current_branch(repo_path)?;
+            let target_branch = operation.branch.as_deref().unwrap_or(&current_branch);
+
+            if !crate::git::needs_push(repo_path, target_branch)? {
+                steps.push(format!("nothing to push on {}, it would be logged as skipped", target_branch));
+                return Ok(steps);
+            }
 
+            let stash = crate::git::has_unstaged_changes(repo_path)?;
+            if stash {
+                steps.push("stash uncommitted changes".to_string());
+            }
+            if current_branch != target_branch {
+                steps.push(format!("check out {}", target_branch));
+            }
+
+            let upstream = crate::git::upstream(repo_path, target_branch)?;
+            let range = format!("{}..{}", upstream.tracking(), target_branch);
+            let log = git(repo_path, &["log", "--oneline", &range], CLEANUP_TIMEOUT)?;
+            if log.status.success() {
+                let commits = String::from_utf8_lossy(&log.stdout);
+                steps.push(format!(
+                    "push {} commit(s) on {} to {}",
+                    commits.lines().count(),
+                    target_branch,
+                    upstream.remote
+                ));
+                steps.extend(commits.lines().map(|c| format!("  {}", c)));
+            } else {
+                steps.push(format!("push {} to {} as a new branch", target_branch, upstream.remote));
+            }
+
+            if let Some(policy) = &operation.on_reject {
+                let test = policy
+                    .test_command
+                    .as_ref()
+                    .map(|cmd| format!(", run `{}`", cmd))
+                    .unwrap_or_default();
+                steps.push(format!(
+                    "if rejected: {} onto {} in a scratch worktree{}, push again",
+                    policy.strategy,
+                    upstream.tracking(),
+                    test
+                ));
+            }
+            if current_branch != target_branch {
+                steps.push(format!("switch back to {}", current_branch));
+            }
+            if stash {
+                steps.push("restore the stashed changes".to_string());
+            }
+        }
+    }
+
+    Ok(steps)
+}
+
+// run git non-interactively, killing it (and whatever it started) once `timeout` runs out
+pub fn git(dir: &Path, args: &[&str], timeout: Duration) -> Result<Output> {
+    process::output_with_timeout(Command::new("git").args(args).current_dir(dir), timeout)
+        .map_err(timed_out)
+}
+
+fn remaining(deadline: Instant) -> Duration {
+    deadline.saturating_duration_since(Instant::now())
+}
+
+// a killed child becomes a timeout error, anything else passes through
+fn timed_out(error: anyhow::Error) -> anyhow::Error {
+    match error.downcast::<process::TimedOut>() {
+        Ok(timed_out) => GitError::new(ErrorCategory::Timeout, timed_out.to_string()).into(),
+        Err(error) => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    fn run(dir: &Path, args: &[&str]) {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .env("GIT_AUTHOR_NAME", "test")
+            .env("GIT_AUTHOR_EMAIL", "test@example.com")
+            .env("GIT_COMMITTER_NAME", "test")
+            .env("GIT_COMMITTER_EMAIL", "test@example.com")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+    }
+
+    fn commit_file(dir: &Path, name: &str, content: &str) {
+        fs::write(dir.join(name), content).unwrap();
+        run(dir, &["add", name]);
+        run(dir, &["commit", "-m", name]);
+    }
+
+    // a bare remote plus two clones of it, "mine" (calling it `remote_name`) and "theirs"
+    // mine has its own identity, the daemon's git commands don't go through run()
+    fn diverged_clones(their_file: &str, remote_name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let root = std::env::temp_dir().join(format!("git-delayed-test-{}", uuid::Uuid::new_v4()));
+        let remote = root.join("remote.git");
+        let mine = root.join("mine");
+        let theirs = root.join("theirs");
+        fs::create_dir_all(&remote).unwrap();
+        run(&remote, &["init", "--bare", "-b", "main"]);
+        run(&root, &["clone", "--origin", remote_name, "remote.git", "mine"]);
+        run(&mine, &["config", "user.name", "test"]);
+        run(&mine, &["config", "user.email", "test@example.com"]);
+        run(&mine, &["checkout", "-b", "main"]);
+        commit_file(&mine, "base.txt", "base");
+        run(&mine, &["push", "-u", remote_name, "main"]);
+        run(&root, &["clone", "remote.git", "theirs"]);
+
+        commit_file(&theirs, their_file, "theirs");
+        run(&theirs, &["push", "origin", "main"]);
+        commit_file(&mine, "mine.txt", "mine");
+        (root, mine, theirs)
+    }
+
+    #[test]
+    fn test_integrate_rebase_and_push() {
+        let (root, mine, theirs) = diverged_clones("theirs.txt", "origin");
+
+        let err = execute_push_with_branch(&mine, Some("main"), TIMEOUT).unwrap_err();
+        assert_eq!(error_category(&err), ErrorCategory::NonFastForward);
+
+        let policy = RejectPolicy {
+            strategy: IntegrationStrategy::Rebase,
+            test_command: Some("test -f mine.txt && test -f theirs.txt".to_string()),
+        };
+        integrate_and_push(&mine, "main", &policy, &root.join("wt"), TIMEOUT).unwrap();
+        assert!(!root.join("wt").exists());
+
+        run(&theirs, &["pull", "--ff-only"]);
+        assert!(theirs.join("mine.txt").exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_integrate_with_another_upstream_remote() {
+        let (root, mine, theirs) = diverged_clones("theirs.txt", "upstream");
+
+        let policy = RejectPolicy {
+            strategy: IntegrationStrategy::Merge,
+            test_command: None,
+        };
+        let message = integrate_and_push(&mine, "main", &policy, &root.join("wt"), TIMEOUT).unwrap();
+        assert!(message.contains("upstream/main"), "{}", message);
+
+        run(&theirs, &["pull", "--ff-only"]);
+        assert!(theirs.join("mine.txt").exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_integrate_conflict_aborts() {
+        let (root, mine, _) = diverged_clones("mine.txt", "origin");
+
+        let policy = RejectPolicy {
+            strategy: IntegrationStrategy::Rebase,
+            test_command: None,
+        };
+        let err = integrate_and_push(&mine, "main", &policy, &root.join("wt"), TIMEOUT).unwrap_err();
+        assert_eq!(error_category(&err), ErrorCategory::Conflict);
+        assert!(!root.join("wt").exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_test_command_timeout() {
+        let (root, mine, _) = diverged_clones("theirs.txt", "origin");
+
+        let policy = RejectPolicy {
+            strategy: IntegrationStrategy::Rebase,
+            test_command: Some("sleep 30".to_string()),
+        };
+        let err = integrate_and_push(&mine, "main", &policy, &root.join("wt"), Duration::from_secs(2))
+            .unwrap_err();
+        assert_eq!(error_category(&err), ErrorCategory::Timeout);
+        assert!(!root.join("wt").exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_classify_non_fast_forward() {
+        let stderr = " ! [rejected]        main -> main (fetch first)\nerror: failed to push some refs";
+        assert_eq!(classify(stderr), ErrorCategory::NonFastForward);
+    }
+
+    #[test]
+    fn test_classify_protected_branch() {
+        let stderr = "remote: error: GH006: Protected branch update failed for refs/heads/main.\n ! [remote rejected] main -> main (protected branch hook declined)";
+        assert_eq!(classify(stderr), ErrorCategory::ProtectedBranch);
+    }
+
+    #[test]
+    fn test_classify_hook_rejected() {
+        let stderr = "error: failed to push some refs to 'origin'\nhint: the pre-push hook declined";
+        assert_eq!(classify(stderr), ErrorCategory::HookRejected);
+    }
+
+    #[test]
+    fn test_classify_auth() {
+        let stderr = "git@github.com: Permission denied (publickey).\nfatal: Could not read from remote repository.";
+        assert_eq!(classify(stderr), ErrorCategory::Auth);
+    }
+
+    #[test]
+    fn test_classify_https_forbidden() {
+        let stderr = "remote: Permission to x/y.git denied to someone.\nfatal: unable to access 'https://github.com/x/y.git/': The requested URL returned error: 403";
+        assert_eq!(classify(stderr), ErrorCategory::Auth);
+    }
+
+    #[test]
+    fn test_classify_filesystem_permission_denied() {
+        let stderr = "error: unable to create file src/main.rs: Permission denied\nfatal: could not reset index file";
+        assert_eq!(classify(stderr), ErrorCategory::Other);
+        let stderr = "error: insufficient permission for adding an object to repository database .git/objects";
+        assert_eq!(classify(stderr), ErrorCategory::Other);
+    }
+
+    #[test]
+    fn test_classify_network() {
+        let stderr = "fatal: unable to access 'https://github.com/x/y.git/': Could not resolve host: github.com";
+        assert_eq!(classify(stderr), ErrorCategory::Network);
+        assert!(ErrorCategory::Network.is_transient());
+    }
+
+    #[test]
+    fn test_classify_nothing_to_commit() {
+        let stdout = "On branch main\nnothing to commit, working tree clean";
+        assert_eq!(classify(stdout), ErrorCategory::NothingToCommit);
+        assert!(!ErrorCategory::NothingToCommit.is_transient());
+    }
+
+    #[test]
+    fn test_classify_unknown() {
+        assert_eq!(classify("something odd happened"), ErrorCategory::Other);
+        assert!(ErrorCategory::Other.is_transient());
+    }
+
+    #[test]
+    fn test_error_category_from_anyhow() {
+        let err: anyhow::Error = GitError::new(ErrorCategory::Auth, "nope".to_string()).into();
+        assert_eq!(error_category(&err), ErrorCategory::Auth);
+        assert_eq!(error_category(&anyhow::anyhow!("io")), ErrorCategory::Other);
+    }
+}
@@ -7,8 +7,12 @@ use std::path::{Path, PathBuf};
 // walks up the directory tree looking for .git
 pub fn get_repository_path() -> Result<PathBuf> {
     let current_dir = env::current_dir().context("couldn't get current dir")?;
+    find_repository(&current_dir)
+}
 
-    let repo = Repository::discover(&current_dir)
+// the working directory of the repo containing `path`
+pub fn find_repository(path: &Path) -> Result<PathBuf> {
+    let repo = Repository::discover(path)
         .context("not in a git repo. run this from inside a git repository.")?;
 
     let workdir = repo
@@ -18,6 +22,12 @@ pub fn get_repository_path() -> Result<PathBuf> {
     Ok(workdir.to_path_buf())
 }
 
+// the repo's git dir (.git, or the worktree's dir under it)
+pub fn git_dir(repo_path: &Path) -> Result<PathBuf> {
+    let repo = Repository::open(repo_path)?;
+    Ok(repo.path().to_path_buf())
+}
+
 // get the current branch name
 pub fn get_current_branch(repo_path: &Path) -> Result<String> {
     let repo = Repository::open(repo_path)?;
@@ -35,8 +45,62 @@ pub fn has_unstaged_changes(repo_path: &Path) -> Result<bool> {
     Ok(!statuses.is_empty())
 }
 
+// check if anything is staged for the next commit
+pub fn has_staged_changes(repo_path: &Path) -> Result<bool> {
+    let repo = Repository::open(repo_path)?;
+    let statuses = repo.statuses(None)?;
+    let staged = git2::Status::INDEX_NEW
+        | git2::Status::INDEX_MODIFIED
+        | git2::Status::INDEX_DELETED
+        | git2::Status::INDEX_RENAMED
+        | git2::Status::INDEX_TYPECHANGE;
+    Ok(statuses.iter().any(|entry| entry.status().intersects(staged)))
+}
+
+// where a branch pulls from and pushes to
+pub struct Upstream {
+    pub remote: String,
+    // the branch's name on the remote
+    pub branch: String,
+}
+
+impl Upstream {
+    // the remote-tracking branch, e.g. origin/main
+    pub fn tracking(&self) -> String {
+        format!("{}/{}", self.remote, self.branch)
+    }
+}
+
+// the branch's configured upstream, origin and the same name if it has none
+// (or only tracks a local branch)
+pub fn upstream(repo_path: &Path, branch: &str) -> Result<Upstream> {
+    let repo = Repository::open(repo_path)?;
+    let config = repo.config()?;
+    let remote = config
+        .get_string(&format!("branch.{}.remote", branch))
+        .ok()
+        .filter(|remote| remote != ".");
+    let merge = config
+        .get_string(&format!("branch.{}.merge", branch))
+        .ok()
+        .and_then(|merge| merge.strip_prefix("refs/heads/").map(str::to_string));
+
+    Ok(match (remote, merge) {
+        (Some(remote), Some(merge)) => Upstream { remote, branch: merge },
+        (Some(remote), None) => Upstream {
+            remote,
+            branch: branch.to_string(),
+        },
+        (None, _) => Upstream {
+            remote: "origin".to_string(),
+            branch: branch.to_string(),
+        },
+    })
+}
+
 // check if branch needs push (has unpushed commits)
 pub fn needs_push(repo_path: &Path, branch: &str) -> Result<bool> {
+    let upstream = upstream(repo_path, branch)?;
     let repo = Repository::open(repo_path)?;
     
     // get local branch
@@ -47,8 +111,7 @@ pub fn needs_push(repo_path: &Path, branch: &str) -> Result<bool> {
         .ok_or_else(|| anyhow::anyhow!("no local commit"))?;
     
     // try to get remote branch
-    let remote_name = format!("origin/{}", branch);
-    let remote_branch = repo.find_branch(&remote_name, git2::BranchType::Remote);
+    let remote_branch = repo.find_branch(&upstream.tracking(), git2::BranchType::Remote);
     
     match remote_branch {
         Ok(branch) => {
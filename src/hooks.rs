@@ -0,0 +1,267 @@
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use crate::git;
+use crate::models::{LogEntry, ScheduledOperation};
+use crate::process;
+use crate::storage;
+
+// most of a hook's output that ends up in the log, the tail is kept
+const MAX_OUTPUT: usize = 4000;
+
+// user scripts run around an operation
+// they live in the hooks dir next to the queue (every repo) and in $GIT_DIR/delayed/hooks (one repo)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hook {
+    PreOperation,
+    PostOperation,
+    OnFailure,
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Hook::PreOperation => write!(f, "pre-operation"),
+            Hook::PostOperation => write!(f, "post-operation"),
+            Hook::OnFailure => write!(f, "on-failure"),
+        }
+    }
+}
+
+pub struct HookRun {
+    pub hook: Hook,
+    pub path: PathBuf,
+    pub success: bool,
+    // "exited with 1", "timed out after 60s" and so on, None if it succeeded
+    pub failure: Option<String>,
+    pub output: String,
+}
+
+// executable hook scripts for `hook`, global ones first
+pub fn find_hooks(hook: Hook, repository_path: &Path) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(dir) = storage::get_hooks_dir() {
+        dirs.push(dir);
+    }
+    if let Ok(git_dir) = git::git_dir(repository_path) {
+        dirs.push(git_dir.join("delayed").join("hooks"));
+    }
+
+    dirs.into_iter()
+        .map(|dir| dir.join(hook.to_string()))
+        .filter(|path| is_executable(path))
+        .collect()
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+// run every script for `hook` in order, stopping at the first failing pre-operation hook
+// `outcome` is the log entry for post-operation and on-failure hooks
+pub fn run_hooks(
+    hook: Hook,
+    operation: &ScheduledOperation,
+    outcome: Option<&LogEntry>,
+    timeout: Duration,
+) -> Vec<HookRun> {
+    let mut runs = Vec::new();
+    for path in find_hooks(hook, &operation.repository_path) {
+        let run = run_hook(hook, &path, operation, outcome, timeout);
+        let stop = hook == Hook::PreOperation && !run.success;
+        runs.push(run);
+        if stop {
+            break;
+        }
+    }
+    runs
+}
+
+fn run_hook(
+    hook: Hook,
+    path: &Path,
+    operation: &ScheduledOperation,
+    outcome: Option<&LogEntry>,
+    timeout: Duration,
+) -> HookRun {
+    let input = serde_json::json!({
+        "hook": hook.to_string(),
+        "operation": operation,
+        "outcome": outcome,
+    });
+
+    let mut command = Command::new(path);
+    command.current_dir(&operation.repository_path);
+    for (key, value) in hook_env(hook, operation, outcome) {
+        command.env(key, value);
+    }
+
+    let result = process::output_with_input(&mut command, Some(input.to_string().as_bytes()), timeout);
+    let (failure, output) = match result {
+        Ok(output) => {
+            let text = format!(
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            let failure = match output.status.code() {
+                Some(0) => None,
+                Some(code) => Some(format!("exited with {}", code)),
+                None => Some("killed by a signal".to_string()),
+            };
+            (failure, text)
+        }
+        Err(e) => (Some(e.to_string()), String::new()),
+    };
+
+    HookRun {
+        hook,
+        path: path.to_path_buf(),
+        success: failure.is_none(),
+        failure,
+        output: tail(output.trim(), MAX_OUTPUT),
+    }
+}
+
+// GIT_DELAYED_* variables describing the operation, and its outcome if there is one
+pub fn hook_env(
+    hook: Hook,
+    operation: &ScheduledOperation,
+    outcome: Option<&LogEntry>,
+) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("GIT_DELAYED_HOOK", hook.to_string()),
+        ("GIT_DELAYED_ID", operation.id.clone()),
+        ("GIT_DELAYED_TYPE", operation.operation_type.to_string()),
+        ("GIT_DELAYED_REPO", operation.repository_path.display().to_string()),
+        ("GIT_DELAYED_BRANCH", operation.branch.clone().unwrap_or_default()),
+        ("GIT_DELAYED_MESSAGE", operation.commit_message.clone()),
+        ("GIT_DELAYED_SCHEDULED_TIME", operation.scheduled_time.to_rfc3339()),
+        ("GIT_DELAYED_RETRY_COUNT", operation.retry_count.to_string()),
+    ];
+
+    if let Some(outcome) = outcome {
+        env.push(("GIT_DELAYED_STATUS", outcome.status.to_string().to_lowercase()));
+        env.push(("GIT_DELAYED_ERROR", outcome.error_message.clone().unwrap_or_default()));
+        env.push((
+            "GIT_DELAYED_ERROR_CATEGORY",
+            outcome.error_category.map(|c| c.to_string()).unwrap_or_default(),
+        ));
+    }
+    env
+}
+
+// everything the hooks printed, for the log entry
+pub fn format_output(runs: &[HookRun]) -> Option<String> {
+    let sections: Vec<String> = runs
+        .iter()
+        .filter(|run| !run.output.is_empty() || run.failure.is_some())
+        .map(|run| {
+            let mut header = format!("[{} {}]", run.hook, run.path.display());
+            if let Some(failure) = &run.failure {
+                header.push_str(&format!(" {}", failure));
+            }
+            if run.output.is_empty() {
+                header
+            } else {
+                format!("{}\n{}", header, run.output)
+            }
+        })
+        .collect();
+
+    if sections.is_empty() {
+        None
+    } else {
+        Some(sections.join("\n"))
+    }
+}
+
+fn tail(text: &str, max: usize) -> String {
+    if text.len() <= max {
+        return text.to_string();
+    }
+    let mut start = text.len() - max;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("...{}", &text[start..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{MisfirePolicy, OperationState, OperationType};
+    use chrono::Local;
+    use std::fs;
+
+    fn operation(repo: &Path) -> ScheduledOperation {
+        ScheduledOperation {
+            id: "hook-test".to_string(),
+            repository_path: repo.to_path_buf(),
+            operation_type: OperationType::Push,
+            commit_message: "push".to_string(),
+            scheduled_time: Local::now(),
+            created_at: Local::now(),
+            retry_count: 0,
+            state: OperationState::Pending,
+            branch: Some("main".to_string()),
+            on_reject: None,
+            misfire: MisfirePolicy::RunImmediately,
+            paused_until: None,
+            state_before_pause: None,
+            timeout_secs: None,
+        }
+    }
+
+    fn write_hook(path: &Path, script: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, script).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_repo_hook_gets_env_and_stdin() {
+        let repo = std::env::temp_dir().join(format!("git-delayed-hooks-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&repo).unwrap();
+        assert!(Command::new("git").arg("init").current_dir(&repo).output().unwrap().status.success());
+
+        let hook = repo.join(".git/delayed/hooks/pre-operation");
+        write_hook(&hook, "#!/bin/sh\necho \"$GIT_DELAYED_ID $GIT_DELAYED_BRANCH\"\ngrep -q '\"hook\":\"pre-operation\"' && exit 3\n");
+
+        let runs = run_hooks(Hook::PreOperation, &operation(&repo), None, Duration::from_secs(10));
+        let run = runs.iter().find(|run| run.path == hook).unwrap();
+        assert!(!run.success);
+        assert_eq!(run.failure.as_deref(), Some("exited with 3"));
+        assert_eq!(run.output, "hook-test main");
+
+        let output = format_output(&runs).unwrap();
+        assert!(output.contains("pre-operation"));
+        assert!(output.contains("exited with 3"));
+
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_non_executable_hooks_are_ignored() {
+        let repo = std::env::temp_dir().join(format!("git-delayed-hooks-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&repo).unwrap();
+        assert!(Command::new("git").arg("init").current_dir(&repo).output().unwrap().status.success());
+
+        let hook = repo.join(".git/delayed/hooks/post-operation");
+        write_hook(&hook, "#!/bin/sh\nexit 0\n");
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o644)).unwrap();
+
+        assert!(!find_hooks(Hook::PostOperation, &repo).contains(&hook));
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_tail_keeps_the_end() {
+        assert_eq!(tail("abcdef", 3), "...def");
+        assert_eq!(tail("abc", 3), "abc");
+    }
+}
@@ -0,0 +1,505 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::migrations;
+use crate::models::{LogEntry, OperationLogs};
+use crate::storage;
+use crate::store::LogQuery;
+
+// the execution log of the json backend: one JSON entry per line, only ever appended to
+// the active file is rotated to logs-<time>.jsonl when it gets too big or too old
+// every file starts with a {"version": N} line, see migrations.rs
+const ACTIVE_FILE: &str = "logs.jsonl";
+const ROTATED_PREFIX: &str = "logs-";
+const ROTATED_SUFFIX: &str = ".jsonl";
+
+// the pretty-printed file older versions rewrote on every entry
+const LEGACY_FILE: &str = "logs.json";
+
+// when the active file is rotated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rotation {
+    pub max_bytes: u64,
+    pub max_age_days: u32,
+}
+
+pub struct LogFile {
+    dir: PathBuf,
+    rotation: Rotation,
+}
+
+impl LogFile {
+    pub fn new(dir: &Path, rotation: Rotation) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            rotation,
+        }
+    }
+
+    fn active(&self) -> PathBuf {
+        self.dir.join(ACTIVE_FILE)
+    }
+
+    // every log file, oldest first, the active one last
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        let mut rotated: Vec<PathBuf> = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| is_rotated(path))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        // the timestamp in the name sorts in time order
+        rotated.sort();
+        rotated.push(self.active());
+        Ok(rotated)
+    }
+
+    // held while appending, rotating, pruning or migrating, so none of them interleave
+    fn lock<R>(&self, operation: impl FnOnce() -> Result<R>) -> Result<R> {
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.dir.join(format!("{}.lock", ACTIVE_FILE)))?;
+        storage::with_file_lock(&lock, || {
+            self.migrate_legacy()?;
+            self.upgrade_files()?;
+            operation()
+        })
+    }
+
+    // whether some file is from an older version or logs.json is still around
+    fn needs_migration(&self) -> Result<bool> {
+        if self.dir.join(LEGACY_FILE).exists() {
+            return Ok(true);
+        }
+        for path in self.files()? {
+            if file_version(&path)?.is_some_and(|version| version < migrations::LOG.current()) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    pub fn append(&self, entry: &LogEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        self.lock(|| {
+            self.rotate_if_needed(entry.executed_at)?;
+            self.write_lines(&line)
+        })
+    }
+
+    // one write, so a crash leaves at most one partial line at the end, which reading skips
+    // a partial line left by an earlier crash is ended first so it can't swallow the new one
+    fn write_lines(&self, lines: &str) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(self.active())?;
+        let mut content = String::new();
+        if file.metadata()?.len() == 0 {
+            content.push_str(&header());
+        } else if !ends_with_newline(&mut file)? {
+            content.push('\n');
+        }
+        content.push_str(lines);
+        file.write_all(content.as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    fn rotate_if_needed(&self, now: DateTime<Local>) -> Result<()> {
+        let active = self.active();
+        let Ok(metadata) = fs::metadata(&active) else {
+            return Ok(());
+        };
+        if metadata.len() == 0 {
+            return Ok(());
+        }
+
+        let too_big = metadata.len() >= self.rotation.max_bytes;
+        let too_old = first_entry(&active)?
+            .is_some_and(|first| (now - first.executed_at).num_days() >= self.rotation.max_age_days as i64);
+        if !too_big && !too_old {
+            return Ok(());
+        }
+
+        // named by when it was rotated, so the names sort oldest first
+        let rotated = loop {
+            let path = self.dir.join(format!(
+                "{}{}{}",
+                ROTATED_PREFIX,
+                Local::now().format("%Y%m%d-%H%M%S%.3f"),
+                ROTATED_SUFFIX
+            ));
+            if !path.exists() {
+                break path;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        };
+        fs::rename(&active, &rotated)
+            .with_context(|| format!("couldn't rotate {}", active.display()))?;
+        Ok(())
+    }
+
+    // every entry, in the order it was logged
+    pub fn read_all(&self) -> Result<Vec<LogEntry>> {
+        if self.needs_migration()? {
+            // migrating needs the lock, plain reads don't
+            self.lock(|| Ok(()))?;
+        }
+        let mut entries = Vec::new();
+        for path in self.files()? {
+            entries.extend(read_file(&path)?);
+        }
+        Ok(entries)
+    }
+
+    pub fn query(&self, query: &LogQuery) -> Result<Vec<LogEntry>> {
+        Ok(query.apply(self.read_all()?))
+    }
+
+    // add entries in bulk, oldest first
+    pub fn extend(&self, entries: &[LogEntry]) -> Result<()> {
+        let mut lines = String::new();
+        for entry in entries {
+            lines.push_str(&serde_json::to_string(entry)?);
+            lines.push('\n');
+        }
+        self.lock(|| self.write_lines(&lines))
+    }
+
+    // drop entries logged before `before`, returns how many went
+    // rotated files that are entirely older are deleted, the rest rewritten
+    pub fn prune(&self, before: DateTime<Local>) -> Result<usize> {
+        self.lock(|| {
+            let mut removed = 0;
+            for path in self.files()? {
+                let entries = read_file(&path)?;
+                let kept: Vec<&LogEntry> = entries.iter().filter(|e| e.executed_at >= before).collect();
+                if kept.len() == entries.len() {
+                    continue;
+                }
+                removed += entries.len() - kept.len();
+
+                if kept.is_empty() && path != self.active() {
+                    fs::remove_file(&path)?;
+                    continue;
+                }
+                rewrite(&path, kept)?;
+            }
+            Ok(removed)
+        })
+    }
+
+    // log files with lines that don't parse, and how many
+    pub fn damaged(&self) -> Result<Vec<(PathBuf, usize)>> {
+        let mut damaged = Vec::new();
+        for path in self.files()? {
+            let (_, skipped) = scan_file(&path)?;
+            if skipped > 0 {
+                damaged.push((path, skipped));
+            }
+        }
+        Ok(damaged)
+    }
+
+    // rewrite a damaged file with only the entries that parse, the original is quarantined
+    pub fn repair(&self, path: &Path) -> Result<PathBuf> {
+        self.lock(|| {
+            let quarantined = storage::quarantine(path)?;
+            rewrite(path, &read_file(path)?)?;
+            Ok(quarantined)
+        })
+    }
+
+    // rewrite files from an older version in the current one, after backing them up
+    fn upgrade_files(&self) -> Result<()> {
+        for path in self.files()? {
+            let Some(version) = file_version(&path)? else {
+                continue;
+            };
+            migrations::check(&migrations::LOG, version, &path)?;
+            if version < migrations::LOG.current() {
+                migrations::backup(&path, version)?;
+                rewrite(&path, &read_file(&path)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    // move entries from an old logs.json to the end of the active file
+    // logs.json is kept as logs.json.bak
+    fn migrate_legacy(&self) -> Result<()> {
+        let legacy = self.dir.join(LEGACY_FILE);
+        if !legacy.exists() {
+            return Ok(());
+        }
+
+        let content = fs::read_to_string(&legacy)?;
+        let parse = || format!("couldn't parse {}", legacy.display());
+        let mut old: serde_json::Value = if content.trim().is_empty() {
+            serde_json::to_value(OperationLogs::default())?
+        } else {
+            serde_json::from_str(&content).with_context(parse)?
+        };
+        // logs.json never had a version
+        if let Some(entries) = old.get_mut("entries").and_then(serde_json::Value::as_array_mut) {
+            for entry in entries {
+                migrations::upgrade(&migrations::LOG, entry, 1).with_context(parse)?;
+            }
+        }
+        let old: OperationLogs = serde_json::from_value(old).with_context(parse)?;
+
+        // the old entries go before anything already in the active file
+        let active = self.active();
+        let mut entries = old.entries;
+        entries.extend(read_file(&active)?);
+        rewrite(&active, &entries)?;
+        fs::rename(&legacy, self.dir.join(format!("{}.bak", LEGACY_FILE)))?;
+        Ok(())
+    }
+}
+
+fn header() -> String {
+    format!("{}\n", serde_json::json!({ "version": migrations::LOG.current() }))
+}
+
+// replace a log file with `entries` in the current version
+fn rewrite<'a>(path: &Path, entries: impl IntoIterator<Item = &'a LogEntry>) -> Result<()> {
+    let mut content = header();
+    for entry in entries {
+        content.push_str(&serde_json::to_string(entry)?);
+        content.push('\n');
+    }
+    storage::write_atomic(path, content.as_bytes())
+}
+
+// the version in a file's header line, 1 if it has none, None if the file is missing or empty
+fn file_version(path: &Path) -> Result<Option<u32>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut line = String::new();
+    if BufReader::new(file).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(header_version(&line).unwrap_or(1)))
+}
+
+// a header is an object with a version and nothing else
+fn header_version(line: &str) -> Option<u32> {
+    let value: serde_json::Value = serde_json::from_str(line).ok()?;
+    if value.as_object()?.len() != 1 {
+        return None;
+    }
+    migrations::version_of(&value).ok()
+}
+
+fn is_rotated(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(ROTATED_PREFIX) && name.ends_with(ROTATED_SUFFIX))
+}
+
+// the entries in one file, upgraded to the current version, skipping lines that don't parse
+// the only expected one is a half-written last line after a crash
+fn read_file(path: &Path) -> Result<Vec<LogEntry>> {
+    Ok(scan_file(path)?.0)
+}
+
+// the entries in one file and how many lines were skipped
+fn scan_file(path: &Path) -> Result<(Vec<LogEntry>, usize)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e.into()),
+    };
+    let mut version = 1;
+    let mut entries = Vec::new();
+    let mut skipped = 0;
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if i == 0 {
+            if let Some(header) = header_version(&line) {
+                migrations::check(&migrations::LOG, header, path)?;
+                version = header;
+                continue;
+            }
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Ok(mut value) = serde_json::from_str(&line) else {
+            skipped += 1;
+            continue;
+        };
+        migrations::upgrade(&migrations::LOG, &mut value, version)?;
+        match serde_json::from_value(value) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => skipped += 1,
+        }
+    }
+    Ok((entries, skipped))
+}
+
+fn ends_with_newline(file: &mut File) -> Result<bool> {
+    let len = file.metadata()?.len();
+    if len == 0 {
+        return Ok(true);
+    }
+    let mut last = [0u8; 1];
+    file.seek(SeekFrom::Start(len - 1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] == b'\n')
+}
+
+// the first entry, after the header
+fn first_entry(path: &Path) -> Result<Option<LogEntry>> {
+    for line in BufReader::new(File::open(path)?).lines().take(2) {
+        if let Ok(entry) = serde_json::from_str(&line?) {
+            return Ok(Some(entry));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::conformance;
+    use chrono::Duration;
+
+    const ROTATION: Rotation = Rotation {
+        max_bytes: 10 * 1024 * 1024,
+        max_age_days: 30,
+    };
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("git-delayed-logfile-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_partial_last_line_is_skipped() {
+        let dir = temp_dir();
+        let log = LogFile::new(&dir, ROTATION);
+        log.append(&conformance::entry("a", "/one", Local::now())).unwrap();
+        let mut file = OpenOptions::new().append(true).open(log.active()).unwrap();
+        file.write_all(b"{\"id\":\"half").unwrap();
+        assert_eq!(log.read_all().unwrap().len(), 1);
+
+        log.append(&conformance::entry("b", "/one", Local::now())).unwrap();
+        assert_eq!(log.read_all().unwrap().len(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotates_by_size_and_age() {
+        let dir = temp_dir();
+        let log = LogFile::new(&dir, Rotation { max_bytes: 1, max_age_days: 30 });
+        let now = Local::now();
+        log.append(&conformance::entry("a", "/one", now)).unwrap();
+        log.append(&conformance::entry("b", "/one", now)).unwrap();
+        assert_eq!(log.files().unwrap().len(), 2);
+
+        let log = LogFile::new(&dir, ROTATION);
+        log.append(&conformance::entry("c", "/one", now + Duration::days(31))).unwrap();
+        assert_eq!(log.files().unwrap().len(), 3);
+
+        let ids: Vec<String> = log.read_all().unwrap().into_iter().map(|e| e.id).collect();
+        assert_eq!(ids, ["a", "b", "c"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune() {
+        let dir = temp_dir();
+        let log = LogFile::new(&dir, Rotation { max_bytes: 1, max_age_days: 30 });
+        let now = Local::now();
+        log.append(&conformance::entry("old", "/one", now - Duration::days(100))).unwrap();
+        log.append(&conformance::entry("new", "/one", now)).unwrap();
+
+        assert_eq!(log.prune(now - Duration::days(90)).unwrap(), 1);
+        let ids: Vec<String> = log.read_all().unwrap().into_iter().map(|e| e.id).collect();
+        assert_eq!(ids, ["new"]);
+        // the rotated file that only held the old entry is gone
+        assert_eq!(log.files().unwrap().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_legacy_logs_json_is_migrated() {
+        let dir = temp_dir();
+        let legacy = OperationLogs {
+            entries: vec![conformance::entry("legacy", "/one", Local::now() - Duration::hours(1))],
+        };
+        fs::write(dir.join(LEGACY_FILE), serde_json::to_string_pretty(&legacy).unwrap()).unwrap();
+
+        let log = LogFile::new(&dir, ROTATION);
+        log.append(&conformance::entry("new", "/one", Local::now())).unwrap();
+
+        let ids: Vec<String> = log.read_all().unwrap().into_iter().map(|e| e.id).collect();
+        assert_eq!(ids, ["legacy", "new"]);
+        assert!(!dir.join(LEGACY_FILE).exists());
+        assert!(dir.join("logs.json.bak").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_unversioned_file_is_upgraded_with_backup() {
+        let dir = temp_dir();
+        // an entry from before error_category existed, in a file without a header
+        let mut old = serde_json::to_value(conformance::entry("old", "/one", Local::now())).unwrap();
+        old.as_object_mut().unwrap().remove("error_category");
+        fs::write(dir.join(ACTIVE_FILE), format!("{}\n", old)).unwrap();
+
+        let log = LogFile::new(&dir, ROTATION);
+        log.append(&conformance::entry("new", "/one", Local::now())).unwrap();
+
+        assert_eq!(log.read_all().unwrap().len(), 2);
+        assert_eq!(file_version(&log.active()).unwrap(), Some(migrations::LOG.current()));
+        assert!(dir.join("logs.jsonl.v1.bak").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_repair_drops_damaged_lines() {
+        let dir = temp_dir();
+        let log = LogFile::new(&dir, ROTATION);
+        log.append(&conformance::entry("a", "/one", Local::now())).unwrap();
+        let mut file = OpenOptions::new().append(true).open(log.active()).unwrap();
+        file.write_all(b"garbage\n").unwrap();
+        log.append(&conformance::entry("b", "/one", Local::now())).unwrap();
+
+        assert_eq!(log.damaged().unwrap(), vec![(log.active(), 1)]);
+        let quarantined = log.repair(&log.active()).unwrap();
+        assert!(fs::read_to_string(quarantined).unwrap().contains("garbage"));
+        assert!(log.damaged().unwrap().is_empty());
+        assert_eq!(log.read_all().unwrap().len(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_newer_file_is_refused() {
+        let dir = temp_dir();
+        let newer = format!("{{\"version\":{}}}\n", migrations::LOG.current() + 1);
+        fs::write(dir.join(ACTIVE_FILE), &newer).unwrap();
+
+        let log = LogFile::new(&dir, ROTATION);
+        assert!(log.read_all().is_err());
+        assert!(log.append(&conformance::entry("a", "/one", Local::now())).is_err());
+        assert_eq!(fs::read_to_string(log.active()).unwrap(), newer);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
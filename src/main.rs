@@ -1,14 +1,36 @@
 mod cli;
+mod config;
+mod control;
 mod models;
 mod storage;
+mod store;
 mod schedule;
+mod service;
 mod daemon;
+mod doctor;
 mod executor;
 mod git;
+mod hooks;
+mod logfile;
+mod metrics;
+mod migrations;
+mod notify;
+mod pause;
+mod process;
+mod repos;
+mod sdnotify;
+mod signals;
+mod sqlite;
+mod transfer;
+mod wakeup;
 
 fn main() {
     if let Err(e) = cli::run() {
         eprintln!("Error: {}", e);
-        std::process::exit(1);
+        let code = e
+            .downcast_ref::<daemon::DaemonExit>()
+            .map(|exit| exit.code)
+            .unwrap_or(daemon::EXIT_FAILURE);
+        std::process::exit(code);
     }
 }
@@ -0,0 +1,290 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::daemon;
+use crate::models::{ExecutionStatus, OperationState, OperationType, ScheduledOperation};
+use crate::pause;
+use crate::storage;
+
+// Prometheus text format on 127.0.0.1:<metrics_port>, GET /metrics
+
+const RETRY_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0];
+const DURATION_BUCKETS: &[f64] = &[0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+const STATUSES: [ExecutionStatus; 4] = [
+    ExecutionStatus::Success,
+    ExecutionStatus::Failure,
+    ExecutionStatus::Skipped,
+    ExecutionStatus::Cancelled,
+];
+const STATES: [OperationState; 4] = [
+    OperationState::Pending,
+    OperationState::Failing,
+    OperationState::NeedsAttention,
+    OperationState::Paused,
+];
+const TYPES: [OperationType; 2] = [OperationType::Commit, OperationType::Push];
+
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    const fn new(buckets: &'static [f64]) -> Self {
+        Self { buckets, counts: Vec::new(), sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        self.counts.resize(self.buckets.len(), 0);
+        for (count, bound) in self.counts.iter_mut().zip(self.buckets) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (i, bound) in self.buckets.iter().enumerate() {
+            let count = self.counts.get(i).copied().unwrap_or(0);
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, self.count);
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+// what happened since the daemon started, counters only ever go up
+struct Counters {
+    executions: [u64; STATUSES.len()],
+    retries: Histogram,
+    durations: [Histogram; TYPES.len()],
+}
+
+static COUNTERS: Mutex<Counters> = Mutex::new(Counters {
+    executions: [0; STATUSES.len()],
+    retries: Histogram::new(RETRY_BUCKETS),
+    durations: [Histogram::new(DURATION_BUCKETS), Histogram::new(DURATION_BUCKETS)],
+});
+
+// count a log entry by its status
+pub fn record_outcome(status: &ExecutionStatus) {
+    let mut counters = COUNTERS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(i) = STATUSES.iter().position(|s| s == status) {
+        counters.executions[i] += 1;
+    }
+}
+
+// an operation was actually run: how often it had been retried and how long it took
+pub fn record_execution(operation_type: &OperationType, retry_count: u32, duration: Duration) {
+    let mut counters = COUNTERS.lock().unwrap_or_else(|e| e.into_inner());
+    counters.retries.observe(retry_count as f64);
+    if let Some(i) = TYPES.iter().position(|t| t == operation_type) {
+        counters.durations[i].observe(duration.as_secs_f64());
+    }
+}
+
+// serve /metrics from a background thread, only on loopback
+pub fn start_server(port: u16) -> Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .with_context(|| format!("couldn't bind 127.0.0.1:{}", port))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let _ = serve_connection(stream);
+            });
+        }
+    });
+    Ok(())
+}
+
+fn serve_connection(mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // the headers don't matter, but they have to be read before answering
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let (status, content_type, body) = match (method, path.split('?').next().unwrap_or("")) {
+        ("GET", "/metrics") => match gather() {
+            Ok(body) => ("200 OK", "text/plain; version=0.0.4", body),
+            Err(e) => ("500 Internal Server Error", "text/plain", format!("{:#}\n", e)),
+        },
+        ("GET", _) => ("404 Not Found", "text/plain", "try /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", String::new()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+fn gather() -> Result<String> {
+    let operations = storage::load_scheduled_operations()?.operations;
+    let pauses = storage::load_pauses()?;
+    let now = Local::now();
+    // held operations aren't stuck, they're waiting on purpose
+    let overdue = operations
+        .iter()
+        .filter(|op| op.scheduled_time <= now && !pause::is_blocked(op, &pauses, now))
+        .map(|op| op.scheduled_time)
+        .min();
+    Ok(render(&operations, overdue, daemon::last_tick(), now))
+}
+
+fn render(
+    operations: &[ScheduledOperation],
+    oldest_overdue: Option<DateTime<Local>>,
+    last_tick: Option<DateTime<Local>>,
+    now: DateTime<Local>,
+) -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP git_delayed_queue_operations Queued operations by state and type.\n");
+    out.push_str("# TYPE git_delayed_queue_operations gauge\n");
+    for state in &STATES {
+        for operation_type in &TYPES {
+            let count = operations
+                .iter()
+                .filter(|op| op.state == *state && op.operation_type == *operation_type)
+                .count();
+            let _ = writeln!(
+                out,
+                "git_delayed_queue_operations{{state=\"{}\",type=\"{}\"}} {}",
+                state, operation_type, count
+            );
+        }
+    }
+
+    out.push_str("# HELP git_delayed_oldest_overdue_seconds How long the most overdue operation has been due, 0 if none.\n");
+    out.push_str("# TYPE git_delayed_oldest_overdue_seconds gauge\n");
+    let overdue = oldest_overdue.map(|t| (now - t).num_milliseconds().max(0) as f64 / 1000.0);
+    let _ = writeln!(out, "git_delayed_oldest_overdue_seconds {}", overdue.unwrap_or(0.0));
+
+    let counters = COUNTERS.lock().unwrap_or_else(|e| e.into_inner());
+
+    out.push_str("# HELP git_delayed_executions_total Logged outcomes by status since the daemon started.\n");
+    out.push_str("# TYPE git_delayed_executions_total counter\n");
+    for (status, count) in STATUSES.iter().zip(counters.executions) {
+        let _ = writeln!(
+            out,
+            "git_delayed_executions_total{{status=\"{}\"}} {}",
+            status.to_string().to_lowercase(),
+            count
+        );
+    }
+
+    out.push_str("# HELP git_delayed_retry_count Retries an operation had behind it when it ran.\n");
+    out.push_str("# TYPE git_delayed_retry_count histogram\n");
+    counters.retries.render(&mut out, "git_delayed_retry_count", "");
+
+    out.push_str("# HELP git_delayed_execution_duration_seconds Time spent running an operation's git commands.\n");
+    out.push_str("# TYPE git_delayed_execution_duration_seconds histogram\n");
+    for (operation_type, histogram) in TYPES.iter().zip(&counters.durations) {
+        let labels = format!("type=\"{}\"", operation_type);
+        histogram.render(&mut out, "git_delayed_execution_duration_seconds", &labels);
+    }
+
+    out.push_str("# HELP git_delayed_last_tick_timestamp_seconds When the daemon loop last ticked.\n");
+    out.push_str("# TYPE git_delayed_last_tick_timestamp_seconds gauge\n");
+    let tick = last_tick.map(|t| t.timestamp_millis() as f64 / 1000.0);
+    let _ = writeln!(out, "git_delayed_last_tick_timestamp_seconds {}", tick.unwrap_or(0.0));
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::MisfirePolicy;
+    use std::path::PathBuf;
+
+    fn operation(state: OperationState) -> ScheduledOperation {
+        ScheduledOperation {
+            id: "metrics-test".to_string(),
+            repository_path: PathBuf::from("/src/app"),
+            operation_type: OperationType::Push,
+            commit_message: "push".to_string(),
+            scheduled_time: Local::now(),
+            created_at: Local::now(),
+            retry_count: 0,
+            state,
+            branch: None,
+            on_reject: None,
+            misfire: MisfirePolicy::RunImmediately,
+            paused_until: None,
+            state_before_pause: None,
+            timeout_secs: None,
+        }
+    }
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::new(&[1.0, 5.0]);
+        histogram.observe(0.5);
+        histogram.observe(3.0);
+        histogram.observe(9.0);
+
+        let mut out = String::new();
+        histogram.render(&mut out, "h", "type=\"push\"");
+        assert!(out.contains("h_bucket{type=\"push\",le=\"1\"} 1\n"));
+        assert!(out.contains("h_bucket{type=\"push\",le=\"5\"} 2\n"));
+        assert!(out.contains("h_bucket{type=\"push\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("h_sum{type=\"push\"} 12.5\n"));
+        assert!(out.contains("h_count{type=\"push\"} 3\n"));
+    }
+
+    #[test]
+    fn test_render_queue_and_overdue() {
+        let now = Local::now();
+        let operations = vec![
+            operation(OperationState::Pending),
+            operation(OperationState::Pending),
+            operation(OperationState::NeedsAttention),
+        ];
+        let out = render(
+            &operations,
+            Some(now - chrono::Duration::seconds(90)),
+            Some(now),
+            now,
+        );
+        assert!(out.contains("git_delayed_queue_operations{state=\"pending\",type=\"push\"} 2\n"));
+        assert!(out.contains("git_delayed_queue_operations{state=\"attention\",type=\"push\"} 1\n"));
+        assert!(out.contains("git_delayed_queue_operations{state=\"failing\",type=\"commit\"} 0\n"));
+        assert!(out.contains("git_delayed_oldest_overdue_seconds 90\n"));
+        assert!(out.contains("git_delayed_executions_total{status=\"success\"}"));
+        assert!(out.contains("git_delayed_retry_count_bucket{le=\"0\"}"));
+    }
+}
@@ -0,0 +1,164 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// the data files record the version of their format, files from before that was
+// recorded are version 1
+// an old file is upgraded step by step when it's read, and rewritten (after a backup)
+// the next time it's written; a file from a newer version is refused
+
+type Step = fn(&mut Value) -> Result<()>;
+
+pub struct Schema {
+    name: &'static str,
+    // steps[n] turns version n + 1 into n + 2
+    steps: &'static [Step],
+}
+
+impl Schema {
+    // the version this binary reads and writes
+    pub const fn current(&self) -> u32 {
+        self.steps.len() as u32 + 1
+    }
+}
+
+// scheduled.json, the steps work on the whole file
+pub const QUEUE: Schema = Schema {
+    name: "queue",
+    steps: &[queue_v2, queue_v3],
+};
+
+// the execution log, the version is in the header line of each file
+// and the steps work on a single entry
+pub const LOG: Schema = Schema {
+    name: "log",
+    steps: &[log_v2],
+};
+
+// the "version" of a file, 1 if it has none
+pub fn version_of(value: &Value) -> Result<u32> {
+    match value.get("version") {
+        None => Ok(1),
+        Some(version) => version
+            .as_u64()
+            .map(|version| version as u32)
+            .context("\"version\" isn't a number"),
+    }
+}
+
+// saving a file from a newer version would silently drop whatever this one doesn't know about
+pub fn check(schema: &Schema, version: u32, path: &Path) -> Result<()> {
+    if version > schema.current() {
+        return Err(anyhow::anyhow!(
+            "{} was written by a newer git-delayed ({} format version {}, this one knows up to {}), upgrade git-delayed to use it",
+            path.display(),
+            schema.name,
+            version,
+            schema.current()
+        ));
+    }
+    Ok(())
+}
+
+// run the steps from `version` up to the current one
+pub fn upgrade(schema: &Schema, value: &mut Value, version: u32) -> Result<()> {
+    let from = (version.max(1) - 1) as usize;
+    for step in schema.steps.iter().skip(from) {
+        step(value)?;
+    }
+    Ok(())
+}
+
+// copy `path` to `<file>.v<version>.bak` before it's upgraded in place
+// an existing backup of the same version is the original one and kept
+pub fn backup(path: &Path, version: u32) -> Result<PathBuf> {
+    let name = path.file_name().context("no file name")?.to_string_lossy();
+    let backup = path.with_file_name(format!("{}.v{}.bak", name, version));
+    if !backup.exists() {
+        fs::copy(path, &backup).with_context(|| format!("couldn't back up {}", path.display()))?;
+    }
+    Ok(backup)
+}
+
+// set `key` if it's missing
+fn fill(object: &mut Value, key: &str, value: Value) {
+    if let Some(object) = object.as_object_mut() {
+        object.entry(key).or_insert(value);
+    }
+}
+
+// version 2 spells out the fields that used to be filled in when missing
+fn queue_v2(queue: &mut Value) -> Result<()> {
+    if let Some(operations) = queue.get_mut("operations").and_then(Value::as_array_mut) {
+        for operation in operations {
+            fill(operation, "retry_count", json!(0));
+            fill(operation, "state", json!("Pending"));
+            fill(operation, "branch", Value::Null);
+            fill(operation, "on_reject", Value::Null);
+            fill(operation, "misfire", json!("RunImmediately"));
+            fill(operation, "paused_until", Value::Null);
+            fill(operation, "timeout_secs", Value::Null);
+        }
+    }
+    Ok(())
+}
+
+// version 3 remembers the state a paused operation was in
+fn queue_v3(queue: &mut Value) -> Result<()> {
+    if let Some(operations) = queue.get_mut("operations").and_then(Value::as_array_mut) {
+        for operation in operations {
+            fill(operation, "state_before_pause", Value::Null);
+        }
+    }
+    Ok(())
+}
+
+fn log_v2(entry: &mut Value) -> Result<()> {
+    fill(entry, "error_category", Value::Null);
+    fill(entry, "hook_output", Value::Null);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{MisfirePolicy, OperationState, ScheduledOperations};
+
+    #[test]
+    fn test_version_1_queue_is_upgraded() {
+        let mut queue = json!({"operations": [{
+            "id": "a",
+            "repository_path": "/src/app",
+            "operation_type": "Push",
+            "commit_message": "push",
+            "scheduled_time": "2025-01-01T09:00:00+01:00",
+            "created_at": "2025-01-01T08:00:00+01:00"
+        }]});
+        let version = version_of(&queue).unwrap();
+        assert_eq!(version, 1);
+        upgrade(&QUEUE, &mut queue, version).unwrap();
+        queue["version"] = json!(QUEUE.current());
+
+        let queue: ScheduledOperations = serde_json::from_value(queue).unwrap();
+        assert_eq!(queue.operations[0].retry_count, 0);
+        assert_eq!(queue.operations[0].state, OperationState::Pending);
+        assert_eq!(queue.operations[0].misfire, MisfirePolicy::RunImmediately);
+    }
+
+    #[test]
+    fn test_upgrade_keeps_existing_values() {
+        let mut queue = json!({"operations": [{"retry_count": 3, "state": "Paused"}]});
+        upgrade(&QUEUE, &mut queue, 1).unwrap();
+        assert_eq!(queue["operations"][0]["retry_count"], 3);
+        assert_eq!(queue["operations"][0]["state"], "Paused");
+    }
+
+    #[test]
+    fn test_newer_version_is_refused() {
+        let path = Path::new("scheduled.json");
+        assert!(check(&QUEUE, QUEUE.current(), path).is_ok());
+        let error = check(&QUEUE, QUEUE.current() + 1, path).unwrap_err();
+        assert!(error.to_string().contains("newer git-delayed"));
+    }
+}
@@ -2,6 +2,9 @@ use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::migrations;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum OperationType {
@@ -18,10 +21,13 @@ impl fmt::Display for OperationType {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
 pub enum OperationState {
+    #[default]
     Pending,
     Failing,
+    NeedsAttention,
+    Paused,
 }
 
 impl fmt::Display for OperationState {
@@ -29,10 +35,78 @@ impl fmt::Display for OperationState {
         match self {
             OperationState::Pending => write!(f, "pending"),
             OperationState::Failing => write!(f, "failing"),
+            OperationState::NeedsAttention => write!(f, "attention"),
+            OperationState::Paused => write!(f, "paused"),
+        }
+    }
+}
+
+// how to bring a rejected push up to date with the remote
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum IntegrationStrategy {
+    Rebase,
+    Merge,
+}
+
+impl fmt::Display for IntegrationStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrationStrategy::Rebase => write!(f, "rebase"),
+            IntegrationStrategy::Merge => write!(f, "merge"),
+        }
+    }
+}
+
+impl FromStr for IntegrationStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rebase" => Ok(IntegrationStrategy::Rebase),
+            "merge" => Ok(IntegrationStrategy::Merge),
+            _ => Err(anyhow::anyhow!("unknown strategy: {} (use rebase or merge)", s)),
+        }
+    }
+}
+
+// opt-in policy for pushes rejected as non-fast-forward
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RejectPolicy {
+    pub strategy: IntegrationStrategy,
+    #[serde(default)]
+    pub test_command: Option<String>,
+}
+
+// what to do with an operation whose time passed while the daemon couldn't run it
+// (machine off or asleep, daemon stopped)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub enum MisfirePolicy {
+    #[default]
+    RunImmediately,
+    RunIfWithin { max_late_minutes: i64 },
+    Skip,
+    Reschedule,
+}
+
+impl fmt::Display for MisfirePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MisfirePolicy::RunImmediately => write!(f, "run"),
+            MisfirePolicy::RunIfWithin { max_late_minutes } => {
+                write!(f, "run if less than {}m late", max_late_minutes)
+            }
+            MisfirePolicy::Skip => write!(f, "skip"),
+            MisfirePolicy::Reschedule => write!(f, "reschedule"),
         }
     }
 }
 
+// ids are made by the CLI as UUIDs, and end up in file names (claim locks, worktrees)
+pub fn is_valid_id(id: &str) -> bool {
+    uuid::Uuid::parse_str(id).is_ok_and(|uuid| uuid.hyphenated().to_string() == id.to_lowercase())
+}
+
+// stored in scheduled.json, a new field needs a migration step (see migrations.rs)
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ScheduledOperation {
     pub id: String,
@@ -41,18 +115,16 @@ pub struct ScheduledOperation {
     pub commit_message: String,
     pub scheduled_time: DateTime<Local>,
     pub created_at: DateTime<Local>,
-    #[serde(default)]
     pub retry_count: u32,
-    #[serde(default)]
     pub state: OperationState,
-    #[serde(default)]
     pub branch: Option<String>,
-}
-
-impl Default for OperationState {
-    fn default() -> Self {
-        OperationState::Pending
-    }
+    pub on_reject: Option<RejectPolicy>,
+    pub misfire: MisfirePolicy,
+    pub paused_until: Option<DateTime<Local>>,
+    // what a paused operation goes back to when it's resumed
+    pub state_before_pause: Option<OperationState>,
+    // overrides operation_timeout_secs from the config
+    pub timeout_secs: Option<u64>,
 }
 
 impl fmt::Display for ScheduledOperation {
@@ -69,7 +141,7 @@ impl fmt::Display for ScheduledOperation {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum ExecutionStatus {
     Success,
     Failure,
@@ -88,6 +160,49 @@ impl fmt::Display for ExecutionStatus {
     }
 }
 
+// what kind of failure a git operation ran into
+// transient ones get retried, permanent ones fail straight away
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCategory {
+    Auth,
+    Network,
+    NonFastForward,
+    HookRejected,
+    ProtectedBranch,
+    Conflict,
+    TestFailed,
+    NothingToCommit,
+    Repository,
+    // a git or test command ran past the operation's timeout and was killed
+    Timeout,
+    Other,
+}
+
+impl ErrorCategory {
+    pub fn is_transient(&self) -> bool {
+        // unknown errors keep the old retry behaviour
+        matches!(self, ErrorCategory::Network | ErrorCategory::Timeout | ErrorCategory::Other)
+    }
+}
+
+impl fmt::Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCategory::Auth => write!(f, "auth"),
+            ErrorCategory::Network => write!(f, "network"),
+            ErrorCategory::NonFastForward => write!(f, "non-fast-forward"),
+            ErrorCategory::HookRejected => write!(f, "hook-rejected"),
+            ErrorCategory::ProtectedBranch => write!(f, "protected-branch"),
+            ErrorCategory::Conflict => write!(f, "conflict"),
+            ErrorCategory::TestFailed => write!(f, "test-failed"),
+            ErrorCategory::NothingToCommit => write!(f, "nothing-to-commit"),
+            ErrorCategory::Repository => write!(f, "repository"),
+            ErrorCategory::Timeout => write!(f, "timeout"),
+            ErrorCategory::Other => write!(f, "other"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct LogEntry {
     pub id: String,
@@ -98,6 +213,9 @@ pub struct LogEntry {
     pub executed_at: DateTime<Local>,
     pub status: ExecutionStatus,
     pub error_message: Option<String>,
+    pub error_category: Option<ErrorCategory>,
+    // what the user's hook scripts printed around this execution
+    pub hook_output: Option<String>,
 }
 
 impl fmt::Display for LogEntry {
@@ -120,28 +238,90 @@ impl fmt::Display for LogEntry {
     }
 }
 
+// scheduled.json, see migrations.rs for the version
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ScheduledOperations {
+    pub version: u32,
     pub operations: Vec<ScheduledOperation>,
 }
 
 impl Default for ScheduledOperations {
     fn default() -> Self {
         Self {
+            version: migrations::QUEUE.current(),
             operations: Vec::new(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+// logs.json from before the log was JSONL, always version 1
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct OperationLogs {
     pub entries: Vec<LogEntry>,
 }
 
-impl Default for OperationLogs {
-    fn default() -> Self {
-        Self {
-            entries: Vec::new(),
-        }
+// a pause, either open-ended or until a given time
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Pause {
+    pub since: DateTime<Local>,
+    #[serde(default)]
+    pub until: Option<DateTime<Local>>,
+}
+
+impl Pause {
+    pub fn is_active(&self, now: DateTime<Local>) -> bool {
+        self.until.is_none_or(|until| until > now)
     }
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RepositoryPause {
+    pub repository_path: PathBuf,
+    #[serde(flatten)]
+    pub pause: Pause,
+}
+
+// global and per-repository pauses, per-operation ones live on the operation itself
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Pauses {
+    #[serde(default)]
+    pub global: Option<Pause>,
+    #[serde(default)]
+    pub repositories: Vec<RepositoryPause>,
+}
+
+// repositories that keep their queue in their git dir, see repos.rs
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RepositoryIndex {
+    #[serde(default)]
+    pub repositories: Vec<RegisteredRepository>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RegisteredRepository {
+    // from $GIT_DIR/delayed/id, so a repository that moved replaces its old entry
+    pub id: String,
+    pub git_dir: PathBuf,
+}
+
+// the next operation the daemon is waiting for
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NextDue {
+    pub id: String,
+    pub scheduled_time: DateTime<Local>,
+}
+
+// written by the daemon on every tick, so a hung daemon can be told from a busy one
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Heartbeat {
+    pub pid: u32,
+    pub version: String,
+    pub started_at: DateTime<Local>,
+    pub last_tick: DateTime<Local>,
+    #[serde(default)]
+    pub running: Vec<String>,
+    #[serde(default)]
+    pub next_due: Option<NextDue>,
+    #[serde(default)]
+    pub failures: u32,
+}
@@ -0,0 +1,348 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::models::LogEntry;
+use crate::process;
+
+// a sink that hasn't delivered in this long is given up on
+const SEND_TIMEOUT: Duration = Duration::from_secs(15);
+
+// what happened to an operation, sinks pick which ones they care about
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Success,
+    // failed, and will be retried or needs attention
+    Failure,
+    // failed for the last time, max_retries ran out
+    Exhausted,
+    Skipped,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Success => write!(f, "success"),
+            Event::Failure => write!(f, "failure"),
+            Event::Exhausted => write!(f, "exhausted"),
+            Event::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+// one entry of "notifications" in config.json
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Sink {
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    // which events go to this sink, failures and exhausted retries if not given
+    #[serde(default = "default_events")]
+    pub on: Vec<Event>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkKind {
+    // notify-send on linux, osascript on macOS
+    Desktop,
+    // POST the JSON payload to a url with curl
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: Vec<String>,
+    },
+    // pipe a mail to sendmail -t
+    Sendmail {
+        to: String,
+        #[serde(default = "default_sendmail")]
+        command: String,
+    },
+    // append the JSON payload as a line
+    File { path: PathBuf },
+}
+
+// at most `max` notifications per `per_minutes`, the rest are dropped
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub max: usize,
+    pub per_minutes: u64,
+}
+
+fn default_events() -> Vec<Event> {
+    vec![Event::Failure, Event::Exhausted]
+}
+
+fn default_sendmail() -> String {
+    "sendmail".to_string()
+}
+
+// the sinks from the current config, set by whoever runs operations
+static SINKS: Mutex<Vec<Sink>> = Mutex::new(Vec::new());
+
+// when each sink last sent, keyed by the sink itself so a config reload keeps the history
+static SENT: Mutex<Vec<(Sink, VecDeque<Instant>)>> = Mutex::new(Vec::new());
+
+// deliveries still in flight, so a short-lived process can wait for them before exiting
+static PENDING: Mutex<Vec<thread::JoinHandle<()>>> = Mutex::new(Vec::new());
+
+pub fn configure(sinks: &[Sink]) {
+    *SINKS.lock().unwrap_or_else(|e| e.into_inner()) = sinks.to_vec();
+}
+
+// tell every interested sink about an outcome
+// delivery happens on a background thread, a slow webhook mustn't hold up the queue
+pub fn notify(event: Event, entry: &LogEntry) {
+    let sinks: Vec<Sink> = SINKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter(|sink| sink.on.contains(&event))
+        .filter(|sink| allow(sink, Instant::now()))
+        .cloned()
+        .collect();
+    if sinks.is_empty() {
+        return;
+    }
+
+    let entry = entry.clone();
+    let handle = thread::spawn(move || {
+        for sink in sinks {
+            if let Err(e) = send(&sink, event, &entry) {
+                eprintln!("notification via {} failed: {:#}", sink.kind.name(), e);
+            }
+        }
+    });
+
+    let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+    pending.retain(|handle| !handle.is_finished());
+    pending.push(handle);
+}
+
+// wait for notifications that are still being sent
+pub fn flush() {
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap_or_else(|e| e.into_inner()));
+    for handle in pending {
+        let _ = handle.join();
+    }
+}
+
+// sliding window rate limit, records the send if it's allowed
+fn allow(sink: &Sink, now: Instant) -> bool {
+    let Some(limit) = sink.rate_limit else {
+        return true;
+    };
+    let window = Duration::from_secs(limit.per_minutes * 60);
+
+    let mut sent = SENT.lock().unwrap_or_else(|e| e.into_inner());
+    let index = match sent.iter().position(|(s, _)| s == sink) {
+        Some(index) => index,
+        None => {
+            sent.push((sink.clone(), VecDeque::new()));
+            sent.len() - 1
+        }
+    };
+    let times = &mut sent[index].1;
+    while times.front().is_some_and(|t| now.duration_since(*t) >= window) {
+        times.pop_front();
+    }
+    if times.len() >= limit.max {
+        return false;
+    }
+    times.push_back(now);
+    true
+}
+
+impl SinkKind {
+    fn name(&self) -> &'static str {
+        match self {
+            SinkKind::Desktop => "desktop",
+            SinkKind::Webhook { .. } => "webhook",
+            SinkKind::Sendmail { .. } => "sendmail",
+            SinkKind::File { .. } => "file",
+        }
+    }
+}
+
+pub fn payload(event: Event, entry: &LogEntry) -> serde_json::Value {
+    serde_json::json!({
+        "event": event,
+        "entry": entry,
+    })
+}
+
+fn title(event: Event, entry: &LogEntry) -> String {
+    let repo = entry
+        .repository_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| entry.repository_path.display().to_string());
+    let what = match event {
+        Event::Success => "done",
+        Event::Failure => "failed",
+        Event::Exhausted => "gave up",
+        Event::Skipped => "skipped",
+    };
+    format!("git-delayed: {} {} in {}", entry.operation_type, what, repo)
+}
+
+fn body(entry: &LogEntry) -> String {
+    let mut body = format!("{} ({})", entry.commit_message, entry.id);
+    if let Some(error) = &entry.error_message {
+        body.push_str(&format!("\n{}", error));
+    }
+    body
+}
+
+fn send(sink: &Sink, event: Event, entry: &LogEntry) -> Result<()> {
+    match &sink.kind {
+        SinkKind::Desktop => {
+            let mut command = if cfg!(target_os = "macos") {
+                let script = format!(
+                    "display notification {:?} with title {:?}",
+                    body(entry),
+                    title(event, entry)
+                );
+                let mut command = Command::new("osascript");
+                command.args(["-e", &script]);
+                command
+            } else {
+                let mut command = Command::new("notify-send");
+                command.args(["--app-name=git-delayed", &title(event, entry), &body(entry)]);
+                command
+            };
+            run(&mut command, None)
+        }
+        SinkKind::Webhook { url, headers } => {
+            let mut command = Command::new("curl");
+            command.args(["-sS", "--fail", "-X", "POST", "-H", "Content-Type: application/json"]);
+            for header in headers {
+                command.args(["-H", header]);
+            }
+            command.args(["--data-binary", "@-", url]);
+            run(&mut command, Some(payload(event, entry).to_string()))
+        }
+        SinkKind::Sendmail { to, command } => {
+            let mail = format!(
+                "To: {}\nSubject: {}\nContent-Type: text/plain; charset=utf-8\n\n{}\n",
+                to,
+                title(event, entry),
+                body(entry)
+            );
+            run(Command::new(command).arg("-t"), Some(mail))
+        }
+        SinkKind::File { path } => {
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("couldn't open {}", path.display()))?;
+            writeln!(file, "{}", payload(event, entry))?;
+            Ok(())
+        }
+    }
+}
+
+fn run(command: &mut Command, input: Option<String>) -> Result<()> {
+    let output = process::output_with_input(command, input.as_deref().map(str::as_bytes), SEND_TIMEOUT)?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "{:?} failed: {}",
+            command.get_program(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ExecutionStatus, OperationType};
+    use chrono::Local;
+
+    fn entry() -> LogEntry {
+        LogEntry {
+            id: "notify-test".to_string(),
+            repository_path: PathBuf::from("/src/app"),
+            operation_type: OperationType::Push,
+            commit_message: "push".to_string(),
+            scheduled_time: Local::now(),
+            executed_at: Local::now(),
+            status: ExecutionStatus::Failure,
+            error_message: Some("network down".to_string()),
+            error_category: None,
+            hook_output: None,
+        }
+    }
+
+    #[test]
+    fn test_sink_config() {
+        let sinks: Vec<Sink> = serde_json::from_str(
+            r#"[
+                {"type": "desktop"},
+                {"type": "webhook", "url": "https://example.com/hook", "on": ["success"],
+                 "rate_limit": {"max": 2, "per_minutes": 60}},
+                {"type": "sendmail", "to": "me@example.com"}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(sinks[0].kind, SinkKind::Desktop);
+        assert_eq!(sinks[0].on, vec![Event::Failure, Event::Exhausted]);
+        assert_eq!(sinks[1].on, vec![Event::Success]);
+        assert_eq!(sinks[1].rate_limit, Some(RateLimit { max: 2, per_minutes: 60 }));
+        assert!(matches!(&sinks[2].kind, SinkKind::Sendmail { command, .. } if command == "sendmail"));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let sink = Sink {
+            kind: SinkKind::File { path: PathBuf::from("/tmp/rate-limit-test") },
+            on: default_events(),
+            rate_limit: Some(RateLimit { max: 2, per_minutes: 1 }),
+        };
+        let now = Instant::now();
+        assert!(allow(&sink, now));
+        assert!(allow(&sink, now));
+        assert!(!allow(&sink, now));
+        assert!(allow(&sink, now + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_file_sink_appends_json() {
+        let path = std::env::temp_dir().join(format!("git-delayed-notify-{}.jsonl", uuid::Uuid::new_v4()));
+        let sink = Sink {
+            kind: SinkKind::File { path: path.clone() },
+            on: default_events(),
+            rate_limit: None,
+        };
+        send(&sink, Event::Failure, &entry()).unwrap();
+        send(&sink, Event::Exhausted, &entry()).unwrap();
+
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["event"], "exhausted");
+        assert_eq!(lines[1]["entry"]["id"], "notify-test");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_title() {
+        assert_eq!(title(Event::Failure, &entry()), "git-delayed: push failed in app");
+    }
+}
@@ -0,0 +1,278 @@
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::models::{OperationState, Pause, Pauses, RepositoryPause, ScheduledOperation};
+use crate::storage;
+
+// what a pause or resume applies to
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseTarget {
+    All,
+    Repository(PathBuf),
+    Operation(String),
+}
+
+pub fn pause(target: &PauseTarget, until: Option<DateTime<Local>>) -> Result<String> {
+    let now = Local::now();
+    let new_pause = Pause { since: now, until };
+    let until_str = until
+        .map(|t| format!(" until {}", t.format("%Y-%m-%d %H:%M:%S")))
+        .unwrap_or_default();
+
+    match target {
+        PauseTarget::All => {
+            storage::update_pauses(|pauses| {
+                pauses.global = Some(new_pause);
+                Ok(())
+            })?;
+            Ok(format!("all operations paused{}", until_str))
+        }
+        PauseTarget::Repository(path) => {
+            storage::update_pauses(|pauses| {
+                pauses.repositories.retain(|p| &p.repository_path != path);
+                pauses.repositories.push(RepositoryPause {
+                    repository_path: path.clone(),
+                    pause: new_pause,
+                });
+                Ok(())
+            })?;
+            Ok(format!("operations in {} paused{}", path.display(), until_str))
+        }
+        PauseTarget::Operation(id) => {
+            if !is_queued(id)? {
+                return Err(anyhow::anyhow!("Operation not found: {}", id));
+            }
+            // gone between the two looks means the daemon took it off the queue to run it
+            let paused = storage::update_operations(|operation| {
+                if &operation.id != id {
+                    return false;
+                }
+                // pausing again only changes how long for
+                if operation.state != OperationState::Paused {
+                    operation.state_before_pause = Some(operation.state.clone());
+                    operation.state = OperationState::Paused;
+                }
+                operation.paused_until = until;
+                true
+            })?;
+            if paused == 0 {
+                return Err(anyhow::anyhow!("operation {} is already running", id));
+            }
+            Ok(format!("operation {} paused{}", id, until_str))
+        }
+    }
+}
+
+pub fn resume(target: &PauseTarget) -> Result<String> {
+    match target {
+        PauseTarget::All => {
+            storage::update_pauses(|pauses| match pauses.global.take() {
+                Some(_) => Ok(()),
+                None => Err(anyhow::anyhow!("operations aren't paused globally")),
+            })?;
+            Ok("all operations resumed".to_string())
+        }
+        PauseTarget::Repository(path) => {
+            storage::update_pauses(|pauses| {
+                let before = pauses.repositories.len();
+                pauses.repositories.retain(|p| &p.repository_path != path);
+                if pauses.repositories.len() == before {
+                    return Err(anyhow::anyhow!("{} isn't paused", path.display()));
+                }
+                Ok(())
+            })?;
+            Ok(format!("operations in {} resumed", path.display()))
+        }
+        PauseTarget::Operation(id) => {
+            if !is_queued(id)? {
+                return Err(anyhow::anyhow!("Operation not found: {}", id));
+            }
+            let resumed = storage::update_operations(|operation| {
+                if &operation.id != id || operation.state != OperationState::Paused {
+                    return false;
+                }
+                unpause(operation);
+                true
+            })?;
+            if resumed == 0 {
+                return Err(anyhow::anyhow!("operation {} isn't paused", id));
+            }
+            Ok(format!("operation {} resumed", id))
+        }
+    }
+}
+
+// back to the state it was paused in, so a failing or stuck operation isn't re-armed
+fn unpause(operation: &mut ScheduledOperation) {
+    operation.state = operation.state_before_pause.take().unwrap_or_default();
+    operation.paused_until = None;
+}
+
+fn is_queued(id: &str) -> Result<bool> {
+    Ok(storage::load_scheduled_operations()?
+        .operations
+        .iter()
+        .any(|op| op.id == id))
+}
+
+pub fn is_operation_paused(operation: &ScheduledOperation, now: DateTime<Local>) -> bool {
+    operation.state == OperationState::Paused && operation.paused_until.is_none_or(|until| until > now)
+}
+
+// held back by a global or per-repository pause
+pub fn is_held(operation: &ScheduledOperation, pauses: &Pauses, now: DateTime<Local>) -> bool {
+    pauses.global.as_ref().is_some_and(|p| p.is_active(now))
+        || pauses
+            .repositories
+            .iter()
+            .any(|p| p.repository_path == operation.repository_path && p.pause.is_active(now))
+}
+
+// may the daemon run this operation right now
+pub fn is_blocked(operation: &ScheduledOperation, pauses: &Pauses, now: DateTime<Local>) -> bool {
+    is_operation_paused(operation, now) || is_held(operation, pauses, now)
+}
+
+// earliest time a timed pause runs out, so the daemon knows when to look again
+pub fn next_expiry(
+    operations: &[ScheduledOperation],
+    pauses: &Pauses,
+    now: DateTime<Local>,
+) -> Option<DateTime<Local>> {
+    let global = pauses.global.iter().filter_map(|p| p.until);
+    let repos = pauses.repositories.iter().filter_map(|p| p.pause.until);
+    let ops = operations
+        .iter()
+        .filter(|op| op.state == OperationState::Paused)
+        .filter_map(|op| op.paused_until);
+
+    global.chain(repos).chain(ops).filter(|t| *t > now).min()
+}
+
+// drop pauses whose time ran out, true if anything changed
+// overdue operations then go through their misfire policy like any other
+pub fn clear_expired(now: DateTime<Local>) -> Result<bool> {
+    let pauses_changed = storage::update_pauses(|pauses| {
+        let before = (pauses.global.is_some(), pauses.repositories.len());
+        if pauses.global.as_ref().is_some_and(|p| !p.is_active(now)) {
+            pauses.global = None;
+        }
+        pauses.repositories.retain(|p| p.pause.is_active(now));
+        Ok(before != (pauses.global.is_some(), pauses.repositories.len()))
+    })?;
+
+    let operations_changed = storage::update_operations(|operation| {
+        if operation.state != OperationState::Paused || is_operation_paused(operation, now) {
+            return false;
+        }
+        unpause(operation);
+        true
+    })?;
+
+    Ok(pauses_changed || operations_changed > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{MisfirePolicy, OperationType};
+    use chrono::Duration;
+
+    fn operation(repo: &str) -> ScheduledOperation {
+        ScheduledOperation {
+            id: "op".to_string(),
+            repository_path: PathBuf::from(repo),
+            operation_type: OperationType::Push,
+            commit_message: "push".to_string(),
+            scheduled_time: Local::now(),
+            created_at: Local::now(),
+            retry_count: 0,
+            state: OperationState::Pending,
+            branch: None,
+            on_reject: None,
+            misfire: MisfirePolicy::RunImmediately,
+            paused_until: None,
+            state_before_pause: None,
+            timeout_secs: None,
+        }
+    }
+
+    fn pause_until(until: Option<DateTime<Local>>) -> Pause {
+        Pause { since: Local::now(), until }
+    }
+
+    #[test]
+    fn test_global_pause_holds_everything() {
+        let now = Local::now();
+        let pauses = Pauses {
+            global: Some(pause_until(None)),
+            repositories: Vec::new(),
+        };
+        assert!(is_held(&operation("/a"), &pauses, now));
+    }
+
+    #[test]
+    fn test_repository_pause_only_holds_that_repo() {
+        let now = Local::now();
+        let pauses = Pauses {
+            global: None,
+            repositories: vec![RepositoryPause {
+                repository_path: PathBuf::from("/a"),
+                pause: pause_until(None),
+            }],
+        };
+        assert!(is_held(&operation("/a"), &pauses, now));
+        assert!(!is_held(&operation("/b"), &pauses, now));
+    }
+
+    #[test]
+    fn test_expired_pause_no_longer_holds() {
+        let now = Local::now();
+        let pauses = Pauses {
+            global: Some(pause_until(Some(now - Duration::minutes(1)))),
+            repositories: Vec::new(),
+        };
+        assert!(!is_held(&operation("/a"), &pauses, now));
+    }
+
+    #[test]
+    fn test_resume_restores_the_state_before_pause() {
+        for state in [OperationState::Pending, OperationState::Failing, OperationState::NeedsAttention] {
+            let mut op = operation("/a");
+            op.id = uuid::Uuid::new_v4().to_string();
+            op.state = state.clone();
+            storage::add_scheduled_operation(op.clone()).unwrap();
+            let target = PauseTarget::Operation(op.id.clone());
+
+            pause(&target, None).unwrap();
+            // pausing twice mustn't forget where it came from
+            pause(&target, Some(Local::now() + Duration::hours(1))).unwrap();
+            resume(&target).unwrap();
+
+            let queue = storage::load_scheduled_operations().unwrap();
+            let resumed = queue.operations.iter().find(|o| o.id == op.id).unwrap();
+            assert_eq!(resumed.state, state);
+            assert_eq!(resumed.state_before_pause, None);
+            assert_eq!(resumed.paused_until, None);
+            storage::remove_scheduled_operation(&op.id).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_operation_pause_until() {
+        let now = Local::now();
+        let mut op = operation("/a");
+        op.state = OperationState::Paused;
+        assert!(is_operation_paused(&op, now));
+
+        op.paused_until = Some(now + Duration::hours(1));
+        assert!(is_operation_paused(&op, now));
+        assert_eq!(next_expiry(&[op.clone()], &Pauses::default(), now), op.paused_until);
+
+        op.paused_until = Some(now - Duration::hours(1));
+        assert!(!is_operation_paused(&op, now));
+    }
+}
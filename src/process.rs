@@ -0,0 +1,240 @@
+use anyhow::Result;
+use std::fmt;
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how often a running child is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// how long a child gets to exit after SIGTERM before it's killed outright
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+// stands in for a deadline too far out to represent, nothing runs for ten years
+const FAR_FUTURE: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+// a child process that ran past its deadline and was killed
+#[derive(Debug)]
+pub struct TimedOut {
+    pub program: String,
+    pub timeout: Duration,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // rounded up, a step late in an operation gets whatever is left of its time
+        let secs = self.timeout.as_secs_f64().ceil();
+        write!(f, "{} timed out after {}s and was killed", self.program, secs)
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+// run a command without a terminal: no stdin, its own process group,
+// and git told to fail instead of prompting for credentials or passphrases
+pub fn non_interactive(command: &mut Command) -> &mut Command {
+    let already_set = command.get_envs().any(|(key, _)| key == "GIT_SSH_COMMAND");
+    if !already_set {
+        let ssh = batch_ssh_command(
+            std::env::var("GIT_SSH_COMMAND").ok(),
+            std::env::var_os("GIT_SSH").is_some(),
+            configured_ssh_command(command.get_current_dir()),
+        );
+        if let Some(ssh) = ssh {
+            command.env("GIT_SSH_COMMAND", ssh);
+        }
+    }
+    command
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null())
+        .process_group(0)
+}
+
+// BatchMode makes ssh fail instead of asking for a passphrase or host key confirmation
+// GIT_SSH_COMMAND wins over everything else, so it's built from whatever git would have
+// used: $GIT_SSH_COMMAND, then core.sshCommand; $GIT_SSH is a bare program that can't
+// take options, it's left alone
+fn batch_ssh_command(env_command: Option<String>, env_ssh: bool, configured: Option<String>) -> Option<String> {
+    let ssh = match (env_command, env_ssh, configured) {
+        (Some(command), _, _) => command,
+        (None, true, _) => return None,
+        (None, false, Some(command)) => command,
+        (None, false, None) => "ssh".to_string(),
+    };
+    Some(format!("{} -o BatchMode=yes", ssh))
+}
+
+// core.sshCommand of the repository the command runs in, or of the user's config
+fn configured_ssh_command(dir: Option<&Path>) -> Option<String> {
+    let config = match dir.and_then(|dir| git2::Repository::discover(dir).ok()) {
+        Some(repo) => repo.config().ok()?,
+        None => git2::Config::open_default().ok()?,
+    };
+    config.get_string("core.sshCommand").ok()
+}
+
+// `timeout` from now, or as far out as an Instant goes if that's further
+pub fn deadline(timeout: Duration) -> Instant {
+    let now = Instant::now();
+    now.checked_add(timeout).unwrap_or_else(|| now + FAR_FUTURE)
+}
+
+// like Command::output, but the whole process group is killed once `timeout` runs out
+pub fn output_with_timeout(command: &mut Command, timeout: Duration) -> Result<Output> {
+    output_with_input(command, None, timeout)
+}
+
+// same, with `input` fed to the child's stdin instead of nothing
+pub fn output_with_input(command: &mut Command, input: Option<&[u8]>, timeout: Duration) -> Result<Output> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    non_interactive(command);
+    if input.is_some() {
+        command.stdin(Stdio::piped());
+    }
+    let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+
+    // written from a thread, a child that doesn't read its stdin mustn't block us
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        let input = input.to_vec();
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+
+    // drain the pipes in the background so a chatty child can't block on a full pipe
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = deadline(timeout);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            kill_group(&mut child);
+            return Err(TimedOut { program, timeout }.into());
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+fn drain(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+// the child leads its own group, so this also gets ssh, credential helpers and hooks it started
+fn kill_group(child: &mut Child) {
+    use nix::sys::signal::{killpg, Signal};
+    use nix::unistd::Pid;
+
+    let group = Pid::from_raw(child.id() as i32);
+    let _ = killpg(group, Signal::SIGTERM);
+
+    let grace = Instant::now() + KILL_GRACE;
+    while Instant::now() < grace {
+        if matches!(child.try_wait(), Ok(Some(_))) {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    // anything left in the group, including grandchildren that ignored SIGTERM
+    let _ = killpg(group, Signal::SIGKILL);
+    let _ = child.wait();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deadline_too_far_out() {
+        assert!(deadline(Duration::MAX) > Instant::now() + Duration::from_secs(365 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn test_output_with_timeout_finishes() {
+        let output =
+            output_with_timeout(Command::new("sh").args(["-c", "echo hi"]), Duration::from_secs(5)).unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "hi\n");
+    }
+
+    #[test]
+    fn test_output_with_timeout_kills_group() {
+        let started = Instant::now();
+        // the background sleep is a grandchild, it has to die with the group or the pipe stays open
+        let err = output_with_timeout(
+            Command::new("sh").args(["-c", "sleep 30 & sleep 30"]),
+            Duration::from_millis(200),
+        )
+        .unwrap_err();
+        assert!(err.downcast_ref::<TimedOut>().is_some());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_output_with_input() {
+        let output =
+            output_with_input(&mut Command::new("cat"), Some(b"{}"), Duration::from_secs(5)).unwrap();
+        assert_eq!(output.stdout, b"{}");
+    }
+
+    #[test]
+    fn test_batch_ssh_command() {
+        let set = |command: &str| Some(command.to_string());
+        assert_eq!(batch_ssh_command(None, false, None), set("ssh -o BatchMode=yes"));
+        assert_eq!(batch_ssh_command(set("ssh -J jump"), false, set("ssh -i key")), set("ssh -J jump -o BatchMode=yes"));
+        assert_eq!(batch_ssh_command(None, false, set("ssh -i key")), set("ssh -i key -o BatchMode=yes"));
+        assert_eq!(batch_ssh_command(None, true, set("ssh -i key")), None);
+    }
+
+    #[test]
+    fn test_repository_ssh_command_is_kept() {
+        let repo = std::env::temp_dir().join(format!("git-delayed-ssh-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&repo).unwrap();
+        assert!(Command::new("git").arg("init").current_dir(&repo).output().unwrap().status.success());
+        let set = Command::new("git")
+            .args(["config", "core.sshCommand", "ssh -i /keys/deploy"])
+            .current_dir(&repo)
+            .output()
+            .unwrap();
+        assert!(set.status.success());
+
+        let mut command = Command::new("git");
+        command.current_dir(&repo);
+        non_interactive(&mut command);
+        let ssh = command
+            .get_envs()
+            .find(|(key, _)| *key == "GIT_SSH_COMMAND")
+            .and_then(|(_, value)| value)
+            .map(|value| value.to_string_lossy().into_owned());
+        // unless the environment says otherwise, which wins for git too
+        if std::env::var_os("GIT_SSH_COMMAND").is_none() && std::env::var_os("GIT_SSH").is_none() {
+            assert_eq!(ssh.as_deref(), Some("ssh -i /keys/deploy -o BatchMode=yes"));
+        }
+        std::fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_no_stdin() {
+        // would hang reading a terminal if stdin were inherited
+        let output = output_with_timeout(&mut Command::new("cat"), Duration::from_secs(5)).unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+    }
+}
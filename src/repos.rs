@@ -0,0 +1,446 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use git2::Repository;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::git;
+use crate::models::{RegisteredRepository, RepositoryIndex, ScheduledOperation};
+use crate::storage::{self, QueueFile};
+use crate::store;
+
+// a repository can keep its queue in $GIT_DIR/delayed/scheduled.json instead of the global
+// one, so the queue goes wherever the repository goes
+// repositories.json in the storage dir only tells the daemon where to look; a repository
+// that moved is found again by `repo relocate` (which the daemon also runs when it starts),
+// or the next time something is scheduled in it
+const QUEUE_DIR: &str = "delayed";
+const QUEUE_FILE: &str = "scheduled.json";
+const ID_FILE: &str = "id";
+// how many levels below a search dir `relocate` looks
+const SEARCH_DEPTH: usize = 3;
+
+// the queue of one registered repository
+pub struct RepoQueue {
+    pub git_dir: PathBuf,
+    // where the repository is now, operations are run there whatever path they were scheduled with
+    pub workdir: PathBuf,
+    queue: QueueFile,
+}
+
+impl RepoQueue {
+    fn open(git_dir: &Path) -> Result<Self> {
+        let repo = Repository::open(git_dir)?;
+        let workdir = repo.workdir().context("repo has no working directory")?;
+        Ok(Self {
+            git_dir: git_dir.to_path_buf(),
+            workdir: workdir.to_path_buf(),
+            queue: QueueFile::new(queue_path(git_dir)),
+        })
+    }
+
+    pub fn path(&self) -> PathBuf {
+        queue_path(&self.git_dir)
+    }
+
+    fn relocate(&self, mut operation: ScheduledOperation) -> ScheduledOperation {
+        operation.repository_path = self.workdir.clone();
+        operation
+    }
+
+    pub fn operations(&self) -> Result<Vec<ScheduledOperation>> {
+        Ok(self.queue.operations()?.into_iter().map(|op| self.relocate(op)).collect())
+    }
+
+    pub fn enqueue(&self, operation: ScheduledOperation) -> Result<()> {
+        self.queue.extend(vec![self.relocate(operation)])
+    }
+
+    pub fn remove(&self, id: &str) -> Result<bool> {
+        self.queue.remove(id)
+    }
+
+    // the new location is written back along with whatever `change` does
+    pub fn update(&self, change: &mut dyn FnMut(&mut ScheduledOperation) -> bool) -> Result<usize> {
+        self.queue.update(&mut |operation| {
+            operation.repository_path = self.workdir.clone();
+            change(operation)
+        })
+    }
+
+    pub fn claim(&self, id: &str, scheduled_time: DateTime<Local>) -> Result<Option<ScheduledOperation>> {
+        Ok(self.queue.claim(id, scheduled_time)?.map(|op| self.relocate(op)))
+    }
+}
+
+pub fn queue_path(git_dir: &Path) -> PathBuf {
+    git_dir.join(QUEUE_DIR).join(QUEUE_FILE)
+}
+
+fn read_id(git_dir: &Path) -> Option<String> {
+    let id = fs::read_to_string(git_dir.join(QUEUE_DIR).join(ID_FILE)).ok()?;
+    Some(id.trim().to_string()).filter(|id| !id.is_empty())
+}
+
+// the repository's id, made up the first time
+fn repository_id(git_dir: &Path) -> Result<String> {
+    if let Some(id) = read_id(git_dir) {
+        return Ok(id);
+    }
+    let id = Uuid::new_v4().to_string();
+    fs::create_dir_all(git_dir.join(QUEUE_DIR))?;
+    fs::write(git_dir.join(QUEUE_DIR).join(ID_FILE), format!("{}\n", id))?;
+    Ok(id)
+}
+
+// the queue for `repo_path` if it keeps its own, None if it uses the global one
+// (or isn't a repository at all), registering it again in case it moved
+pub fn queue_for(repo_path: &Path) -> Result<Option<RepoQueue>> {
+    let Ok(git_dir) = git::git_dir(repo_path) else {
+        return Ok(None);
+    };
+    if !queue_path(&git_dir).exists() {
+        return Ok(None);
+    }
+    register(&storage::get_repositories_file_path()?, &git_dir)?;
+    Ok(Some(RepoQueue::open(&git_dir)?))
+}
+
+// add or update the repository's entry, a moved one replaces its old entry
+fn register(index: &Path, git_dir: &Path) -> Result<()> {
+    let entry = RegisteredRepository {
+        id: repository_id(git_dir)?,
+        git_dir: git_dir.to_path_buf(),
+    };
+    storage::transaction(index, storage::load_json, |index: &mut RepositoryIndex| {
+        match index.repositories.iter().position(|r| r.id == entry.id) {
+            Some(position) => index.repositories[position] = entry.clone(),
+            None => index.repositories.push(entry.clone()),
+        }
+        // another repository that used to live here is gone
+        index.repositories.retain(|r| r.id == entry.id || r.git_dir != entry.git_dir);
+        Ok(())
+    })
+}
+
+fn unregister(index: &Path, git_dir: &Path) -> Result<()> {
+    let id = read_id(git_dir);
+    storage::transaction(index, storage::load_json, |index: &mut RepositoryIndex| {
+        index
+            .repositories
+            .retain(|r| r.git_dir != git_dir && Some(&r.id) != id.as_ref());
+        Ok(())
+    })
+}
+
+pub fn registered() -> Result<Vec<RegisteredRepository>> {
+    let index: RepositoryIndex = storage::load_json(&storage::get_repositories_file_path()?)?;
+    Ok(index.repositories)
+}
+
+// the queue of a registered repository, None if it isn't where the index says:
+// moved, deleted, on a drive that isn't mounted, or replaced by another repository
+pub fn locate(repository: &RegisteredRepository) -> Option<RepoQueue> {
+    if !queue_path(&repository.git_dir).exists() || read_id(&repository.git_dir)? != repository.id {
+        return None;
+    }
+    RepoQueue::open(&repository.git_dir).ok()
+}
+
+// the queues of the registered repositories that can be found
+pub fn queues() -> Result<Vec<RepoQueue>> {
+    queues_in(&storage::get_repositories_file_path()?)
+}
+
+fn queues_in(index: &Path) -> Result<Vec<RepoQueue>> {
+    let index: RepositoryIndex = storage::load_json(index)?;
+    Ok(index.repositories.iter().filter_map(locate).collect())
+}
+
+// the queues that can be read, the others are left out (with a warning) like the
+// operations in them are, so one broken queue doesn't stop the daemon from working on the rest
+pub fn readable_queues() -> Result<Vec<RepoQueue>> {
+    Ok(readable(queues()?))
+}
+
+fn readable(queues: Vec<RepoQueue>) -> Vec<RepoQueue> {
+    queues
+        .into_iter()
+        .filter(|queue| match queue.operations() {
+            Ok(_) => true,
+            Err(e) => {
+                warn_unreadable(queue, &e);
+                false
+            }
+        })
+        .collect()
+}
+
+// once per queue and error, the daemon reads the queue on every tick
+pub fn warn_unreadable(queue: &RepoQueue, error: &anyhow::Error) {
+    static WARNED: Mutex<Vec<(PathBuf, String)>> = Mutex::new(Vec::new());
+
+    let warning = (queue.path(), format!("{:#}", error));
+    let mut warned = WARNED.lock().unwrap_or_else(|e| e.into_inner());
+    if !warned.contains(&warning) {
+        eprintln!("warning: skipping {}, it can't be read: {}", warning.0.display(), warning.1);
+        warned.push(warning);
+    }
+}
+
+// registered repositories whose queue can't be used, with why
+// their operations are left out of the queue until they're back
+pub fn unavailable() -> Result<Vec<(PathBuf, String)>> {
+    let mut unavailable = Vec::new();
+    for repository in registered()? {
+        match locate(&repository) {
+            None => unavailable.push((
+                repository.git_dir,
+                "not found (moved, deleted or on a drive that isn't mounted)".to_string(),
+            )),
+            Some(queue) => {
+                if let Err(e) = queue.operations() {
+                    unavailable.push((queue.path(), format!("can't be read: {:#}", e)));
+                }
+            }
+        }
+    }
+    Ok(unavailable)
+}
+
+// look for registered repositories that can't be found, near where they used to be and in
+// `search`, and register them where they are now
+// returns each one that was missing with its new working dir, None if it's still missing
+pub fn relocate(search: &[PathBuf]) -> Result<Vec<(RegisteredRepository, Option<PathBuf>)>> {
+    relocate_in(&storage::get_repositories_file_path()?, search)
+}
+
+fn relocate_in(index: &Path, search: &[PathBuf]) -> Result<Vec<(RegisteredRepository, Option<PathBuf>)>> {
+    let registered: RepositoryIndex = storage::load_json(index)?;
+    let mut results = Vec::new();
+    for repository in registered.repositories {
+        if locate(&repository).is_some() {
+            continue;
+        }
+        let mut roots = search_roots(&repository.git_dir);
+        roots.extend(search.iter().cloned());
+        let found = roots.iter().find_map(|root| find_by_id(root, &repository.id, SEARCH_DEPTH));
+        let workdir = match found {
+            Some(git_dir) => {
+                register(index, &git_dir)?;
+                Some(RepoQueue::open(&git_dir)?.workdir)
+            }
+            None => None,
+        };
+        results.push((repository, workdir));
+    }
+    Ok(results)
+}
+
+// the two dirs above the repository's old working dir, where a renamed or moved one usually is
+fn search_roots(git_dir: &Path) -> Vec<PathBuf> {
+    let workdir = match git_dir.file_name() {
+        Some(name) if name == ".git" => git_dir.parent(),
+        _ => git_dir.ancestors().find(|dir| dir.file_name().is_some_and(|name| name == ".git")).and_then(Path::parent),
+    };
+    workdir
+        .into_iter()
+        .flat_map(|workdir| workdir.ancestors().skip(1).take(2))
+        .filter(|dir| dir.parent().is_some())
+        .map(Path::to_path_buf)
+        .collect()
+}
+
+// the git dir of the repository with `id` in or below `dir`, hidden dirs and symlinks aren't followed
+fn find_by_id(dir: &Path, id: &str, depth: usize) -> Option<PathBuf> {
+    if dir.join(".git").exists() {
+        if let Ok(git_dir) = git::git_dir(dir) {
+            if read_id(&git_dir).as_deref() == Some(id) && queue_path(&git_dir).exists() {
+                return Some(git_dir);
+            }
+        }
+    }
+    if depth == 0 {
+        return None;
+    }
+    let mut subdirs: Vec<PathBuf> = fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .map(|entry| entry.path())
+        .collect();
+    subdirs.sort();
+    subdirs.into_iter().find_map(|subdir| find_by_id(&subdir, id, depth - 1))
+}
+
+// keep the queue of the repository at `repo_path` in its git dir, moving its operations
+// out of the global queue; returns how many were moved
+pub fn enable(repo_path: &Path) -> Result<usize> {
+    let git_dir = git::git_dir(repo_path)?;
+    fs::create_dir_all(git_dir.join(QUEUE_DIR))?;
+    let queue = RepoQueue::open(&git_dir)?;
+    // the file existing is what turns it on
+    queue.queue.extend(Vec::new())?;
+    register(&storage::get_repositories_file_path()?, &git_dir)?;
+
+    let global = store::current()?;
+    let moving: Vec<ScheduledOperation> = global
+        .operations()?
+        .into_iter()
+        .filter(|op| op.repository_path == queue.workdir)
+        .collect();
+    // copied before they're removed, a failure in between leaves duplicates rather than losing them
+    queue.queue.extend(moving.clone())?;
+    for operation in &moving {
+        global.remove(&operation.id)?;
+    }
+    Ok(moving.len())
+}
+
+// go back to the global queue, moving the repository's operations there
+// returns how many were moved
+pub fn disable(repo_path: &Path) -> Result<usize> {
+    let git_dir = git::git_dir(repo_path)?;
+    let path = queue_path(&git_dir);
+    if !path.exists() {
+        return Err(anyhow::anyhow!("{} doesn't keep its own queue", repo_path.display()));
+    }
+    let queue = RepoQueue::open(&git_dir)?;
+
+    let moved = storage::locked(&path, || {
+        let (operations, _) = storage::load_queue(&path)?;
+        let operations: Vec<ScheduledOperation> = operations
+            .operations
+            .into_iter()
+            .map(|op| queue.relocate(op))
+            .collect();
+        let count = operations.len();
+        store::current()?.import(operations, Vec::new())?;
+        fs::remove_file(&path)?;
+        Ok(count)
+    })?;
+    unregister(&storage::get_repositories_file_path()?, &git_dir)?;
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{MisfirePolicy, OperationState, OperationType};
+    use std::process::Command;
+
+    fn init_repo(dir: &Path) -> PathBuf {
+        fs::create_dir_all(dir).unwrap();
+        assert!(Command::new("git").arg("init").current_dir(dir).output().unwrap().status.success());
+        git::git_dir(dir).unwrap()
+    }
+
+    fn operation(id: &str, repository_path: &Path) -> ScheduledOperation {
+        ScheduledOperation {
+            id: id.to_string(),
+            repository_path: repository_path.to_path_buf(),
+            operation_type: OperationType::Push,
+            commit_message: "push".to_string(),
+            scheduled_time: Local::now(),
+            created_at: Local::now(),
+            retry_count: 0,
+            state: OperationState::Pending,
+            branch: Some("main".to_string()),
+            on_reject: None,
+            misfire: MisfirePolicy::RunImmediately,
+            paused_until: None,
+            state_before_pause: None,
+            timeout_secs: None,
+        }
+    }
+
+    #[test]
+    fn test_unreadable_queue_is_left_out() {
+        let base = std::env::temp_dir().join(format!("git-delayed-repos-{}", Uuid::new_v4()));
+        let index = base.join("repositories.json");
+        for name in ["good", "broken"] {
+            let git_dir = init_repo(&base.join(name));
+            fs::create_dir_all(git_dir.join(QUEUE_DIR)).unwrap();
+            let contents = if name == "good" { r#"{"operations": []}"# } else { "{ not json" };
+            fs::write(queue_path(&git_dir), contents).unwrap();
+            register(&index, &git_dir).unwrap();
+        }
+        assert_eq!(queues_in(&index).unwrap().len(), 2);
+
+        let readable = readable(queues_in(&index).unwrap());
+        assert_eq!(readable.len(), 1);
+        assert!(readable[0].workdir.ends_with("good/"));
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_queue_follows_a_moved_repository() {
+        let base = std::env::temp_dir().join(format!("git-delayed-repos-{}", Uuid::new_v4()));
+        let index = base.join("repositories.json");
+        let git_dir = init_repo(&base.join("before"));
+        fs::create_dir_all(git_dir.join(QUEUE_DIR)).unwrap();
+        register(&index, &git_dir).unwrap();
+        let queue = RepoQueue::open(&git_dir).unwrap();
+        queue.enqueue(operation("a", &base.join("before"))).unwrap();
+        assert_eq!(queues_in(&index).unwrap().len(), 1);
+
+        fs::rename(base.join("before"), base.join("after")).unwrap();
+        assert!(queues_in(&index).unwrap().is_empty());
+
+        // found again once it's used in its new place
+        register(&index, &git::git_dir(&base.join("after")).unwrap()).unwrap();
+        let registered: RepositoryIndex = storage::load_json(&index).unwrap();
+        assert_eq!(registered.repositories.len(), 1);
+        let queues = queues_in(&index).unwrap();
+        let operations = queues[0].operations().unwrap();
+        assert_eq!(operations[0].id, "a");
+        assert_eq!(fs::canonicalize(&operations[0].repository_path).unwrap(), fs::canonicalize(base.join("after")).unwrap());
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_relocate_finds_a_moved_repository() {
+        let base = std::env::temp_dir().join(format!("git-delayed-repos-{}", Uuid::new_v4()));
+        let index = base.join("repositories.json");
+        let git_dir = init_repo(&base.join("src/app"));
+        register(&index, &git_dir).unwrap();
+        RepoQueue::open(&git_dir).unwrap().queue.extend(Vec::new()).unwrap();
+        let elsewhere = init_repo(&base.join("elsewhere/gone"));
+        register(&index, &elsewhere).unwrap();
+        RepoQueue::open(&elsewhere).unwrap().queue.extend(Vec::new()).unwrap();
+
+        fs::create_dir_all(base.join("projects")).unwrap();
+        fs::rename(base.join("src/app"), base.join("projects/app")).unwrap();
+        fs::remove_dir_all(base.join("elsewhere")).unwrap();
+
+        let results = relocate_in(&index, &[]).unwrap();
+        assert_eq!(results.len(), 2);
+        let found: Vec<_> = results.iter().filter_map(|(_, workdir)| workdir.as_ref()).collect();
+        assert_eq!(found.len(), 1);
+        assert!(found[0].ends_with("projects/app"));
+        assert_eq!(queues_in(&index).unwrap().len(), 1);
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_another_repository_in_the_same_place_isnt_used() {
+        let base = std::env::temp_dir().join(format!("git-delayed-repos-{}", Uuid::new_v4()));
+        let index = base.join("repositories.json");
+        let git_dir = init_repo(&base.join("repo"));
+        register(&index, &git_dir).unwrap();
+        RepoQueue::open(&git_dir).unwrap().queue.extend(Vec::new()).unwrap();
+        assert_eq!(queues_in(&index).unwrap().len(), 1);
+
+        fs::remove_file(git_dir.join(QUEUE_DIR).join(ID_FILE)).unwrap();
+        repository_id(&git_dir).unwrap();
+        assert!(queues_in(&index).unwrap().is_empty());
+
+        fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -2,6 +2,9 @@ use anyhow::Result;
 use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, Timelike, Weekday};
 use regex::Regex;
 
+use crate::config::MAX_TIMEOUT_SECS;
+use crate::models::MisfirePolicy;
+
 // parse things like "+10 hours", "+2 days", "+30 minutes"
 pub fn parse_relative_time(spec: &str) -> Result<DateTime<Local>> {
     let re = Regex::new(r"^\+(\d+)\s*(hours?|days?|minutes?)$")?;
@@ -113,6 +116,103 @@ pub fn parse_time_spec(spec: &str) -> Result<DateTime<Local>> {
     ))
 }
 
+// parse a misfire policy: "run", "skip", "reschedule" or "within:30m" (also h and d)
+pub fn parse_misfire_policy(spec: &str) -> Result<MisfirePolicy> {
+    let spec = spec.trim().to_lowercase();
+    match spec.as_str() {
+        "run" => return Ok(MisfirePolicy::RunImmediately),
+        "skip" => return Ok(MisfirePolicy::Skip),
+        "reschedule" => return Ok(MisfirePolicy::Reschedule),
+        _ => {}
+    }
+
+    let re = Regex::new(r"^within:(\d+)\s*([mhd])$")?;
+    let captures = re
+        .captures(&spec)
+        .ok_or_else(|| anyhow::anyhow!("bad misfire policy, try: run, skip, reschedule or within:30m"))?;
+
+    let too_large = || anyhow::anyhow!("misfire window {} is too large", spec);
+    let amount: i64 = captures[1].parse().map_err(|_| too_large())?;
+    if amount <= 0 {
+        return Err(anyhow::anyhow!("amount must be positive"));
+    }
+
+    let max_late_minutes = match &captures[2] {
+        "h" => amount.checked_mul(60),
+        "d" => amount.checked_mul(60 * 24),
+        _ => Some(amount),
+    }
+    // it's compared with durations, so it has to fit in one
+    .filter(|minutes| Duration::try_minutes(*minutes).is_some())
+    .ok_or_else(too_large)?;
+    Ok(MisfirePolicy::RunIfWithin { max_late_minutes })
+}
+
+// parse an operation timeout like "90s", "10m" or "1h" into seconds
+pub fn parse_timeout(spec: &str) -> Result<u64> {
+    let re = Regex::new(r"^(\d+)\s*([smh])$")?;
+    let spec = spec.trim().to_lowercase();
+    let captures = re
+        .captures(&spec)
+        .ok_or_else(|| anyhow::anyhow!("bad timeout, try: 90s, 10m or 1h"))?;
+
+    let too_long = || anyhow::anyhow!("timeout {} is too long, at most {}h", spec, MAX_TIMEOUT_SECS / 3600);
+    let amount: u64 = captures[1].parse().map_err(|_| too_long())?;
+    if amount == 0 {
+        return Err(anyhow::anyhow!("timeout must be positive"));
+    }
+
+    let secs = match &captures[2] {
+        "h" => amount.checked_mul(3600),
+        "m" => amount.checked_mul(60),
+        _ => Some(amount),
+    };
+    secs.filter(|secs| *secs <= MAX_TIMEOUT_SECS).ok_or_else(too_long)
+}
+
+// parse an age like "90d", "2w" or "12h"
+pub fn parse_age(spec: &str) -> Result<Duration> {
+    let re = Regex::new(r"^(\d+)\s*([hdw])$")?;
+    let spec = spec.trim().to_lowercase();
+    let captures = re
+        .captures(&spec)
+        .ok_or_else(|| anyhow::anyhow!("bad age, try: 90d, 2w or 12h"))?;
+
+    let too_large = || anyhow::anyhow!("age {} is too large", spec);
+    let amount: i64 = captures[1].parse().map_err(|_| too_large())?;
+    match &captures[2] {
+        "w" => Duration::try_weeks(amount),
+        "d" => Duration::try_days(amount),
+        _ => Duration::try_hours(amount),
+    }
+    .ok_or_else(too_large)
+}
+
+// next time after `now` at the same time of day as `scheduled`
+// this is where a missed operation goes when its policy is to reschedule
+// counted in calendar days on the local wall clock, so it stays 09:00 across a DST change
+pub fn next_slot(scheduled: DateTime<Local>, now: DateTime<Local>) -> DateTime<Local> {
+    let time = scheduled.naive_local().time();
+    let mut date = scheduled.date_naive().max(now.date_naive());
+    loop {
+        if let Some(slot) = local_time(date.and_time(time)).filter(|slot| *slot > now) {
+            return slot;
+        }
+        match date.succ_opt() {
+            Some(next) => date = next,
+            None => return now,
+        }
+    }
+}
+
+// a wall clock time in the local timezone, the earlier one if it happens twice when the
+// clocks go back, and an hour later if it's skipped when they go forward
+fn local_time(time: NaiveDateTime) -> Option<DateTime<Local>> {
+    time.and_local_timezone(Local)
+        .earliest()
+        .or_else(|| (time + Duration::hours(1)).and_local_timezone(Local).earliest())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -209,4 +309,80 @@ mod tests {
         let result = parse_time_spec("2020-01-01 00:00");
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_misfire_policy_names() {
+        assert_eq!(parse_misfire_policy("run").unwrap(), MisfirePolicy::RunImmediately);
+        assert_eq!(parse_misfire_policy("Skip").unwrap(), MisfirePolicy::Skip);
+        assert_eq!(parse_misfire_policy("reschedule").unwrap(), MisfirePolicy::Reschedule);
+    }
+
+    #[test]
+    fn test_misfire_policy_within() {
+        assert_eq!(
+            parse_misfire_policy("within:2h").unwrap(),
+            MisfirePolicy::RunIfWithin { max_late_minutes: 120 }
+        );
+        assert_eq!(
+            parse_misfire_policy("within:30m").unwrap(),
+            MisfirePolicy::RunIfWithin { max_late_minutes: 30 }
+        );
+    }
+
+    #[test]
+    fn test_misfire_policy_bad() {
+        assert!(parse_misfire_policy("within:0m").is_err());
+        assert!(parse_misfire_policy("sometimes").is_err());
+        assert!(parse_misfire_policy("within:999999999999999d").is_err());
+        assert!(parse_misfire_policy("within:99999999999999999999m").is_err());
+    }
+
+    #[test]
+    fn test_timeout() {
+        assert_eq!(parse_timeout("90s").unwrap(), 90);
+        assert_eq!(parse_timeout("10m").unwrap(), 600);
+        assert_eq!(parse_timeout("1H").unwrap(), 3600);
+        assert!(parse_timeout("0s").is_err());
+        assert!(parse_timeout("forever").is_err());
+        assert_eq!(parse_timeout("168h").unwrap(), MAX_TIMEOUT_SECS);
+        assert!(parse_timeout("169h").is_err());
+        assert!(parse_timeout("18446744073709551615h").is_err());
+        assert!(parse_timeout("99999999999999999999999s").is_err());
+    }
+
+    #[test]
+    fn test_next_slot_keeps_time_of_day() {
+        let scheduled = parse_absolute_time("2025-11-03 09:00").unwrap();
+        let now = parse_absolute_time("2025-11-05 14:30").unwrap();
+        let slot = next_slot(scheduled, now);
+        assert_eq!(slot, parse_absolute_time("2025-11-06 09:00").unwrap());
+    }
+
+    #[test]
+    fn test_next_slot_later_today() {
+        let scheduled = parse_absolute_time("2025-11-03 18:00").unwrap();
+        let now = parse_absolute_time("2025-11-05 08:00").unwrap();
+        let slot = next_slot(scheduled, now);
+        assert_eq!(slot, parse_absolute_time("2025-11-05 18:00").unwrap());
+    }
+
+    #[test]
+    fn test_next_slot_keeps_wall_time_over_weeks() {
+        // crosses the end of daylight saving time wherever it's observed in the northern hemisphere
+        let scheduled = parse_absolute_time("2025-10-01 09:00").unwrap();
+        let now = parse_absolute_time("2025-11-15 10:00").unwrap();
+        let slot = next_slot(scheduled, now);
+        assert_eq!(slot.naive_local(), parse_absolute_time("2025-11-16 09:00").unwrap().naive_local());
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("90d").unwrap(), Duration::days(90));
+        assert_eq!(parse_age("2w").unwrap(), Duration::days(14));
+        assert_eq!(parse_age("12h").unwrap(), Duration::hours(12));
+        assert!(parse_age("90").is_err());
+        assert!(parse_age("5m").is_err());
+        assert!(parse_age("999999999999999d").is_err());
+        assert!(parse_age("99999999999999999999w").is_err());
+    }
 }
@@ -0,0 +1,65 @@
+use anyhow::Result;
+use std::env;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+// minimal client for systemd's notify protocol: one datagram per state change
+// sent to the socket in $NOTIFY_SOCKET. does nothing when not run under systemd.
+pub fn notify(state: &str) -> Result<bool> {
+    let Some(socket) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = socket.to_string_lossy();
+
+    // "@name" is a socket in the linux abstract namespace
+    let addr = match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name.as_bytes())?
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(anyhow::anyhow!("abstract notify sockets need linux")),
+        None => SocketAddr::from_pathname(socket.as_ref())?,
+    };
+
+    let sock = UnixDatagram::unbound()?;
+    sock.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(true)
+}
+
+// how often to send WATCHDOG=1, half the interval systemd asked for
+// None if the watchdog isn't enabled for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+
+    // the watchdog may be meant for another process in the same unit
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+
+    Some(Duration::from_micros(usec) / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify_sends_datagram() {
+        let path = env::temp_dir().join(format!("git-delayed-notify-{}", uuid::Uuid::new_v4()));
+        let server = UnixDatagram::bind(&path).unwrap();
+        env::set_var("NOTIFY_SOCKET", &path);
+
+        assert!(notify("READY=1").unwrap());
+
+        let mut buf = [0u8; 64];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        env::remove_var("NOTIFY_SOCKET");
+        std::fs::remove_file(path).unwrap();
+    }
+}
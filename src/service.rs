@@ -0,0 +1,449 @@
+use anyhow::{Context, Result};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::storage;
+
+const SYSTEMD_UNIT: &str = "git-delayed";
+const LAUNCHD_LABEL: &str = "com.git-delayed.daemon";
+
+// autostart for the daemon: a systemd unit on linux, a launchd plist on macOS
+// --user installs for the current user only, otherwise system-wide running as the current user
+// every profile gets a service of its own
+pub struct ServiceOptions {
+    pub binary: PathBuf,
+    pub user: bool,
+    pub profile: Option<String>,
+    // GIT_DELAYED_HOME, passed on to the daemon
+    pub home: Option<PathBuf>,
+}
+
+// git-delayed.service, or git-delayed-<profile>.service
+fn systemd_unit_name(profile: Option<&str>) -> String {
+    match profile {
+        Some(profile) => format!("{}-{}.service", SYSTEMD_UNIT, profile),
+        None => format!("{}.service", SYSTEMD_UNIT),
+    }
+}
+
+fn launchd_label(profile: Option<&str>) -> String {
+    match profile {
+        Some(profile) => format!("{}.{}", LAUNCHD_LABEL, profile),
+        None => LAUNCHD_LABEL.to_string(),
+    }
+}
+
+// what the service runs, after the binary
+fn daemon_args(profile: Option<&str>) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(profile) = profile {
+        args.extend(["--profile".to_string(), profile.to_string()]);
+    }
+    args.extend(["daemon", "run", "--foreground"].map(str::to_string));
+    args
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WriteOutcome {
+    Created,
+    Updated,
+    Unchanged,
+}
+
+pub struct ServiceStatus {
+    pub path: PathBuf,
+    pub installed: bool,
+    pub up_to_date: bool,
+    pub active: bool,
+}
+
+pub fn systemd_unit(options: &ServiceOptions) -> String {
+    let (run_as, wanted_by) = if options.user {
+        (String::new(), "default.target")
+    } else {
+        (format!("User={}\n", current_user()), "multi-user.target")
+    };
+    let environment = options
+        .home
+        .as_ref()
+        .map(|home| {
+            let assignment = format!("{}={}", storage::HOME_ENV, home.display());
+            format!("Environment={}\n", systemd_quote(&assignment, false))
+        })
+        .unwrap_or_default();
+    let exec_start: Vec<String> = std::iter::once(options.binary.display().to_string())
+        .chain(daemon_args(options.profile.as_deref()))
+        .map(|arg| systemd_quote(&arg, true))
+        .collect();
+
+    format!(
+        "[Unit]
+Description=Git Delayed Daemon
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=notify
+{run_as}{environment}ExecStart={exec_start}
+Restart=always
+RestartSec=10
+RestartPreventExitStatus=78
+
+[Install]
+WantedBy={wanted_by}
+",
+        exec_start = exec_start.join(" "),
+    )
+}
+
+// a word of a unit file line: specifiers (and variables in ExecStart) are escaped,
+// and it's quoted when it has spaces, quotes or control characters
+fn systemd_quote(value: &str, exec: bool) -> String {
+    let mut escaped = value.replace('%', "%%");
+    if exec {
+        escaped = escaped.replace('$', "$$");
+    }
+    let plain = !escaped.is_empty()
+        && !escaped
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '"' | '\'' | '\\' | ';'));
+    if plain {
+        return escaped;
+    }
+
+    let mut quoted = String::from("\"");
+    for c in escaped.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+pub fn launchd_plist(options: &ServiceOptions, log_dir: &Path) -> String {
+    let run_as = if options.user {
+        String::new()
+    } else {
+        format!("    <key>UserName</key>\n    <string>{}</string>\n", xml_escape(&current_user()))
+    };
+    let args: String = daemon_args(options.profile.as_deref())
+        .iter()
+        .map(|arg| format!("        <string>{}</string>\n", xml_escape(arg)))
+        .collect();
+    let environment = options
+        .home
+        .as_ref()
+        .map(|home| {
+            format!(
+                "    <key>EnvironmentVariables</key>\n    <dict>\n        <key>{}</key>\n        <string>{}</string>\n    </dict>\n",
+                storage::HOME_ENV,
+                xml_escape(&home.display().to_string())
+            )
+        })
+        .unwrap_or_default();
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+{run_as}{environment}    <key>ProgramArguments</key>
+    <array>
+        <string>{binary}</string>
+{args}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardOutPath</key>
+    <string>{log_dir}/launchd.out</string>
+    <key>StandardErrorPath</key>
+    <string>{log_dir}/launchd.err</string>
+</dict>
+</plist>
+"#,
+        label = xml_escape(&launchd_label(options.profile.as_deref())),
+        binary = xml_escape(&options.binary.display().to_string()),
+        log_dir = xml_escape(&log_dir.display().to_string()),
+    )
+}
+
+// who the system service runs as: whoever ran sudo, not root
+fn current_user() -> String {
+    if let Ok(user) = env::var("SUDO_USER") {
+        if !user.is_empty() {
+            return user;
+        }
+    }
+    #[cfg(unix)]
+    if let Ok(Some(user)) = nix::unistd::User::from_uid(nix::unistd::getuid()) {
+        return user.name;
+    }
+    env::var("USER").unwrap_or_else(|_| "root".to_string())
+}
+
+// directory the service file goes into
+pub fn service_dir(user: bool) -> Result<PathBuf> {
+    let home = dirs::home_dir().context("no home dir")?;
+    let dir = match (cfg!(target_os = "macos"), user) {
+        (true, true) => home.join("Library/LaunchAgents"),
+        (true, false) => PathBuf::from("/Library/LaunchDaemons"),
+        (false, true) => home.join(".config/systemd/user"),
+        (false, false) => PathBuf::from("/etc/systemd/system"),
+    };
+    Ok(dir)
+}
+
+fn service_file_name(profile: Option<&str>) -> String {
+    if cfg!(target_os = "macos") {
+        format!("{}.plist", launchd_label(profile))
+    } else {
+        systemd_unit_name(profile)
+    }
+}
+
+fn service_contents(options: &ServiceOptions) -> Result<String> {
+    if cfg!(target_os = "macos") {
+        Ok(launchd_plist(options, &storage::get_storage_dir()?))
+    } else {
+        Ok(systemd_unit(options))
+    }
+}
+
+// write the service file into `dir`, leaving it alone if it's already identical
+pub fn write_service_file(dir: &Path, profile: Option<&str>, contents: &str) -> Result<WriteOutcome> {
+    let path = dir.join(service_file_name(profile));
+    let outcome = match fs::read_to_string(&path) {
+        Ok(existing) if existing == contents => return Ok(WriteOutcome::Unchanged),
+        Ok(_) => WriteOutcome::Updated,
+        Err(_) => WriteOutcome::Created,
+    };
+
+    fs::create_dir_all(dir)?;
+    fs::write(&path, contents).with_context(|| format!("couldn't write {}", path.display()))?;
+    Ok(outcome)
+}
+
+// remove the service file from `dir`, false if there wasn't one
+pub fn remove_service_file(dir: &Path, profile: Option<&str>) -> Result<bool> {
+    let path = dir.join(service_file_name(profile));
+    if !path.exists() {
+        return Ok(false);
+    }
+    fs::remove_file(&path).with_context(|| format!("couldn't remove {}", path.display()))?;
+    Ok(true)
+}
+
+pub fn install(options: &ServiceOptions, start: bool) -> Result<(PathBuf, WriteOutcome)> {
+    let profile = options.profile.as_deref();
+    let dir = service_dir(options.user)?;
+    let outcome = write_service_file(&dir, profile, &service_contents(options)?)?;
+    let path = dir.join(service_file_name(profile));
+    let unit = systemd_unit_name(profile);
+
+    if cfg!(target_os = "macos") {
+        if outcome == WriteOutcome::Updated {
+            let _ = launchctl(&["unload", &path.to_string_lossy()]);
+        }
+        if start && outcome != WriteOutcome::Unchanged {
+            launchctl(&["load", &path.to_string_lossy()])?;
+        }
+    } else {
+        if outcome != WriteOutcome::Unchanged {
+            systemctl(options.user, &["daemon-reload"])?;
+        }
+        systemctl(options.user, &["enable", &unit])?;
+        if start {
+            // restart picks up a changed unit, and starts it if it wasn't running
+            let action = if outcome == WriteOutcome::Unchanged { "start" } else { "restart" };
+            systemctl(options.user, &[action, &unit])?;
+        }
+    }
+
+    Ok((path, outcome))
+}
+
+pub fn uninstall(user: bool, profile: Option<&str>) -> Result<bool> {
+    let dir = service_dir(user)?;
+    let path = dir.join(service_file_name(profile));
+
+    if cfg!(target_os = "macos") {
+        if path.exists() {
+            let _ = launchctl(&["unload", &path.to_string_lossy()]);
+        }
+        remove_service_file(&dir, profile)
+    } else {
+        let _ = systemctl(user, &["disable", "--now", &systemd_unit_name(profile)]);
+        let removed = remove_service_file(&dir, profile)?;
+        if removed {
+            systemctl(user, &["daemon-reload"])?;
+        }
+        Ok(removed)
+    }
+}
+
+pub fn status(options: &ServiceOptions) -> Result<ServiceStatus> {
+    let profile = options.profile.as_deref();
+    let path = service_dir(options.user)?.join(service_file_name(profile));
+    let existing = fs::read_to_string(&path).ok();
+
+    let active = if cfg!(target_os = "macos") {
+        launchctl(&["list", &launchd_label(profile)]).is_ok()
+    } else {
+        systemctl(options.user, &["is-active", "--quiet", &systemd_unit_name(profile)]).is_ok()
+    };
+
+    Ok(ServiceStatus {
+        installed: existing.is_some(),
+        up_to_date: existing.as_deref() == Some(service_contents(options)?.as_str()),
+        active,
+        path,
+    })
+}
+
+fn systemctl(user: bool, args: &[&str]) -> Result<()> {
+    let mut command = Command::new("systemctl");
+    if user {
+        command.arg("--user");
+    }
+    run(command.args(args))
+}
+
+fn launchctl(args: &[&str]) -> Result<()> {
+    run(Command::new("launchctl").args(args))
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    let output = command
+        .output()
+        .with_context(|| format!("couldn't run {:?}", command.get_program()))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        env::temp_dir().join(format!("git-delayed-service-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn options(binary: &str, user: bool) -> ServiceOptions {
+        ServiceOptions {
+            binary: PathBuf::from(binary),
+            user,
+            profile: None,
+            home: None,
+        }
+    }
+
+    #[test]
+    fn test_systemd_user_unit() {
+        let unit = systemd_unit(&options("/opt/bin/git-delayed", true));
+        assert!(unit.contains("ExecStart=/opt/bin/git-delayed daemon run --foreground"));
+        assert!(unit.contains("Type=notify"));
+        assert!(unit.contains("WantedBy=default.target"));
+        assert!(!unit.contains("User="));
+    }
+
+    #[test]
+    fn test_systemd_system_unit_runs_as_user() {
+        let unit = systemd_unit(&options("/usr/local/bin/git-delayed", false));
+        assert!(unit.contains("User="));
+        assert!(unit.contains("WantedBy=multi-user.target"));
+    }
+
+    #[test]
+    fn test_profile_unit() {
+        let options = ServiceOptions {
+            profile: Some("work".to_string()),
+            home: Some(PathBuf::from("/data/git-delayed")),
+            ..options("/opt/bin/git-delayed", true)
+        };
+        let unit = systemd_unit(&options);
+        assert!(unit.contains("ExecStart=/opt/bin/git-delayed --profile work daemon run --foreground"));
+        assert!(unit.contains("Environment=GIT_DELAYED_HOME=/data/git-delayed\n"));
+        assert_eq!(systemd_unit_name(Some("work")), "git-delayed-work.service");
+
+        let plist = launchd_plist(&options, Path::new("/tmp/logs"));
+        assert!(plist.contains("<string>com.git-delayed.daemon.work</string>"));
+        assert!(plist.contains("<string>--profile</string>\n        <string>work</string>"));
+        assert!(plist.contains("<key>GIT_DELAYED_HOME</key>"));
+    }
+
+    #[test]
+    fn test_paths_with_spaces_are_quoted() {
+        let options = ServiceOptions {
+            home: Some(PathBuf::from("/data/my \"git\" 100%")),
+            ..options("/opt/my tools/git-delayed", true)
+        };
+        let unit = systemd_unit(&options);
+        assert!(unit.contains("ExecStart=\"/opt/my tools/git-delayed\" daemon run --foreground\n"));
+        assert!(unit.contains("Environment=\"GIT_DELAYED_HOME=/data/my \\\"git\\\" 100%%\"\n"));
+        assert_eq!(systemd_quote("$HOME", true), "$$HOME");
+
+        let options = ServiceOptions {
+            home: Some(PathBuf::from("/data/a&b <c>")),
+            ..options
+        };
+        let plist = launchd_plist(&options, Path::new("/tmp/R&D"));
+        assert!(plist.contains("<string>/data/a&amp;b &lt;c&gt;</string>"));
+        assert!(plist.contains("<string>/tmp/R&amp;D/launchd.err</string>"));
+    }
+
+    #[test]
+    fn test_launchd_plist() {
+        let plist = launchd_plist(&options("/opt/bin/git-delayed", true), Path::new("/tmp/logs"));
+        assert!(plist.contains("<string>/opt/bin/git-delayed</string>"));
+        assert!(plist.contains("<string>--foreground</string>"));
+        assert!(plist.contains("<string>/tmp/logs/launchd.err</string>"));
+        assert!(!plist.contains("UserName"));
+    }
+
+    #[test]
+    fn test_write_service_file_is_idempotent() {
+        let dir = temp_dir();
+        let unit = systemd_unit(&options("/usr/local/bin/git-delayed", true));
+
+        assert_eq!(write_service_file(&dir, None, &unit).unwrap(), WriteOutcome::Created);
+        assert_eq!(write_service_file(&dir, None, &unit).unwrap(), WriteOutcome::Unchanged);
+
+        let moved = systemd_unit(&options("/opt/git-delayed", true));
+        assert_eq!(write_service_file(&dir, None, &moved).unwrap(), WriteOutcome::Updated);
+        let written = fs::read_to_string(dir.join(service_file_name(None))).unwrap();
+        assert_eq!(written, moved);
+
+        assert!(remove_service_file(&dir, None).unwrap());
+        assert!(!remove_service_file(&dir, None).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
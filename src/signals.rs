@@ -0,0 +1,98 @@
+use anyhow::Result;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::OnceLock;
+
+// set from the signal handlers, picked up by the daemon loop
+static WAKEUP: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+// self-pipe: a byte written here wakes whatever thread is blocked polling the other end,
+// no matter which thread the signal was delivered to
+static WAKE_PAIR: OnceLock<(UnixStream, UnixStream)> = OnceLock::new();
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+fn poke() {
+    let fd = WAKE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        // write(2) is async-signal-safe, a full buffer just means a wakeup is already pending
+        let byte = 1u8;
+        unsafe { nix::libc::write(fd, &byte as *const u8 as *const nix::libc::c_void, 1) };
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn handle_signal(signal: nix::libc::c_int) {
+    match signal {
+        nix::libc::SIGTERM | nix::libc::SIGINT => SHUTDOWN.store(true, Ordering::SeqCst),
+        nix::libc::SIGHUP => RELOAD.store(true, Ordering::SeqCst),
+        _ => {}
+    }
+    // every signal also wakes the loop so it can act on it
+    WAKEUP.store(true, Ordering::SeqCst);
+    poke();
+}
+
+// install the daemon's signal handlers
+// SIGTERM/SIGINT: finish the running operation, then exit cleanly
+// SIGHUP: reload config.json
+// SIGUSR1: scan the queue right away (sent by the CLI after scheduling)
+// no SA_RESTART, so a blocking poll returns early when a signal arrives
+pub fn install() -> Result<()> {
+    if WAKE_PAIR.get().is_none() {
+        let (reader, writer) = UnixStream::pair()?;
+        reader.set_nonblocking(true)?;
+        writer.set_nonblocking(true)?;
+        WAKE_FD.store(writer.as_raw_fd(), Ordering::SeqCst);
+        let _ = WAKE_PAIR.set((reader, writer));
+    }
+
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+        let action = SigAction::new(
+            SigHandler::Handler(handle_signal),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        for signal in [Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP, Signal::SIGUSR1] {
+            unsafe { sigaction(signal, &action)? };
+        }
+    }
+    Ok(())
+}
+
+// readable end of the self-pipe, for the waker to poll on
+pub fn wake_receiver() -> Option<&'static UnixStream> {
+    WAKE_PAIR.get().map(|(reader, _)| reader)
+}
+
+// same as receiving SIGUSR1, for other threads in the daemon
+pub fn request_wakeup() {
+    WAKEUP.store(true, Ordering::SeqCst);
+    poke();
+}
+
+// same as receiving SIGHUP
+pub fn request_reload() {
+    RELOAD.store(true, Ordering::SeqCst);
+    request_wakeup();
+}
+
+// true if a wakeup was requested since the last call
+pub fn take_wakeup() -> bool {
+    WAKEUP.swap(false, Ordering::SeqCst)
+}
+
+// true if a config reload was requested since the last call
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+// stays true once a shutdown was requested
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
@@ -0,0 +1,445 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde_json::{json, Value};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::migrations;
+use crate::models::{LogEntry, ScheduledOperation};
+use crate::storage;
+use crate::store::{LogQuery, Store};
+
+// how long a write waits for another process's transaction to finish
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// each row keeps the whole operation or entry as JSON, so new fields need no schema change
+// the other columns are copies for indexing and filtering
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS operations (
+        id TEXT PRIMARY KEY,
+        repository_path TEXT NOT NULL,
+        state TEXT NOT NULL,
+        scheduled_at INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS operations_scheduled_at ON operations (scheduled_at);
+
+    CREATE TABLE IF NOT EXISTS logs (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        id TEXT NOT NULL,
+        repository_path TEXT NOT NULL,
+        status TEXT NOT NULL,
+        executed_at INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS logs_executed_at ON logs (executed_at);
+    CREATE INDEX IF NOT EXISTS logs_repository ON logs (repository_path, executed_at);
+    CREATE INDEX IF NOT EXISTS logs_id ON logs (id);
+";
+
+// the schema version is kept in PRAGMA user_version, 0 for a new database
+// MIGRATIONS[n] brings version n to n + 1, the first one creates the tables
+const MIGRATIONS: &[&str] = &[SCHEMA];
+
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path).with_context(|| format!("couldn't open {}", path.display()))?;
+        Self::init(connection, Some(path))
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?, None)
+    }
+
+    fn init(mut connection: Connection, path: Option<&Path>) -> Result<Self> {
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        migrate(&mut connection, path)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // a write transaction, taken up front so two processes can't both read and then write
+    fn write<R>(&self, change: impl FnOnce(&rusqlite::Transaction) -> Result<R>) -> Result<R> {
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let result = change(&tx)?;
+        tx.commit()?;
+        Ok(result)
+    }
+}
+
+// bring the database up to the current schema, backing up the file first if it had data
+fn migrate(connection: &mut Connection, path: Option<&Path>) -> Result<()> {
+    let tx = connection.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+    let version: u32 = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let current = MIGRATIONS.len() as u32;
+    if version > current {
+        return Err(anyhow::anyhow!(
+            "{} was written by a newer git-delayed (database version {}, this one knows up to {}), upgrade git-delayed to use it",
+            path.map(|p| p.display().to_string()).unwrap_or_else(|| "the database".to_string()),
+            version,
+            current
+        ));
+    }
+    if version == current {
+        return Ok(());
+    }
+
+    if let (Some(path), true) = (path, version > 0) {
+        migrations::backup(path, version)?;
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        tx.execute_batch(migration)?;
+    }
+    tx.pragma_update(None, "user_version", current)?;
+    tx.commit()?;
+    Ok(())
+}
+
+// what's wrong with the database at `path`, nothing if it's healthy
+pub fn check(path: &Path) -> Result<Vec<String>> {
+    // opening it also refuses a database from a newer version
+    drop(SqliteStore::open(path)?);
+    let connection = Connection::open(path)?;
+
+    let mut problems = Vec::new();
+    let mut statement = connection.prepare("PRAGMA integrity_check")?;
+    for message in statement.query_map([], |row| row.get::<_, String>(0))? {
+        let message = message?;
+        if message != "ok" {
+            problems.push(message);
+        }
+    }
+
+    let (_, lost) = read_rows(&connection, "SELECT data FROM operations", parse_operation);
+    if lost > 0 {
+        problems.push(format!("{} queued operation(s) can't be read", lost));
+    }
+    let (_, lost) = read_rows(&connection, "SELECT data FROM logs", parse_entry);
+    if lost > 0 {
+        problems.push(format!("{} log entries can't be read", lost));
+    }
+    Ok(problems)
+}
+
+// replace the database with a fresh one holding every row that can still be read
+// the original is quarantined first, returns the copy and how many rows were lost
+pub fn rebuild(path: &Path) -> Result<(PathBuf, usize)> {
+    let quarantined = storage::quarantine(path)?;
+    let connection = Connection::open(path)?;
+    let (operations, lost_operations) =
+        read_rows(&connection, "SELECT data FROM operations ORDER BY scheduled_at, rowid", parse_operation);
+    let (logs, lost_logs) = read_rows(&connection, "SELECT data FROM logs ORDER BY seq", parse_entry);
+    drop(connection);
+
+    let name = path.file_name().context("no file name")?.to_string_lossy();
+    let fresh = path.with_file_name(format!("{}.rebuild", name));
+    let _ = fs::remove_file(&fresh);
+    SqliteStore::open(&fresh)?.import(operations, logs)?;
+    fs::rename(&fresh, path)?;
+    let _ = fs::remove_file(path.with_file_name(format!("{}-journal", name)));
+    Ok((quarantined, lost_operations + lost_logs))
+}
+
+// the rows of a `SELECT data` that still parse, and how many didn't
+// reading stops at the first row sqlite itself can't return
+fn read_rows<T>(connection: &Connection, sql: &str, parse: fn(&str) -> Result<T>) -> (Vec<T>, usize) {
+    let mut rows = Vec::new();
+    let mut lost = 0;
+    let Ok(mut statement) = connection.prepare(sql) else {
+        return (rows, 1);
+    };
+    let Ok(mut query) = statement.query([]) else {
+        return (rows, 1);
+    };
+    loop {
+        match query.next() {
+            Ok(Some(row)) => match row.get::<_, String>(0).ok().and_then(|data| parse(&data).ok()) {
+                Some(value) => rows.push(value),
+                None => lost += 1,
+            },
+            Ok(None) => break,
+            Err(_) => {
+                lost += 1;
+                break;
+            }
+        }
+    }
+    (rows, lost)
+}
+
+// a row written by an older git-delayed is missing the fields added since, the rows don't
+// record a version but the steps only fill in missing fields, so they're safe on any row
+fn parse_operation(data: &str) -> Result<ScheduledOperation> {
+    let mut queue = json!({ "operations": [serde_json::from_str::<Value>(data)?] });
+    migrations::upgrade(&migrations::QUEUE, &mut queue, 1)?;
+    Ok(serde_json::from_value(queue["operations"][0].take())?)
+}
+
+fn parse_entry(data: &str) -> Result<LogEntry> {
+    let mut entry: Value = serde_json::from_str(data)?;
+    migrations::upgrade(&migrations::LOG, &mut entry, 1)?;
+    Ok(serde_json::from_value(entry)?)
+}
+
+fn millis(time: DateTime<Local>) -> i64 {
+    time.timestamp_millis()
+}
+
+fn insert_operation(tx: &rusqlite::Transaction, operation: &ScheduledOperation) -> Result<()> {
+    tx.execute(
+        "INSERT INTO operations (id, repository_path, state, scheduled_at, data) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            operation.id,
+            operation.repository_path.to_string_lossy(),
+            operation.state.to_string(),
+            millis(operation.scheduled_time),
+            serde_json::to_string(operation)?,
+        ],
+    )
+    .with_context(|| format!("couldn't queue {}", operation.id))?;
+    Ok(())
+}
+
+fn insert_log(tx: &rusqlite::Transaction, entry: &LogEntry) -> Result<()> {
+    tx.execute(
+        "INSERT INTO logs (id, repository_path, status, executed_at, data) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            entry.id,
+            entry.repository_path.to_string_lossy(),
+            entry.status.to_string().to_lowercase(),
+            millis(entry.executed_at),
+            serde_json::to_string(entry)?,
+        ],
+    )?;
+    Ok(())
+}
+
+impl Store for SqliteStore {
+    fn operations(&self) -> Result<Vec<ScheduledOperation>> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let mut statement = connection.prepare("SELECT data FROM operations ORDER BY scheduled_at, rowid")?;
+        let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+        rows.map(|data| parse_operation(&data?)).collect()
+    }
+
+    fn enqueue(&self, operation: ScheduledOperation) -> Result<()> {
+        self.write(|tx| insert_operation(tx, &operation))
+    }
+
+    fn remove(&self, id: &str) -> Result<bool> {
+        self.write(|tx| Ok(tx.execute("DELETE FROM operations WHERE id = ?1", [id])? > 0))
+    }
+
+    fn update(&self, change: &mut dyn FnMut(&mut ScheduledOperation) -> bool) -> Result<usize> {
+        self.write(|tx| {
+            let operations: Vec<ScheduledOperation> = {
+                let mut statement = tx.prepare("SELECT data FROM operations")?;
+                let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+                rows.map(|data| parse_operation(&data?)).collect::<Result<_>>()?
+            };
+
+            let mut changed = 0;
+            for mut operation in operations {
+                if !change(&mut operation) {
+                    continue;
+                }
+                tx.execute(
+                    "UPDATE operations SET repository_path = ?2, state = ?3, scheduled_at = ?4, data = ?5
+                     WHERE id = ?1",
+                    params![
+                        operation.id,
+                        operation.repository_path.to_string_lossy(),
+                        operation.state.to_string(),
+                        millis(operation.scheduled_time),
+                        serde_json::to_string(&operation)?,
+                    ],
+                )?;
+                changed += 1;
+            }
+            Ok(changed)
+        })
+    }
+
+    fn claim(&self, id: &str, scheduled_time: DateTime<Local>) -> Result<Option<ScheduledOperation>> {
+        self.write(|tx| {
+            let data: Option<String> = tx
+                .query_row("SELECT data FROM operations WHERE id = ?1", [id], |row| row.get(0))
+                .optional()?;
+            let Some(data) = data else {
+                return Ok(None);
+            };
+            let operation = parse_operation(&data)?;
+            if operation.scheduled_time != scheduled_time {
+                return Ok(None);
+            }
+            tx.execute("DELETE FROM operations WHERE id = ?1", [id])?;
+            Ok(Some(operation))
+        })
+    }
+
+    fn append_log(&self, entry: LogEntry) -> Result<()> {
+        self.write(|tx| insert_log(tx, &entry))
+    }
+
+    fn logs(&self, query: &LogQuery) -> Result<Vec<LogEntry>> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let mut statement = connection.prepare(
+            "SELECT data FROM logs
+             WHERE (?1 IS NULL OR repository_path = ?1)
+               AND (?2 IS NULL OR id = ?2)
+               AND (?3 IS NULL OR executed_at >= ?3)
+             ORDER BY executed_at DESC, seq DESC
+             LIMIT ?4",
+        )?;
+        let rows = statement.query_map(
+            params![
+                query.repository_path.as_ref().map(|p| p.to_string_lossy().into_owned()),
+                query.id,
+                query.since.map(millis),
+                query.limit.map(|limit| limit as i64).unwrap_or(-1),
+            ],
+            |row| row.get::<_, String>(0),
+        )?;
+        rows.map(|data| parse_entry(&data?)).collect()
+    }
+
+    fn import(&self, operations: Vec<ScheduledOperation>, logs: Vec<LogEntry>) -> Result<()> {
+        self.write(|tx| {
+            for operation in &operations {
+                insert_operation(tx, operation)?;
+            }
+            for entry in &logs {
+                insert_log(tx, entry)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn prune_logs(&self, before: DateTime<Local>) -> Result<usize> {
+        self.write(|tx| Ok(tx.execute("DELETE FROM logs WHERE executed_at < ?1", [millis(before)])?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::conformance;
+
+    #[test]
+    fn test_sqlite_store() {
+        conformance::check(&SqliteStore::in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_duplicate_id_is_rejected() {
+        let store = SqliteStore::in_memory().unwrap();
+        store.enqueue(conformance::operation("a", "/one")).unwrap();
+        assert!(store.enqueue(conformance::operation("a", "/one")).is_err());
+        assert_eq!(store.operations().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_newer_database_is_refused() {
+        let path = std::env::temp_dir().join(format!("git-delayed-{}.db", uuid::Uuid::new_v4()));
+        SqliteStore::open(&path).unwrap();
+        Connection::open(&path)
+            .unwrap()
+            .pragma_update(None, "user_version", MIGRATIONS.len() as u32 + 1)
+            .unwrap();
+
+        let error = SqliteStore::open(&path).err().unwrap();
+        assert!(error.to_string().contains("newer git-delayed"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_rebuild_keeps_readable_rows() {
+        let path = std::env::temp_dir().join(format!("git-delayed-{}.db", uuid::Uuid::new_v4()));
+        let store = SqliteStore::open(&path).unwrap();
+        store.enqueue(conformance::operation("a", "/one")).unwrap();
+        store.append_log(conformance::entry("a", "/one", Local::now())).unwrap();
+        drop(store);
+        Connection::open(&path)
+            .unwrap()
+            .execute(
+                "INSERT INTO operations (id, repository_path, state, scheduled_at, data) VALUES ('b', '/one', 'pending', 0, '{')",
+                [],
+            )
+            .unwrap();
+
+        assert_eq!(check(&path).unwrap(), ["1 queued operation(s) can't be read"]);
+        let (quarantined, lost) = rebuild(&path).unwrap();
+        assert_eq!(lost, 1);
+        assert!(check(&path).unwrap().is_empty());
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.operations().unwrap().len(), 1);
+        assert_eq!(store.logs(&LogQuery::default()).unwrap().len(), 1);
+        std::fs::remove_file(quarantined).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_rows_from_an_older_version_are_upgraded() {
+        let store = SqliteStore::in_memory().unwrap();
+        let mut operation = serde_json::to_value(conformance::operation("a", "/one")).unwrap();
+        let mut entry = serde_json::to_value(conformance::entry("a", "/one", Local::now())).unwrap();
+        // as they were written before the fields added by the migration steps
+        let added = [
+            "retry_count", "state", "branch", "on_reject", "misfire", "paused_until", "timeout_secs",
+            "state_before_pause",
+        ];
+        for field in added {
+            operation.as_object_mut().unwrap().remove(field);
+        }
+        for field in ["error_category", "hook_output"] {
+            entry.as_object_mut().unwrap().remove(field);
+        }
+        {
+            let connection = store.connection.lock().unwrap();
+            connection
+                .execute(
+                    "INSERT INTO operations (id, repository_path, state, scheduled_at, data) VALUES ('a', '/one', 'pending', 0, ?1)",
+                    [operation.to_string()],
+                )
+                .unwrap();
+            connection
+                .execute(
+                    "INSERT INTO logs (id, repository_path, status, executed_at, data) VALUES ('a', '/one', 'success', 0, ?1)",
+                    [entry.to_string()],
+                )
+                .unwrap();
+        }
+
+        let operations = store.operations().unwrap();
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].retry_count, 0);
+        assert!(operations[0].state_before_pause.is_none());
+        assert_eq!(store.update(&mut |op| { op.retry_count = 1; true }).unwrap(), 1);
+        assert_eq!(store.logs(&LogQuery::default()).unwrap().len(), 1);
+        let claimed = store.claim("a", operations[0].scheduled_time).unwrap().unwrap();
+        assert_eq!(claimed.retry_count, 1);
+    }
+
+    #[test]
+    fn test_shared_file_between_connections() {
+        let path = std::env::temp_dir().join(format!("git-delayed-{}.db", uuid::Uuid::new_v4()));
+        let cli = SqliteStore::open(&path).unwrap();
+        let daemon = SqliteStore::open(&path).unwrap();
+
+        let operation = conformance::operation("a", "/one");
+        cli.enqueue(operation.clone()).unwrap();
+        assert!(daemon.claim("a", operation.scheduled_time).unwrap().is_some());
+        assert!(cli.operations().unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}
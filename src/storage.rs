@@ -1,140 +1,660 @@
 use anyhow::{Context, Result};
-use crate::models::{LogEntry, OperationLogs, ScheduledOperation, ScheduledOperations};
-use std::fs;
-use std::path::PathBuf;
+use chrono::{DateTime, Local};
+use crate::logfile::{LogFile, Rotation};
+use crate::migrations;
+use crate::repos;
+use crate::models::{Heartbeat, LogEntry, Pauses, ScheduledOperation, ScheduledOperations};
+use crate::store::{self, LogQuery, Store};
+use fs2::FileExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// keep config and state together in this dir instead of the platform's defaults
+pub const HOME_ENV: &str = "GIT_DELAYED_HOME";
+// the profile to use when there's no --profile
+pub const PROFILE_ENV: &str = "GIT_DELAYED_PROFILE";
+const PROFILES_DIR: &str = "profiles";
 
 const SCHEDULED_FILE: &str = "scheduled.json";
-const LOGS_FILE: &str = "logs.json";
+const DATABASE_FILE: &str = "git-delayed.db";
+const PAUSES_FILE: &str = "pauses.json";
+const HEARTBEAT_FILE: &str = "heartbeat.json";
 const PID_FILE: &str = "daemon.pid";
+const LOCK_FILE: &str = "daemon.lock";
+const SOCKET_DIR: &str = "run";
+const SOCKET_FILE: &str = "control.sock";
+const CONFIG_FILE: &str = "config.json";
+const WORKTREES_DIR: &str = "worktrees";
+const LOCKS_DIR: &str = "locks";
+const HOOKS_DIR: &str = "hooks";
+const REPOSITORIES_FILE: &str = "repositories.json";
 
-// get the storage directory, creating it if needed
-// macOS: ~/Library/Application Support/git-delayed
-// Linux: ~/.config/git-delayed
-pub fn get_storage_dir() -> Result<PathBuf> {
-    let dir = dirs::config_dir()
-        .context("no config dir")?
-        .join("git-delayed");
+// files that mean a config dir still holds the queue, from before state had a dir of its own
+const STATE_FILES: &[&str] = &[SCHEDULED_FILE, DATABASE_FILE, "logs.jsonl", "logs.json"];
 
-    if !dir.exists() {
-        fs::create_dir_all(&dir)?;
+// use a profile for the rest of the process
+// it goes into the environment so git-delayed started from hooks uses it too
+pub fn set_profile(name: &str) -> Result<()> {
+    validate_profile(name)?;
+    env::set_var(PROFILE_ENV, name);
+    Ok(())
+}
+
+// the profile in use, None for the default one
+pub fn profile() -> Result<Option<String>> {
+    match env::var(PROFILE_ENV) {
+        Ok(name) if !name.is_empty() => {
+            validate_profile(&name)?;
+            Ok(Some(name))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn validate_profile(name: &str) -> Result<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(anyhow::anyhow!("bad profile name '{}', use letters, digits, - and _", name));
+    }
+    Ok(())
+}
+
+struct Locations {
+    config: PathBuf,
+    state: PathBuf,
+}
+
+#[cfg(not(test))]
+fn base_locations() -> Result<Locations> {
+    if let Some(home) = env::var_os(HOME_ENV).filter(|home| !home.is_empty()) {
+        let home = PathBuf::from(home);
+        return Ok(Locations {
+            config: home.clone(),
+            state: home,
+        });
     }
+    let config = dirs::config_dir().context("no config dir")?.join("git-delayed");
+    // macOS has no separate place for state
+    let state = dirs::state_dir()
+        .map(|dir| dir.join("git-delayed"))
+        .unwrap_or_else(|| config.clone());
+    Ok(Locations { config, state })
+}
+
+// tests never touch the real queue or config
+#[cfg(test)]
+fn base_locations() -> Result<Locations> {
+    let home = env::temp_dir().join(format!("git-delayed-test-{}", std::process::id()));
+    Ok(Locations {
+        config: home.join("config"),
+        state: home.join("state"),
+    })
+}
 
+// a profile lives in profiles/<name> under both dirs
+// an existing queue in the config dir stays where it is, moving it under a running daemon isn't safe
+fn resolve(base: Locations, profile: Option<&str>) -> Locations {
+    let (config, state) = match profile {
+        Some(name) => (
+            base.config.join(PROFILES_DIR).join(name),
+            base.state.join(PROFILES_DIR).join(name),
+        ),
+        None => (base.config, base.state),
+    };
+    let legacy = state != config && !state.exists() && STATE_FILES.iter().any(|file| config.join(file).exists());
+    Locations {
+        state: if legacy { config.clone() } else { state },
+        config,
+    }
+}
+
+fn locations() -> Result<Locations> {
+    Ok(resolve(base_locations()?, profile()?.as_deref()))
+}
+
+fn ensure_dir(dir: PathBuf) -> Result<PathBuf> {
+    if !dir.exists() {
+        fs::create_dir_all(&dir).with_context(|| format!("couldn't create {}", dir.display()))?;
+    }
     Ok(dir)
 }
 
+// where config.json and hooks are, created if needed
+// $GIT_DELAYED_HOME, or ~/.config/git-delayed (~/Library/Application Support/git-delayed on macOS)
+pub fn get_config_dir() -> Result<PathBuf> {
+    ensure_dir(locations()?.config)
+}
+
+// where the queue, logs and daemon files are, created if needed
+// $GIT_DELAYED_HOME, or ~/.local/state/git-delayed on Linux and the config dir on macOS
+pub fn get_storage_dir() -> Result<PathBuf> {
+    ensure_dir(locations()?.state)
+}
+
 pub fn get_scheduled_file_path() -> Result<PathBuf> {
     Ok(get_storage_dir()?.join(SCHEDULED_FILE))
 }
 
-pub fn get_logs_file_path() -> Result<PathBuf> {
-    Ok(get_storage_dir()?.join(LOGS_FILE))
+// the sqlite backend's database, see store.rs
+pub fn get_database_path() -> Result<PathBuf> {
+    Ok(get_storage_dir()?.join(DATABASE_FILE))
+}
+
+// sqlite writes a rollback journal next to it and deletes it on commit
+pub fn get_database_journal_path() -> Result<PathBuf> {
+    Ok(get_storage_dir()?.join(format!("{}-journal", DATABASE_FILE)))
+}
+
+// the repositories keeping their own queue, see repos.rs
+pub fn get_repositories_file_path() -> Result<PathBuf> {
+    Ok(get_storage_dir()?.join(REPOSITORIES_FILE))
+}
+
+pub fn get_pauses_file_path() -> Result<PathBuf> {
+    Ok(get_storage_dir()?.join(PAUSES_FILE))
+}
+
+pub fn get_heartbeat_file_path() -> Result<PathBuf> {
+    Ok(get_storage_dir()?.join(HEARTBEAT_FILE))
 }
 
 pub fn get_pid_file_path() -> Result<PathBuf> {
     Ok(get_storage_dir()?.join(PID_FILE))
 }
 
-use fs2::FileExt;
-use std::fs::File;
-use std::thread;
-use std::time::Duration;
+pub fn get_lock_file_path() -> Result<PathBuf> {
+    Ok(get_storage_dir()?.join(LOCK_FILE))
+}
+
+// a dir of its own so only it has to be private, created by the daemon when it binds the socket
+pub fn get_socket_dir() -> Result<PathBuf> {
+    Ok(get_storage_dir()?.join(SOCKET_DIR))
+}
+
+pub fn get_socket_path() -> Result<PathBuf> {
+    Ok(get_socket_dir()?.join(SOCKET_FILE))
+}
+
+pub fn get_config_file_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join(CONFIG_FILE))
+}
 
-// try to get an exclusive lock on a file, with exponential backoff
-// gives up after 3 attempts
+// scratch worktrees used to rebase/merge rejected pushes
+pub fn get_worktrees_dir() -> Result<PathBuf> {
+    let dir = get_storage_dir()?.join(WORKTREES_DIR);
+    if !dir.exists() {
+        fs::create_dir_all(&dir)?;
+    }
+    Ok(dir)
+}
+
+// user hook scripts that apply to every repository
+pub fn get_hooks_dir() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join(HOOKS_DIR))
+}
+
+// per-operation lock files, so the daemon and `git delayed run` never run the same one twice
+pub fn get_locks_dir() -> Result<PathBuf> {
+    let dir = get_storage_dir()?.join(LOCKS_DIR);
+    if !dir.exists() {
+        fs::create_dir_all(&dir)?;
+    }
+    Ok(dir)
+}
+
+// how long a transaction waits for another process to finish with a file
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// hold an exclusive lock on `file` while `operation` runs
+// waits with backoff, up to LOCK_TIMEOUT
 pub fn with_file_lock<F, T>(file: &File, operation: F) -> Result<T>
 where
     F: FnOnce() -> Result<T>,
 {
-    for attempt in 0..3 {
-        if file.try_lock_exclusive().is_ok() {
-            let result = operation();
-            let _ = FileExt::unlock(file);
-            return result;
+    let deadline = Instant::now() + LOCK_TIMEOUT;
+    let mut delay = Duration::from_millis(10);
+    while file.try_lock_exclusive().is_err() {
+        if Instant::now() >= deadline {
+            return Err(anyhow::anyhow!("couldn't acquire file lock"));
         }
+        thread::sleep(delay);
         // wait a bit longer each time
-        thread::sleep(Duration::from_millis(100 * (1 << (attempt + 1))));
+        delay = (delay * 2).min(Duration::from_millis(200));
     }
-    Err(anyhow::anyhow!("couldn't acquire file lock"))
+    let result = operation();
+    let _ = FileExt::unlock(file);
+    result
 }
 
-pub fn load_scheduled_operations() -> Result<ScheduledOperations> {
-    let path = get_scheduled_file_path()?;
+// read a JSON data file, the default if it's missing or empty
+pub fn load_json<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
     if !path.exists() {
-        return Ok(ScheduledOperations::default());
+        return Ok(T::default());
     }
-    
-    let content = fs::read_to_string(&path)?;
+
+    let content = fs::read_to_string(path)?;
     if content.trim().is_empty() {
-        return Ok(ScheduledOperations::default());
+        return Ok(T::default());
     }
-    
-    Ok(serde_json::from_str(&content)?)
+
+    serde_json::from_str(&content).with_context(|| parse_error(path))
 }
 
-pub fn save_scheduled_operations(operations: &ScheduledOperations) -> Result<()> {
-    let path = get_scheduled_file_path()?;
-    let file = fs::OpenOptions::new()
+// `git delayed doctor --fix` can move a broken file aside and keep what still parses
+fn parse_error(path: &Path) -> String {
+    format!("couldn't parse {} (`git delayed doctor` can repair it)", path.display())
+}
+
+// copy a broken file to `<file>.corrupt-<time>` before it's repaired
+pub fn quarantine(path: &Path) -> Result<PathBuf> {
+    let name = path.file_name().context("no file name")?.to_string_lossy();
+    let stamp = Local::now().format("%Y%m%d-%H%M%S");
+    let mut target = path.with_file_name(format!("{}.corrupt-{}", name, stamp));
+    // never overwrite an earlier copy from the same second
+    let mut n = 1;
+    while target.exists() {
+        target = path.with_file_name(format!("{}.corrupt-{}-{}", name, stamp, n));
+        n += 1;
+    }
+    fs::copy(path, &target).with_context(|| format!("couldn't copy {} aside", path.display()))?;
+    Ok(target)
+}
+
+// replace `path` without ever leaving it half written:
+// write a temp file next to it, fsync it, rename it over, fsync the directory
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    // a temp file of its own for every write, the daemon's threads write the heartbeat at the same time
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+    let name = path.file_name().context("no file name")?.to_string_lossy();
+    let tmp = path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        name,
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })?;
+
+    // the rename itself has to reach the disk too
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// a read-modify-write cycle on a JSON data file
+// `<file>.lock` is held from before the read until after the write, so concurrent
+// transactions from the CLI and the daemon (or its threads) never lose each other's changes
+// the file is only rewritten if it would change, which includes an upgraded schema
+pub fn transaction<T, R>(
+    path: &Path,
+    load: impl FnOnce(&Path) -> Result<T>,
+    change: impl FnOnce(&mut T) -> Result<R>,
+) -> Result<R>
+where
+    T: Serialize,
+{
+    locked(path, || {
+        let before = fs::read_to_string(path).unwrap_or_default();
+        let mut value = load(path)?;
+        let result = change(&mut value)?;
+        let after = serde_json::to_string_pretty(&value)?;
+        if after != before {
+            write_atomic(path, after.as_bytes())?;
+        }
+        Ok(result)
+    })
+}
+
+// hold `<file>.lock`, the lock every transaction on `path` takes
+pub fn locked<R>(path: &Path, operation: impl FnOnce() -> Result<R>) -> Result<R> {
+    let name = path.file_name().context("no file name")?.to_string_lossy();
+    let lock = fs::OpenOptions::new()
         .create(true)
+        .truncate(false)
         .write(true)
-        .truncate(true)
-        .open(&path)?;
-    
-    with_file_lock(&file, || {
-        let content = serde_json::to_string_pretty(operations)?;
-        fs::write(&path, content)?;
-        Ok(())
+        .open(path.with_file_name(format!("{}.lock", name)))?;
+    with_file_lock(&lock, operation)
+}
+
+// read scheduled.json and bring it up to the current schema in memory
+// returns the version the file is at, so a writer knows to back it up
+pub fn load_queue(path: &Path) -> Result<(ScheduledOperations, u32)> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    if content.trim().is_empty() {
+        return Ok((ScheduledOperations::default(), migrations::QUEUE.current()));
+    }
+
+    let parse = || parse_error(path);
+    let mut value: serde_json::Value = serde_json::from_str(&content).with_context(parse)?;
+    let version = migrations::version_of(&value).with_context(parse)?;
+    migrations::check(&migrations::QUEUE, version, path)?;
+    migrations::upgrade(&migrations::QUEUE, &mut value, version).with_context(parse)?;
+    value["version"] = migrations::QUEUE.current().into();
+    Ok((serde_json::from_value(value).with_context(parse)?, version))
+}
+
+// the queue is the store's plus those of the repositories keeping their own (see repos.rs)
+// a repository queue that doesn't load is left out with a warning, so one broken repository
+// doesn't hold up the others; list, daemon status and doctor report it too
+pub fn load_scheduled_operations() -> Result<ScheduledOperations> {
+    let mut operations = store::current()?.operations()?;
+    for queue in repos::queues()? {
+        match queue.operations() {
+            Ok(queued) => operations.extend(queued),
+            Err(e) => repos::warn_unreadable(&queue, &e),
+        }
+    }
+    Ok(ScheduledOperations {
+        operations,
+        ..Default::default()
     })
 }
 
+// errors from a repository's queue say which one
+fn in_queue(queue: &repos::RepoQueue) -> String {
+    format!("queue of {}", queue.workdir.display())
+}
+
 pub fn add_scheduled_operation(operation: ScheduledOperation) -> Result<()> {
-    let mut operations = load_scheduled_operations()?;
-    operations.operations.push(operation);
-    save_scheduled_operations(&operations)
+    match repos::queue_for(&operation.repository_path)? {
+        Some(queue) => queue.enqueue(operation),
+        None => store::current()?.enqueue(operation),
+    }
 }
 
 pub fn remove_scheduled_operation(operation_id: &str) -> Result<bool> {
-    let mut operations = load_scheduled_operations()?;
-    let initial_len = operations.operations.len();
-    operations.operations.retain(|op| op.id != operation_id);
-    
-    if operations.operations.len() < initial_len {
-        save_scheduled_operations(&operations)?;
-        Ok(true)
-    } else {
-        Ok(false)
+    if store::current()?.remove(operation_id)? {
+        return Ok(true);
+    }
+    for queue in repos::readable_queues()? {
+        if queue.remove(operation_id).with_context(|| in_queue(&queue))? {
+            return Ok(true);
+        }
     }
+    Ok(false)
 }
 
-pub fn load_logs() -> Result<OperationLogs> {
-    let path = get_logs_file_path()?;
+// change queued operations, one transaction per queue, returns how many `change` changed
+pub fn update_operations(mut change: impl FnMut(&mut ScheduledOperation) -> bool) -> Result<usize> {
+    let mut changed = store::current()?.update(&mut change)?;
+    for queue in repos::readable_queues()? {
+        changed += queue.update(&mut change).with_context(|| in_queue(&queue))?;
+    }
+    Ok(changed)
+}
+
+// take an operation off the queue to run it, None if it's gone or was rescheduled
+pub fn take_scheduled_operation(
+    operation_id: &str,
+    scheduled_time: DateTime<Local>,
+) -> Result<Option<ScheduledOperation>> {
+    if let Some(operation) = store::current()?.claim(operation_id, scheduled_time)? {
+        return Ok(Some(operation));
+    }
+    for queue in repos::readable_queues()? {
+        let claimed = queue.claim(operation_id, scheduled_time).with_context(|| in_queue(&queue))?;
+        if let Some(operation) = claimed {
+            return Ok(Some(operation));
+        }
+    }
+    Ok(None)
+}
+
+pub fn load_pauses() -> Result<Pauses> {
+    load_json(&get_pauses_file_path()?)
+}
+
+// change the pauses atomically, see `transaction`
+pub fn update_pauses<R>(change: impl FnOnce(&mut Pauses) -> Result<R>) -> Result<R> {
+    transaction(&get_pauses_file_path()?, load_json, change)
+}
+
+// the last heartbeat the daemon wrote, None if it never ran
+pub fn load_heartbeat() -> Result<Option<Heartbeat>> {
+    let path = get_heartbeat_file_path()?;
     if !path.exists() {
-        return Ok(OperationLogs::default());
+        return Ok(None);
     }
-    
-    let content = fs::read_to_string(&path)?;
-    if content.trim().is_empty() {
-        return Ok(OperationLogs::default());
+    Ok(Some(serde_json::from_str(&fs::read_to_string(&path)?)?))
+}
+
+// only the daemon writes it, so it just needs to be atomic for readers
+pub fn save_heartbeat(heartbeat: &Heartbeat) -> Result<()> {
+    write_atomic(&get_heartbeat_file_path()?, serde_json::to_string_pretty(heartbeat)?.as_bytes())
+}
+
+// replace an operation in the queue, false if it isn't there anymore
+pub fn update_scheduled_operation(operation: ScheduledOperation) -> Result<bool> {
+    let changed = update_operations(|existing| {
+        if existing.id != operation.id {
+            return false;
+        }
+        *existing = operation.clone();
+        true
+    })?;
+    Ok(changed > 0)
+}
+
+// claim an operation for running, None if another process already has it
+// the claim is held until the returned guard is dropped
+pub fn claim_operation(operation_id: &str) -> Result<Option<OperationClaim>> {
+    // the id is a file name here, one from an import or a hand-edited queue mustn't point elsewhere
+    if operation_id.is_empty() || Path::new(operation_id).file_name() != Some(std::ffi::OsStr::new(operation_id)) {
+        return Err(anyhow::anyhow!("invalid operation id {:?}", operation_id));
+    }
+    let path = get_locks_dir()?.join(format!("{}.lock", operation_id));
+    loop {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        if file.try_lock_exclusive().is_err() {
+            return Ok(None);
+        }
+        // the holder we opened it behind may have removed it before we locked it,
+        // then the lock is on a file nobody else will look at, so start over
+        if is_same_file(&file, &path) {
+            return Ok(Some(OperationClaim { path, _file: file }));
+        }
+    }
+}
+
+fn is_same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(opened), Ok(current)) => opened.dev() == current.dev() && opened.ino() == current.ino(),
+        _ => false,
+    }
+}
+
+// how many operations are being run right now, by the daemon or `git delayed run`
+pub fn running_operations() -> Result<usize> {
+    let mut running = 0;
+    for entry in fs::read_dir(get_locks_dir()?)? {
+        let Ok(file) = File::open(entry?.path()) else {
+            continue;
+        };
+        match file.try_lock_shared() {
+            Ok(()) => {
+                let _ = FileExt::unlock(&file);
+            }
+            Err(_) => running += 1,
+        }
     }
-    
-    Ok(serde_json::from_str(&content)?)
+    Ok(running)
+}
+
+pub struct OperationClaim {
+    path: PathBuf,
+    _file: File,
+}
+
+impl Drop for OperationClaim {
+    fn drop(&mut self) {
+        // removed while still locked; someone who opened it before that can still lock it
+        // once we're gone, but claim_operation sees it's no longer at the path and retries
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// newest first
+pub fn query_logs(query: &LogQuery) -> Result<Vec<LogEntry>> {
+    store::current()?.logs(query)
 }
 
 pub fn append_log_entry(entry: LogEntry) -> Result<()> {
-    let mut logs = load_logs()?;
-    logs.entries.push(entry);
-    
-    let path = get_logs_file_path()?;
-    let file = fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&path)?;
-    
-    with_file_lock(&file, || {
-        let content = serde_json::to_string_pretty(&logs)?;
-        fs::write(&path, content)?;
-        Ok(())
-    })
+    store::current()?.append_log(entry)
+}
+
+// delete log entries older than `age`, returns how many
+pub fn prune_logs(age: chrono::Duration) -> Result<usize> {
+    let before = Local::now()
+        .checked_sub_signed(age)
+        .ok_or_else(|| anyhow::anyhow!("{} days is further back than dates go", age.num_days()))?;
+    store::current()?.prune_logs(before)
+}
+
+// a scheduled.json, changed in file transactions
+// the global queue of the JSON backend, and the queue of a repository that keeps its own (see repos.rs)
+pub struct QueueFile {
+    path: PathBuf,
+}
+
+impl QueueFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    // an old scheduled.json is backed up before it's rewritten in the current schema
+    fn transaction<R>(&self, change: impl FnOnce(&mut ScheduledOperations) -> Result<R>) -> Result<R> {
+        let load = |path: &Path| {
+            let (queue, version) = load_queue(path)?;
+            if version < migrations::QUEUE.current() {
+                migrations::backup(path, version)?;
+            }
+            Ok(queue)
+        };
+        transaction(&self.path, load, change)
+    }
+
+    pub fn operations(&self) -> Result<Vec<ScheduledOperation>> {
+        let (queue, version) = load_queue(&self.path)?;
+        if version < migrations::QUEUE.current() {
+            // upgrading writes, which needs the lock
+            return self.transaction(|queue| Ok(queue.operations.clone()));
+        }
+        Ok(queue.operations)
+    }
+
+    pub fn extend(&self, operations: Vec<ScheduledOperation>) -> Result<()> {
+        self.transaction(|queue| {
+            queue.operations.extend(operations);
+            Ok(())
+        })
+    }
+
+    pub fn remove(&self, id: &str) -> Result<bool> {
+        self.transaction(|queue| {
+            let initial_len = queue.operations.len();
+            queue.operations.retain(|op| op.id != id);
+            Ok(queue.operations.len() < initial_len)
+        })
+    }
+
+    pub fn update(&self, change: &mut dyn FnMut(&mut ScheduledOperation) -> bool) -> Result<usize> {
+        self.transaction(|queue| {
+            let mut changed = 0;
+            for operation in &mut queue.operations {
+                if change(operation) {
+                    changed += 1;
+                }
+            }
+            Ok(changed)
+        })
+    }
+
+    pub fn claim(&self, id: &str, scheduled_time: DateTime<Local>) -> Result<Option<ScheduledOperation>> {
+        self.transaction(|queue| {
+            let index = queue
+                .operations
+                .iter()
+                .position(|op| op.id == id && op.scheduled_time == scheduled_time);
+            Ok(index.map(|index| queue.operations.remove(index)))
+        })
+    }
+}
+
+// the original storage: scheduled.json and an append-only log (see logfile.rs)
+pub struct JsonStore {
+    queue: QueueFile,
+    logs: LogFile,
+}
+
+impl JsonStore {
+    pub fn new(dir: &Path, rotation: Rotation) -> Self {
+        Self {
+            queue: QueueFile::new(dir.join(SCHEDULED_FILE)),
+            logs: LogFile::new(dir, rotation),
+        }
+    }
+}
+
+impl Store for JsonStore {
+    fn operations(&self) -> Result<Vec<ScheduledOperation>> {
+        self.queue.operations()
+    }
+
+    fn enqueue(&self, operation: ScheduledOperation) -> Result<()> {
+        self.queue.extend(vec![operation])
+    }
+
+    fn remove(&self, id: &str) -> Result<bool> {
+        self.queue.remove(id)
+    }
+
+    fn update(&self, change: &mut dyn FnMut(&mut ScheduledOperation) -> bool) -> Result<usize> {
+        self.queue.update(change)
+    }
+
+    fn claim(&self, id: &str, scheduled_time: DateTime<Local>) -> Result<Option<ScheduledOperation>> {
+        self.queue.claim(id, scheduled_time)
+    }
+
+    fn append_log(&self, entry: LogEntry) -> Result<()> {
+        self.logs.append(&entry)
+    }
+
+    fn logs(&self, query: &LogQuery) -> Result<Vec<LogEntry>> {
+        self.logs.query(query)
+    }
+
+    fn import(&self, operations: Vec<ScheduledOperation>, logs: Vec<LogEntry>) -> Result<()> {
+        self.queue.extend(operations)?;
+        self.logs.extend(&logs)
+    }
+
+    fn prune_logs(&self, before: DateTime<Local>) -> Result<usize> {
+        self.logs.prune(before)
+    }
 }
 
 #[cfg(test)]
@@ -143,39 +663,261 @@ mod tests {
     use chrono::Local;
     use std::path::PathBuf;
 
+    fn operation(id: &str) -> ScheduledOperation {
+        ScheduledOperation {
+            id: id.to_string(),
+            repository_path: PathBuf::from("/tmp/test"),
+            operation_type: crate::models::OperationType::Commit,
+            commit_message: "test".to_string(),
+            scheduled_time: Local::now(),
+            created_at: Local::now(),
+            retry_count: 0,
+            state: crate::models::OperationState::Pending,
+            branch: None,
+            on_reject: None,
+            misfire: crate::models::MisfirePolicy::RunImmediately,
+            paused_until: None,
+            state_before_pause: None,
+            timeout_secs: None,
+        }
+    }
+
     #[test]
     fn test_storage_dir_exists() {
         let result = get_storage_dir();
         assert!(result.is_ok());
         let dir = result.unwrap();
         assert!(dir.exists());
+        assert!(dir.starts_with(env::temp_dir()));
+    }
+
+    #[test]
+    fn test_profile_and_state_dirs() {
+        let base = temp_dir();
+        let locations = |profile| {
+            resolve(
+                Locations {
+                    config: base.join("config"),
+                    state: base.join("state"),
+                },
+                profile,
+            )
+        };
+
+        let default = locations(None);
+        assert_eq!(default.config, base.join("config"));
+        assert_eq!(default.state, base.join("state"));
+        let work = locations(Some("work"));
+        assert_eq!(work.config, base.join("config/profiles/work"));
+        assert_eq!(work.state, base.join("state/profiles/work"));
+
+        // a queue from before the split keeps being used where it is
+        fs::create_dir_all(base.join("config")).unwrap();
+        fs::write(base.join("config").join(SCHEDULED_FILE), "{}").unwrap();
+        assert_eq!(locations(None).state, base.join("config"));
+        // unless the state dir is already in use
+        fs::create_dir_all(base.join("state")).unwrap();
+        assert_eq!(locations(None).state, base.join("state"));
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_profile_names() {
+        assert!(validate_profile("work").is_ok());
+        assert!(validate_profile("side_project-2").is_ok());
+        assert!(validate_profile("../etc").is_err());
+        assert!(validate_profile("").is_err());
     }
 
     #[test]
     fn test_load_empty_operations() {
-        let ops = load_scheduled_operations().unwrap();
-        assert!(ops.operations.len() >= 0);
+        let result = load_scheduled_operations();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_claim_is_exclusive() {
+        let id = format!("claim-{}", uuid::Uuid::new_v4());
+        let claim = claim_operation(&id).unwrap();
+        assert!(claim.is_some());
+        assert!(claim_operation(&id).unwrap().is_none());
+
+        drop(claim);
+        assert!(claim_operation(&id).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_claim_is_exclusive_across_threads() {
+        let id = format!("claim-{}", uuid::Uuid::new_v4());
+        let holders = std::sync::atomic::AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..200 {
+                        if let Some(claim) = claim_operation(&id).unwrap() {
+                            assert_eq!(holders.fetch_add(1, Ordering::SeqCst), 0, "claimed twice");
+                            thread::yield_now();
+                            holders.fetch_sub(1, Ordering::SeqCst);
+                            drop(claim);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_removed_claim_file_is_noticed() {
+        let id = format!("claim-{}", uuid::Uuid::new_v4());
+        let path = get_locks_dir().unwrap().join(format!("{}.lock", id));
+        let claim = claim_operation(&id).unwrap().unwrap();
+        let opened_before = File::open(&path).unwrap();
+        drop(claim);
+        assert!(!is_same_file(&opened_before, &path));
+    }
+
+    #[test]
+    fn test_concurrent_atomic_writes() {
+        let dir = temp_dir();
+        let path = dir.join("heartbeat.json");
+        thread::scope(|scope| {
+            for t in 0..8 {
+                let path = &path;
+                scope.spawn(move || {
+                    for n in 0..20 {
+                        let content = serde_json::json!({"thread": t, "n": n, "padding": "x".repeat(4096)});
+                        write_atomic(path, content.to_string().as_bytes()).unwrap();
+                    }
+                });
+            }
+        });
+        let content: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(content["n"], 19);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_claim_refuses_paths() {
+        for id in ["../escape", "/tmp/escape", "a/b", "..", ""] {
+            assert!(claim_operation(id).is_err(), "{}", id);
+        }
     }
 
     #[test]
     fn test_add_and_remove_operation() {
-        let op = ScheduledOperation {
-            id: "test-123".to_string(),
-            repository_path: PathBuf::from("/tmp/test"),
-            operation_type: crate::models::OperationType::Commit,
-            commit_message: "test".to_string(),
-            scheduled_time: Local::now(),
-            created_at: Local::now(),
-            retry_count: 0,
-            state: crate::models::OperationState::Pending,
-            branch: None,
-        };
+        let op = operation("test-123");
 
         add_scheduled_operation(op).unwrap();
         let removed = remove_scheduled_operation("test-123").unwrap();
         assert!(removed);
     }
 
+    fn rotation() -> Rotation {
+        crate::config::Config::default().log_rotation()
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("git-delayed-store-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_json_store() {
+        let dir = temp_dir();
+        store::conformance::check(&JsonStore::new(&dir, rotation()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_adds_are_not_lost() {
+        let dir = temp_dir();
+        // separate stores share nothing but the files, like the CLI and the daemon
+        thread::scope(|scope| {
+            for t in 0..8 {
+                let dir = &dir;
+                scope.spawn(move || {
+                    let store = JsonStore::new(dir, rotation());
+                    for n in 0..5 {
+                        store.enqueue(operation(&format!("{}-{}", t, n))).unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(JsonStore::new(&dir, rotation()).operations().unwrap().len(), 40);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_transaction_changes_nothing() {
+        let dir = temp_dir();
+        let store = JsonStore::new(&dir, rotation());
+        store.enqueue(operation("kept")).unwrap();
+
+        let result: Result<()> = store.queue.transaction(|queue| {
+            queue.operations.clear();
+            Err(anyhow::anyhow!("nope"))
+        });
+        assert!(result.is_err());
+        assert_eq!(store.operations().unwrap().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_old_queue_is_upgraded_with_backup() {
+        let dir = temp_dir();
+        let old = r#"{"operations": [{
+            "id": "old",
+            "repository_path": "/src/app",
+            "operation_type": "Commit",
+            "commit_message": "wip",
+            "scheduled_time": "2025-01-01T09:00:00+01:00",
+            "created_at": "2025-01-01T08:00:00+01:00"
+        }]}"#;
+        fs::write(dir.join(SCHEDULED_FILE), old).unwrap();
+
+        let store = JsonStore::new(&dir, rotation());
+        assert_eq!(store.operations().unwrap()[0].id, "old");
+        assert_eq!(fs::read_to_string(dir.join("scheduled.json.v1.bak")).unwrap(), old);
+        let (_, version) = load_queue(&dir.join(SCHEDULED_FILE)).unwrap();
+        assert_eq!(version, migrations::QUEUE.current());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune_older_than_dates_go_is_an_error() {
+        assert!(prune_logs(chrono::Duration::days(99_999_999)).is_err());
+        assert!(prune_logs(chrono::Duration::days(u32::MAX as i64)).is_err());
+    }
+
+    #[test]
+    fn test_newer_queue_is_refused() {
+        let dir = temp_dir();
+        let newer = format!(r#"{{"version": {}, "operations": []}}"#, migrations::QUEUE.current() + 1);
+        fs::write(dir.join(SCHEDULED_FILE), &newer).unwrap();
+
+        let store = JsonStore::new(&dir, rotation());
+        assert!(store.operations().is_err());
+        assert!(store.enqueue(operation("a")).is_err());
+        assert_eq!(fs::read_to_string(dir.join(SCHEDULED_FILE)).unwrap(), newer);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomic_leaves_no_temp_files() {
+        let dir = std::env::temp_dir().join(format!("git-delayed-atomic-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.json");
+
+        write_atomic(&path, b"one").unwrap();
+        write_atomic(&path, b"two").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "two");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
     #[test]
     fn test_remove_nonexistent() {
         let removed = remove_scheduled_operation("does-not-exist").unwrap();
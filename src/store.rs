@@ -0,0 +1,340 @@
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::config::Config;
+use crate::models::{LogEntry, ScheduledOperation};
+use crate::sqlite::SqliteStore;
+use crate::storage::{self, JsonStore};
+
+// where the queue and the execution log live
+// the CLI and the daemon both go through the backend chosen in config.json
+pub trait Store: Send + Sync {
+    // every queued operation
+    fn operations(&self) -> Result<Vec<ScheduledOperation>>;
+
+    fn enqueue(&self, operation: ScheduledOperation) -> Result<()>;
+
+    // take an operation off the queue, false if it wasn't there
+    fn remove(&self, id: &str) -> Result<bool>;
+
+    // run `change` on every queued operation in one transaction
+    // it returns whether it changed the operation, the number of changed ones is returned
+    fn update(&self, change: &mut dyn FnMut(&mut ScheduledOperation) -> bool) -> Result<usize>;
+
+    // take a due operation off the queue to run it
+    // None if it's gone or was rescheduled since `scheduled_time` was read
+    fn claim(&self, id: &str, scheduled_time: DateTime<Local>) -> Result<Option<ScheduledOperation>>;
+
+    fn append_log(&self, entry: LogEntry) -> Result<()>;
+
+    // log entries matching `query`, newest first
+    fn logs(&self, query: &LogQuery) -> Result<Vec<LogEntry>>;
+
+    // add operations and log entries in bulk, for migrations and imports
+    fn import(&self, operations: Vec<ScheduledOperation>, logs: Vec<LogEntry>) -> Result<()>;
+
+    // delete log entries executed before `before`, returns how many
+    fn prune_logs(&self, before: DateTime<Local>) -> Result<usize>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    // scheduled.json and logs.json, the original format
+    #[default]
+    Json,
+    // git-delayed.db, indexed and with real transactions
+    Sqlite,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Json => write!(f, "json"),
+            Backend::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
+
+pub fn parse_backend(name: &str) -> Result<Backend> {
+    match name {
+        "json" => Ok(Backend::Json),
+        "sqlite" => Ok(Backend::Sqlite),
+        _ => Err(anyhow::anyhow!("unknown storage backend '{}', use json or sqlite", name)),
+    }
+}
+
+// which log entries to return
+#[derive(Clone, Debug, Default)]
+pub struct LogQuery {
+    pub repository_path: Option<PathBuf>,
+    pub id: Option<String>,
+    pub since: Option<DateTime<Local>>,
+    pub limit: Option<usize>,
+}
+
+impl LogQuery {
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        self.repository_path.as_ref().is_none_or(|p| *p == entry.repository_path)
+            && self.id.as_ref().is_none_or(|id| *id == entry.id)
+            && self.since.is_none_or(|since| entry.executed_at >= since)
+    }
+
+    // filter, newest first and cut to the limit, for backends without an index
+    pub fn apply(&self, entries: Vec<LogEntry>) -> Vec<LogEntry> {
+        // stable, so entries logged at the same instant stay newest (last appended) first
+        let mut entries: Vec<LogEntry> = entries.into_iter().rev().filter(|e| self.matches(e)).collect();
+        entries.sort_by_key(|e| std::cmp::Reverse(e.executed_at));
+        entries.truncate(self.limit.unwrap_or(usize::MAX));
+        entries
+    }
+}
+
+pub fn open(backend: Backend, config: &Config) -> Result<Box<dyn Store>> {
+    Ok(match backend {
+        Backend::Json => Box::new(JsonStore::new(&storage::get_storage_dir()?, config.log_rotation())),
+        Backend::Sqlite => Box::new(SqliteStore::open(&storage::get_database_path()?)?),
+    })
+}
+
+static CURRENT: OnceLock<Box<dyn Store>> = OnceLock::new();
+
+// the store picked in config.json, opened on first use
+// switching backends takes effect the next time the daemon starts
+#[cfg(not(test))]
+pub fn current() -> Result<&'static dyn Store> {
+    if let Some(store) = CURRENT.get() {
+        return Ok(store.as_ref());
+    }
+    let config = crate::config::load_config()?;
+    let store = open(config.storage, &config)?;
+    Ok(CURRENT.get_or_init(|| store).as_ref())
+}
+
+// tests never touch the real queue
+#[cfg(test)]
+pub fn current() -> Result<&'static dyn Store> {
+    Ok(CURRENT.get_or_init(|| Box::new(MemoryStore::default())).as_ref())
+}
+
+// copy everything from one store into another, which has to be empty
+// returns how many operations and log entries were copied
+pub fn migrate(from: &dyn Store, to: &dyn Store) -> Result<(usize, usize)> {
+    if !to.operations()?.is_empty() || !to.logs(&LogQuery { limit: Some(1), ..Default::default() })?.is_empty() {
+        return Err(anyhow::anyhow!("the target store already has data, not mixing it"));
+    }
+
+    let operations = from.operations()?;
+    // oldest first, the order they were logged in
+    let mut logs = from.logs(&LogQuery::default())?;
+    logs.reverse();
+
+    let counts = (operations.len(), logs.len());
+    to.import(operations, logs)?;
+    Ok(counts)
+}
+
+#[cfg(test)]
+pub use memory::MemoryStore;
+
+#[cfg(test)]
+mod memory {
+    use super::*;
+    use std::sync::Mutex;
+
+    // everything in a process-local Vec, for tests
+    #[derive(Default)]
+    pub struct MemoryStore {
+        operations: Mutex<Vec<ScheduledOperation>>,
+        logs: Mutex<Vec<LogEntry>>,
+    }
+
+    impl Store for MemoryStore {
+        fn operations(&self) -> Result<Vec<ScheduledOperation>> {
+            Ok(self.operations.lock().unwrap().clone())
+        }
+
+        fn enqueue(&self, operation: ScheduledOperation) -> Result<()> {
+            self.operations.lock().unwrap().push(operation);
+            Ok(())
+        }
+
+        fn remove(&self, id: &str) -> Result<bool> {
+            let mut operations = self.operations.lock().unwrap();
+            let before = operations.len();
+            operations.retain(|op| op.id != id);
+            Ok(operations.len() < before)
+        }
+
+        fn update(&self, change: &mut dyn FnMut(&mut ScheduledOperation) -> bool) -> Result<usize> {
+            let mut changed = 0;
+            for operation in self.operations.lock().unwrap().iter_mut() {
+                if change(operation) {
+                    changed += 1;
+                }
+            }
+            Ok(changed)
+        }
+
+        fn claim(&self, id: &str, scheduled_time: DateTime<Local>) -> Result<Option<ScheduledOperation>> {
+            let mut operations = self.operations.lock().unwrap();
+            let index = operations
+                .iter()
+                .position(|op| op.id == id && op.scheduled_time == scheduled_time);
+            Ok(index.map(|index| operations.remove(index)))
+        }
+
+        fn append_log(&self, entry: LogEntry) -> Result<()> {
+            self.logs.lock().unwrap().push(entry);
+            Ok(())
+        }
+
+        fn logs(&self, query: &LogQuery) -> Result<Vec<LogEntry>> {
+            Ok(query.apply(self.logs.lock().unwrap().clone()))
+        }
+
+        fn import(&self, operations: Vec<ScheduledOperation>, logs: Vec<LogEntry>) -> Result<()> {
+            self.operations.lock().unwrap().extend(operations);
+            self.logs.lock().unwrap().extend(logs);
+            Ok(())
+        }
+
+        fn prune_logs(&self, before: DateTime<Local>) -> Result<usize> {
+            let mut logs = self.logs.lock().unwrap();
+            let initial_len = logs.len();
+            logs.retain(|entry| entry.executed_at >= before);
+            Ok(initial_len - logs.len())
+        }
+    }
+}
+
+// the same checks run against every backend
+#[cfg(test)]
+pub mod conformance {
+    use super::*;
+    use crate::models::{ExecutionStatus, MisfirePolicy, OperationState, OperationType};
+    use chrono::Duration;
+
+    pub fn operation(id: &str, repo: &str) -> ScheduledOperation {
+        ScheduledOperation {
+            id: id.to_string(),
+            repository_path: PathBuf::from(repo),
+            operation_type: OperationType::Push,
+            commit_message: "push".to_string(),
+            scheduled_time: Local::now(),
+            created_at: Local::now(),
+            retry_count: 0,
+            state: OperationState::Pending,
+            branch: None,
+            on_reject: None,
+            misfire: MisfirePolicy::RunImmediately,
+            paused_until: None,
+            state_before_pause: None,
+            timeout_secs: None,
+        }
+    }
+
+    pub fn entry(id: &str, repo: &str, executed_at: DateTime<Local>) -> LogEntry {
+        LogEntry {
+            id: id.to_string(),
+            repository_path: PathBuf::from(repo),
+            operation_type: OperationType::Push,
+            commit_message: "push".to_string(),
+            scheduled_time: executed_at,
+            executed_at,
+            status: ExecutionStatus::Success,
+            error_message: None,
+            error_category: None,
+            hook_output: None,
+        }
+    }
+
+    pub fn check(store: &dyn Store) {
+        let a = operation("a", "/one");
+        let b = operation("b", "/two");
+        store.enqueue(a.clone()).unwrap();
+        store.enqueue(b.clone()).unwrap();
+        assert_eq!(store.operations().unwrap().len(), 2);
+
+        let changed = store
+            .update(&mut |op| {
+                if op.id != "b" {
+                    return false;
+                }
+                op.state = OperationState::Paused;
+                true
+            })
+            .unwrap();
+        assert_eq!(changed, 1);
+        let stored_b = store.operations().unwrap().into_iter().find(|op| op.id == "b").unwrap();
+        assert_eq!(stored_b.state, OperationState::Paused);
+
+        // a claim only succeeds for the version that was read
+        assert!(store.claim("a", a.scheduled_time + Duration::seconds(1)).unwrap().is_none());
+        assert_eq!(store.claim("a", a.scheduled_time).unwrap().unwrap().id, "a");
+        assert!(store.claim("a", a.scheduled_time).unwrap().is_none());
+
+        assert!(store.remove("b").unwrap());
+        assert!(!store.remove("b").unwrap());
+        assert!(store.operations().unwrap().is_empty());
+
+        let now = Local::now();
+        store.append_log(entry("a", "/one", now - Duration::hours(2))).unwrap();
+        store.append_log(entry("b", "/two", now - Duration::hours(1))).unwrap();
+        store.append_log(entry("c", "/one", now)).unwrap();
+
+        let all = store.logs(&LogQuery::default()).unwrap();
+        assert_eq!(all.iter().map(|e| e.id.as_str()).collect::<Vec<_>>(), ["c", "b", "a"]);
+
+        let query = LogQuery {
+            repository_path: Some(PathBuf::from("/one")),
+            ..Default::default()
+        };
+        assert_eq!(store.logs(&query).unwrap().len(), 2);
+
+        let query = LogQuery {
+            since: Some(now - Duration::minutes(90)),
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(store.logs(&query).unwrap()[0].id, "c");
+
+        assert_eq!(store.prune_logs(now - Duration::minutes(90)).unwrap(), 1);
+        assert_eq!(store.logs(&LogQuery::default()).unwrap().len(), 2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_store() {
+        conformance::check(&MemoryStore::default());
+    }
+
+    #[test]
+    fn test_migrate_copies_everything() {
+        let from = MemoryStore::default();
+        from.enqueue(conformance::operation("a", "/one")).unwrap();
+        from.append_log(conformance::entry("old", "/one", Local::now() - chrono::Duration::hours(1))).unwrap();
+        from.append_log(conformance::entry("new", "/one", Local::now())).unwrap();
+
+        let to = MemoryStore::default();
+        assert_eq!(migrate(&from, &to).unwrap(), (1, 2));
+        assert_eq!(to.logs(&LogQuery::default()).unwrap()[0].id, "new");
+
+        // a second run would mix two histories
+        assert!(migrate(&from, &to).is_err());
+    }
+
+    #[test]
+    fn test_parse_backend() {
+        assert_eq!(parse_backend("sqlite").unwrap(), Backend::Sqlite);
+        assert!(parse_backend("postgres").is_err());
+    }
+}
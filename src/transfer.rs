@@ -0,0 +1,449 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use crate::git;
+use crate::migrations;
+use crate::models::{self, OperationType, ScheduledOperation, ScheduledOperations};
+
+// moving the queue between machines, backing it up, and showing it in a calendar
+// json is the scheduled.json format and the only one that can be imported back,
+// csv and ics are for looking at
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    Ics,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Json => write!(f, "json"),
+            Format::Csv => write!(f, "csv"),
+            Format::Ics => write!(f, "ics"),
+        }
+    }
+}
+
+pub fn parse_format(name: &str) -> Result<Format> {
+    match name.to_lowercase().as_str() {
+        "json" => Ok(Format::Json),
+        "csv" => Ok(Format::Csv),
+        "ics" | "ical" => Ok(Format::Ics),
+        _ => Err(anyhow::anyhow!("unknown format '{}', use json, csv or ics", name)),
+    }
+}
+
+pub fn export(operations: &[ScheduledOperation], format: Format) -> Result<String> {
+    Ok(match format {
+        Format::Json => {
+            let queue = ScheduledOperations {
+                operations: operations.to_vec(),
+                ..Default::default()
+            };
+            serde_json::to_string_pretty(&queue)? + "\n"
+        }
+        Format::Csv => to_csv(operations),
+        Format::Ics => to_ics(operations, Utc::now()),
+    })
+}
+
+const CSV_HEADER: &str = "id,scheduled_time,type,state,repository,branch,message,created_at";
+
+fn to_csv(operations: &[ScheduledOperation]) -> String {
+    let mut csv = format!("{}\r\n", CSV_HEADER);
+    for op in operations {
+        let fields = [
+            op.id.clone(),
+            op.scheduled_time.to_rfc3339(),
+            op.operation_type.to_string(),
+            op.state.to_string(),
+            op.repository_path.display().to_string(),
+            op.branch.clone().unwrap_or_default(),
+            op.commit_message.clone(),
+            op.created_at.to_rfc3339(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+// RFC 4180: quoted if it has a comma, quote or line break, quotes doubled
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// one VEVENT per operation at its scheduled time
+// operations don't repeat, so there's never an RRULE
+fn to_ics(operations: &[ScheduledOperation], now: DateTime<Utc>) -> String {
+    let stamp = |time: DateTime<Utc>| time.format("%Y%m%dT%H%M%SZ").to_string();
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//git-delayed//git-delayed//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+    ];
+    for op in operations {
+        let repo_name = op
+            .repository_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| op.repository_path.display().to_string());
+        let summary = match op.operation_type {
+            OperationType::Push => match &op.branch {
+                Some(branch) => format!("git push {} ({})", repo_name, branch),
+                None => format!("git push {}", repo_name),
+            },
+            OperationType::Commit => format!("git commit {}: {}", repo_name, op.commit_message),
+        };
+        let description = format!(
+            "{} in {}\nID: {}\nState: {}",
+            op.operation_type,
+            op.repository_path.display(),
+            op.id,
+            op.state
+        );
+        let start = op.scheduled_time.with_timezone(&Utc);
+
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:{}@git-delayed", op.id));
+        lines.push(format!("DTSTAMP:{}", stamp(now)));
+        lines.push(format!("DTSTART:{}", stamp(start)));
+        // zero-length events show up oddly in some calendars
+        lines.push(format!("DTEND:{}", stamp(start + chrono::Duration::minutes(5))));
+        lines.push(format!("SUMMARY:{}", ics_text(&summary)));
+        lines.push(format!("DESCRIPTION:{}", ics_text(&description)));
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    lines.iter().map(|line| fold(line) + "\r\n").collect()
+}
+
+// RFC 5545 TEXT escaping
+fn ics_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+// lines longer than 75 octets continue on the next line after a space
+fn fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded
+}
+
+// what to do with an imported operation whose id is already queued
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Conflict {
+    // import nothing
+    Abort,
+    // keep the queued one
+    Skip,
+    Replace,
+    // import it under a new id
+    Rename,
+}
+
+pub fn parse_conflict(name: &str) -> Result<Conflict> {
+    match name.to_lowercase().as_str() {
+        "abort" => Ok(Conflict::Abort),
+        "skip" => Ok(Conflict::Skip),
+        "replace" => Ok(Conflict::Replace),
+        "new-id" => Ok(Conflict::Rename),
+        _ => Err(anyhow::anyhow!("unknown conflict handling '{}', use abort, skip, replace or new-id", name)),
+    }
+}
+
+// --remap /old/prefix=/new/prefix
+pub fn parse_remap(spec: &str) -> Result<(PathBuf, PathBuf)> {
+    match spec.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok((PathBuf::from(from), PathBuf::from(to))),
+        _ => Err(anyhow::anyhow!("bad remap '{}', use FROM=TO (e.g. /home/old/src=/home/new/src)", spec)),
+    }
+}
+
+// the path with the longest matching prefix replaced
+fn remap(path: &Path, remaps: &[(PathBuf, PathBuf)]) -> PathBuf {
+    remaps
+        .iter()
+        .filter_map(|(from, to)| path.strip_prefix(from).ok().map(|rest| (from, to.join(rest))))
+        .max_by_key(|(from, _)| from.components().count())
+        .map(|(_, path)| path)
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+// read an exported queue (or a scheduled.json), older versions are upgraded
+pub fn read(path: &Path) -> Result<Vec<ScheduledOperation>> {
+    let content = std::fs::read_to_string(path)?;
+    let mut value: serde_json::Value = serde_json::from_str(&content).map_err(|e| {
+        anyhow::anyhow!("couldn't parse {} ({}), only the json export can be imported", path.display(), e)
+    })?;
+    let version = migrations::version_of(&value)?;
+    migrations::check(&migrations::QUEUE, version, path)?;
+    migrations::upgrade(&migrations::QUEUE, &mut value, version)?;
+    value["version"] = migrations::QUEUE.current().into();
+    let queue: ScheduledOperations = serde_json::from_value(value)?;
+    Ok(queue.operations)
+}
+
+#[derive(Debug, Default)]
+pub struct ImportPlan {
+    pub add: Vec<ScheduledOperation>,
+    // ids to remove before their replacement is added
+    pub replace: Vec<String>,
+    // (id, why)
+    pub skipped: Vec<(String, String)>,
+    // (id, why) for operations whose repository isn't on this machine
+    pub invalid: Vec<(String, String)>,
+}
+
+// decide what happens to each imported operation, nothing is changed yet
+// `find_repository` turns a path into the repository's working dir, or says why it can't
+pub fn plan(
+    incoming: Vec<ScheduledOperation>,
+    queued_ids: &HashSet<String>,
+    conflict: Conflict,
+    remaps: &[(PathBuf, PathBuf)],
+    find_repository: impl Fn(&Path) -> Result<PathBuf>,
+) -> Result<ImportPlan> {
+    let mut plan = ImportPlan::default();
+    let mut conflicts = Vec::new();
+    let mut seen = HashSet::new();
+
+    for mut operation in incoming {
+        if !models::is_valid_id(&operation.id) {
+            plan.skipped.push((operation.id, "isn't a valid operation id".to_string()));
+            continue;
+        }
+        let path = remap(&operation.repository_path, remaps);
+        match find_repository(&path) {
+            Ok(workdir) => operation.repository_path = workdir,
+            Err(e) => {
+                plan.invalid.push((operation.id, format!("{}: {:#}", path.display(), e)));
+                continue;
+            }
+        }
+
+        if !seen.insert(operation.id.clone()) {
+            plan.skipped.push((operation.id, "appears twice in the file".to_string()));
+            continue;
+        }
+        if queued_ids.contains(&operation.id) {
+            match conflict {
+                Conflict::Abort => {
+                    conflicts.push(operation.id);
+                    continue;
+                }
+                Conflict::Skip => {
+                    plan.skipped.push((operation.id, "already queued".to_string()));
+                    continue;
+                }
+                Conflict::Replace => plan.replace.push(operation.id.clone()),
+                Conflict::Rename => operation.id = Uuid::new_v4().to_string(),
+            }
+        }
+        plan.add.push(operation);
+    }
+
+    if !conflicts.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} operation(s) are already queued ({}), use --on-conflict skip, replace or new-id",
+            conflicts.len(),
+            conflicts.join(", ")
+        ));
+    }
+    Ok(plan)
+}
+
+// a repository path on this machine, as the CLI records it when scheduling
+pub fn find_repository(path: &Path) -> Result<PathBuf> {
+    if !path.exists() {
+        return Err(anyhow::anyhow!("doesn't exist"));
+    }
+    git::find_repository(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{MisfirePolicy, OperationState};
+    use chrono::{Local, TimeZone};
+
+    // the short names in the tests stand for real ids
+    fn id(name: &str) -> String {
+        let hex: String = name.bytes().map(|b| format!("{:02x}", b)).collect();
+        format!("{:0>8}-0000-4000-8000-000000000000", hex)
+    }
+
+    fn operation(name: &str, path: &str) -> ScheduledOperation {
+        let time = Local.with_ymd_and_hms(2025, 3, 10, 9, 30, 0).unwrap();
+        ScheduledOperation {
+            id: id(name),
+            repository_path: PathBuf::from(path),
+            operation_type: OperationType::Commit,
+            commit_message: "fix: parse \"a, b\"; done".to_string(),
+            scheduled_time: time,
+            created_at: time,
+            retry_count: 0,
+            state: OperationState::Pending,
+            branch: None,
+            on_reject: None,
+            misfire: MisfirePolicy::RunImmediately,
+            paused_until: None,
+            state_before_pause: None,
+            timeout_secs: None,
+        }
+    }
+
+    fn any_repository(path: &Path) -> Result<PathBuf> {
+        if path.starts_with("/gone") {
+            return Err(anyhow::anyhow!("doesn't exist"));
+        }
+        Ok(path.to_path_buf())
+    }
+
+    #[test]
+    fn test_csv_quotes_fields() {
+        let csv = to_csv(&[operation("a", "/src/app")]);
+        let mut lines = csv.split("\r\n");
+        assert_eq!(lines.next(), Some(CSV_HEADER));
+        let row = lines.next().unwrap();
+        assert!(row.starts_with(&format!("{},", id("a"))));
+        assert!(row.contains(",\"fix: parse \"\"a, b\"\"; done\","));
+    }
+
+    #[test]
+    fn test_ics_event_per_operation() {
+        let now = Utc.with_ymd_and_hms(2025, 3, 1, 0, 0, 0).unwrap();
+        let ics = to_ics(&[operation("a", "/src/app"), operation("b", "/src/app")], now);
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(ics.ends_with("END:VCALENDAR\r\n"));
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 2);
+        assert!(ics.contains(&format!("UID:{}@git-delayed\r\n", id("a"))));
+        let start = operation("a", "/src/app").scheduled_time.with_timezone(&Utc);
+        assert!(ics.contains(&format!("DTSTART:{}\r\n", start.format("%Y%m%dT%H%M%SZ"))));
+        assert!(ics.contains("SUMMARY:git commit app: fix: parse \"a\\, b\"\\; done\r\n"));
+        assert!(!ics.contains("RRULE"));
+        assert!(ics.split("\r\n").all(|line| line.len() <= 75));
+    }
+
+    #[test]
+    fn test_fold_long_lines() {
+        let line = format!("DESCRIPTION:{}", "x".repeat(100));
+        let folded = fold(&line);
+        let parts: Vec<&str> = folded.split("\r\n").collect();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].len(), 75);
+        assert!(parts[1].starts_with(' '));
+        assert_eq!(folded.replace("\r\n ", ""), line);
+    }
+
+    #[test]
+    fn test_remap_longest_prefix() {
+        let remaps = vec![
+            (PathBuf::from("/home/old"), PathBuf::from("/home/new")),
+            (PathBuf::from("/home/old/work"), PathBuf::from("/work")),
+        ];
+        assert_eq!(remap(Path::new("/home/old/app"), &remaps), PathBuf::from("/home/new/app"));
+        assert_eq!(remap(Path::new("/home/old/work/app"), &remaps), PathBuf::from("/work/app"));
+        assert_eq!(remap(Path::new("/srv/app"), &remaps), PathBuf::from("/srv/app"));
+        // whole components only
+        assert_eq!(remap(Path::new("/home/older"), &remaps), PathBuf::from("/home/older"));
+    }
+
+    #[test]
+    fn test_parse_remap() {
+        assert_eq!(
+            parse_remap("/a=/b").unwrap(),
+            (PathBuf::from("/a"), PathBuf::from("/b"))
+        );
+        assert!(parse_remap("/a").is_err());
+        assert!(parse_remap("=/b").is_err());
+    }
+
+    #[test]
+    fn test_plan_conflicts() {
+        let queued: HashSet<String> = [id("a")].into();
+        let incoming = || vec![operation("a", "/src/app"), operation("b", "/src/app")];
+
+        let error = plan(incoming(), &queued, Conflict::Abort, &[], any_repository).unwrap_err();
+        assert!(error.to_string().contains(&format!("already queued ({})", id("a"))));
+
+        let skipped = plan(incoming(), &queued, Conflict::Skip, &[], any_repository).unwrap();
+        assert_eq!(skipped.add.len(), 1);
+        assert_eq!(skipped.skipped[0].0, id("a"));
+
+        let replaced = plan(incoming(), &queued, Conflict::Replace, &[], any_repository).unwrap();
+        assert_eq!(replaced.add.len(), 2);
+        assert_eq!(replaced.replace, vec![id("a")]);
+
+        let renamed = plan(incoming(), &queued, Conflict::Rename, &[], any_repository).unwrap();
+        assert_eq!(renamed.add.len(), 2);
+        assert_ne!(renamed.add[0].id, id("a"));
+        assert!(renamed.replace.is_empty());
+    }
+
+    #[test]
+    fn test_plan_remaps_and_validates() {
+        let remaps = vec![(PathBuf::from("/old"), PathBuf::from("/src"))];
+        let incoming = vec![operation("a", "/old/app"), operation("b", "/gone/app")];
+        let plan = plan(incoming, &HashSet::new(), Conflict::Abort, &remaps, any_repository).unwrap();
+        assert_eq!(plan.add.len(), 1);
+        assert_eq!(plan.add[0].repository_path, PathBuf::from("/src/app"));
+        assert_eq!(plan.invalid[0].0, id("b"));
+        assert!(plan.invalid[0].1.contains("/gone/app"));
+    }
+
+    #[test]
+    fn test_plan_rejects_ids_that_arent_ids() {
+        let mut incoming = Vec::new();
+        for bad in ["../../escape", "/tmp/x", "a/b", ""] {
+            let mut op = operation("a", "/src/app");
+            op.id = bad.to_string();
+            incoming.push(op);
+        }
+        incoming.push(operation("b", "/src/app"));
+        let plan = plan(incoming, &HashSet::new(), Conflict::Abort, &[], any_repository).unwrap();
+        assert_eq!(plan.add.len(), 1);
+        assert_eq!(plan.add[0].id, id("b"));
+        assert_eq!(plan.skipped.len(), 4);
+    }
+
+    #[test]
+    fn test_json_export_reads_back() {
+        let dir = std::env::temp_dir().join(format!("git-delayed-export-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("queue.json");
+        std::fs::write(&path, export(&[operation("a", "/src/app")], Format::Json).unwrap()).unwrap();
+        let operations = read(&path).unwrap();
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].commit_message, "fix: parse \"a, b\"; done");
+
+        std::fs::write(&path, "id,scheduled_time\r\n").unwrap();
+        assert!(read(&path).unwrap_err().to_string().contains("only the json export"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
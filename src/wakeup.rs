@@ -0,0 +1,181 @@
+use anyhow::Result;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::repos;
+use crate::signals;
+use crate::storage;
+
+// how often the fallback watcher looks at the queue and pause files
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, PartialEq)]
+pub enum Wakeup {
+    Timeout,
+    Changed,
+    Signal,
+}
+
+// blocks the daemon until something is due, the queue changes, or the CLI pokes it
+// uses inotify on linux, elsewhere falls back to checking the file's mtime
+pub struct Waker {
+    watched: Vec<PathBuf>,
+    modified: Vec<Option<SystemTime>>,
+    #[cfg(target_os = "linux")]
+    inotify: Option<nix::sys::inotify::Inotify>,
+}
+
+impl Waker {
+    pub fn new() -> Result<Self> {
+        // the queue of either backend, whichever is in use
+        let mut watched = vec![
+            storage::get_scheduled_file_path()?,
+            storage::get_database_path()?,
+            storage::get_database_journal_path()?,
+            storage::get_pauses_file_path()?,
+        ];
+        // repositories registered later are picked up when the CLI signals the daemon
+        let repository_queues: Vec<PathBuf> = repos::queues()?.iter().map(|queue| queue.path()).collect();
+        watched.extend(repository_queues.iter().cloned());
+        let modified = watched.iter().map(modified_time).collect();
+
+        Ok(Self {
+            #[cfg(target_os = "linux")]
+            inotify: watch_storage_dir(&repository_queues).ok(),
+            watched,
+            modified,
+        })
+    }
+
+    pub fn wait(&mut self, timeout: Duration) -> Wakeup {
+        if signals::take_wakeup() {
+            return Wakeup::Signal;
+        }
+
+        #[cfg(target_os = "linux")]
+        if self.inotify.is_some() {
+            return self.wait_inotify(timeout);
+        }
+
+        self.wait_polling(timeout)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn wait_inotify(&mut self, timeout: Duration) -> Wakeup {
+        use nix::poll::{poll, PollFd, PollFlags};
+
+        let inotify = self.inotify.as_ref().unwrap();
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            // round up so we don't wake a hair before the operation is due
+            let millis = remaining.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32;
+
+            let (result, inotify_ready, wake_ready) = {
+                let mut fds = vec![PollFd::new(inotify, PollFlags::POLLIN)];
+                if let Some(receiver) = signals::wake_receiver() {
+                    fds.push(PollFd::new(receiver, PollFlags::POLLIN));
+                }
+                let result = poll(&mut fds, millis);
+                let ready = |fd: Option<&PollFd>| {
+                    fd.and_then(|fd| fd.revents())
+                        .is_some_and(|events| events.contains(PollFlags::POLLIN))
+                };
+                (result, ready(fds.first()), ready(fds.get(1)))
+            };
+
+            match result {
+                Ok(0) => return Wakeup::Timeout,
+                Ok(_) => {
+                    if wake_ready {
+                        drain_wake_receiver();
+                        if signals::take_wakeup() {
+                            return Wakeup::Signal;
+                        }
+                    }
+                    if !inotify_ready {
+                        continue;
+                    }
+                    let events = inotify.read_events().unwrap_or_default();
+                    let relevant = events.iter().any(|event| {
+                        event.name.as_ref().is_some_and(|name| {
+                            self.watched.iter().any(|p| p.file_name() == Some(name.as_os_str()))
+                        })
+                    });
+                    if relevant {
+                        return Wakeup::Changed;
+                    }
+                }
+                // interrupted by a signal
+                Err(nix::errno::Errno::EINTR) => {
+                    if signals::take_wakeup() {
+                        return Wakeup::Signal;
+                    }
+                }
+                Err(_) => return self.wait_polling(remaining),
+            }
+
+            if Instant::now() >= deadline {
+                return Wakeup::Timeout;
+            }
+        }
+    }
+
+    fn wait_polling(&mut self, timeout: Duration) -> Wakeup {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if signals::take_wakeup() {
+                return Wakeup::Signal;
+            }
+
+            let modified: Vec<_> = self.watched.iter().map(modified_time).collect();
+            if modified != self.modified {
+                self.modified = modified;
+                return Wakeup::Changed;
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Wakeup::Timeout;
+            }
+            thread::sleep(remaining.min(POLL_INTERVAL));
+        }
+    }
+}
+
+fn drain_wake_receiver() {
+    if let Some(mut receiver) = signals::wake_receiver() {
+        let mut buf = [0u8; 64];
+        while matches!(receiver.read(&mut buf), Ok(n) if n > 0) {}
+    }
+}
+
+fn modified_time(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// watch the whole storage dir, files get replaced as well as rewritten
+// and the dirs of the repository queues the same way
+#[cfg(target_os = "linux")]
+fn watch_storage_dir(repository_queues: &[PathBuf]) -> Result<nix::sys::inotify::Inotify> {
+    use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+
+    let flags = AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE;
+    let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+    inotify.add_watch(&storage::get_storage_dir()?, flags)?;
+    for queue in repository_queues {
+        if let Some(dir) = queue.parent() {
+            // a repository on a drive that goes away just isn't watched anymore
+            let _ = inotify.add_watch(dir, flags);
+        }
+    }
+    Ok(inotify)
+}
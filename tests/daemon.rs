@@ -0,0 +1,98 @@
+use chrono::{DateTime, Duration, Local};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Instant;
+
+const BINARY: &str = env!("CARGO_BIN_EXE_git-delayed");
+
+fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("git-delayed-it-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn git(repo: &Path, args: &[&str]) {
+    let status = Command::new("git").arg("-C").arg(repo).args(args).status().unwrap();
+    assert!(status.success(), "git {:?} failed", args);
+}
+
+fn git_delayed(home: &Path) -> Command {
+    let mut command = Command::new(BINARY);
+    command.env("GIT_DELAYED_HOME", home);
+    command
+}
+
+// the log entry for `id`, once the daemon has written one
+fn logged(home: &Path, id: &str) -> Option<serde_json::Value> {
+    let logs = fs::read_to_string(home.join("logs.jsonl")).ok()?;
+    logs.lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .find(|entry| entry["id"] == id)
+}
+
+fn time(value: &serde_json::Value) -> DateTime<Local> {
+    value.as_str().unwrap().parse().unwrap()
+}
+
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn test_queued_operation_fires_on_time() {
+    let dir = temp_dir();
+    let home = dir.join("home");
+    let repo = dir.join("repo");
+    fs::create_dir_all(&repo).unwrap();
+    git(&repo, &["init", "-q"]);
+    git(&repo, &["config", "user.name", "Test"]);
+    git(&repo, &["config", "user.email", "test@example.com"]);
+    git(&repo, &["commit", "-q", "--allow-empty", "-m", "init"]);
+
+    let mut daemon = Daemon(
+        git_delayed(&home)
+            .args(["daemon", "run", "--foreground"])
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    // scheduling signals the daemon, straight away while it's still starting up
+    let when = (Local::now() + Duration::seconds(2)).format("%Y-%m-%d %H:%M:%S").to_string();
+    let output = git_delayed(&home)
+        .current_dir(&repo)
+        .args(["schedule", &when, "commit", "--message", "nothing to commit"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let id = stdout
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("ID: "))
+        .unwrap()
+        .to_string();
+
+    let started = Instant::now();
+    let entry = loop {
+        assert!(daemon.0.try_wait().unwrap().is_none(), "the daemon exited");
+        if let Some(entry) = logged(&home, &id) {
+            break entry;
+        }
+        assert!(started.elapsed().as_secs() < 10, "the operation never ran");
+        thread::sleep(std::time::Duration::from_millis(50));
+    };
+
+    let late = time(&entry["executed_at"]) - time(&entry["scheduled_time"]);
+    assert!(late >= Duration::zero(), "ran {}ms early", -late.num_milliseconds());
+    assert!(late < Duration::seconds(1), "ran {}ms late", late.num_milliseconds());
+
+    drop(daemon);
+    fs::remove_dir_all(dir).unwrap();
+}